@@ -0,0 +1,7 @@
+-- The vaults table as shipped in version 0.1.0: the source of truth,
+-- one row per entity.
+CREATE TABLE IF NOT EXISTS vaults (
+    addr INTEGER PRIMARY KEY,
+    content TEXT,
+    addresses JSON
+);
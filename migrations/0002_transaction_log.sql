@@ -0,0 +1,21 @@
+-- Append-only transaction log: one row per apply_datoms call in `transactions`
+-- and the datoms it changed, in order, in `tx_datoms`.
+-- Undo and redo transactions record the tx they revert in `reverts`.
+CREATE TABLE IF NOT EXISTS transactions (
+    tx INTEGER PRIMARY KEY AUTOINCREMENT,
+    tx_instant INTEGER NOT NULL,
+    kind TEXT NOT NULL DEFAULT 'tx',
+    reverts INTEGER REFERENCES transactions(tx)
+);
+
+CREATE TABLE IF NOT EXISTS tx_datoms (
+    tx INTEGER NOT NULL REFERENCES transactions(tx),
+    idx INTEGER NOT NULL,
+    op TEXT NOT NULL,
+    e TEXT NOT NULL,
+    a TEXT NOT NULL,
+    v TEXT NOT NULL,
+    PRIMARY KEY (tx, idx)
+);
+
+CREATE INDEX IF NOT EXISTS tx_datoms_e ON tx_datoms (e, tx);
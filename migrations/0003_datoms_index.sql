@@ -0,0 +1,12 @@
+-- Current attributes of every entity, one row per (entity, attribute, value).
+-- The primary key serves entity -> attribute -> value lookups and
+-- datoms_ave attribute -> value -> entity lookups.
+-- Rows are built from the vaults table on startup when the table is empty.
+CREATE TABLE IF NOT EXISTS datoms (
+    e TEXT NOT NULL,
+    a TEXT NOT NULL,
+    v TEXT NOT NULL,
+    PRIMARY KEY (e, a, v)
+) WITHOUT ROWID;
+
+CREATE INDEX IF NOT EXISTS datoms_ave ON datoms (a, v, e);
@@ -0,0 +1,2 @@
+-- FTS5 full-text index over block/content, used by search.
+CREATE VIRTUAL TABLE IF NOT EXISTS blocks_fts USING fts5(e UNINDEXED, content);
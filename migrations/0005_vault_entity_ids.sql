@@ -0,0 +1,9 @@
+-- Store the entity id next to its addr, so reads can look entities up by id
+-- and writes can detect two ids hashing to the same addr.
+ALTER TABLE vaults ADD COLUMN entity_id TEXT;
+
+UPDATE vaults
+SET entity_id = CASE WHEN json_valid(content) THEN json_extract(content, '$.id') END
+WHERE entity_id IS NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS vaults_entity_id ON vaults (entity_id);
@@ -0,0 +1,8 @@
+-- Attributes declared by users. The core block/* and page/* attributes are
+-- built in and not stored here.
+CREATE TABLE IF NOT EXISTS attributes (
+  ident TEXT PRIMARY KEY,
+  value_type TEXT NOT NULL,
+  cardinality TEXT NOT NULL DEFAULT 'one',
+  uniq TEXT
+);
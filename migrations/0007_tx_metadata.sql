@@ -0,0 +1,6 @@
+-- Who made a transaction, through which command or client, and why.
+ALTER TABLE transactions ADD COLUMN source TEXT;
+ALTER TABLE transactions ADD COLUMN author TEXT;
+ALTER TABLE transactions ADD COLUMN message TEXT;
+
+CREATE INDEX IF NOT EXISTS transactions_author ON transactions (author);
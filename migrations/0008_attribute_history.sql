@@ -0,0 +1,14 @@
+-- Every declaration of an attribute, with the last transaction before it,
+-- so reads as of a past transaction use the schema of that time.
+-- Declarations made before this table existed count from the start.
+CREATE TABLE IF NOT EXISTS attribute_history (
+  after_tx INTEGER NOT NULL,
+  ident TEXT NOT NULL,
+  value_type TEXT NOT NULL,
+  cardinality TEXT NOT NULL,
+  uniq TEXT
+);
+
+INSERT INTO attribute_history (after_tx, ident, value_type, cardinality, uniq)
+SELECT 0, ident, value_type, cardinality, uniq FROM attributes
+WHERE NOT EXISTS (SELECT 1 FROM attribute_history);
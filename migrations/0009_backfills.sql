@@ -0,0 +1,5 @@
+-- One-off data backfills that run when a vault is opened, recorded once done.
+CREATE TABLE IF NOT EXISTS backfills (
+  name TEXT PRIMARY KEY,
+  done_at INTEGER NOT NULL
+);
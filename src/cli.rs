@@ -1,13 +1,26 @@
-use std::{fs, io, path::PathBuf, process::Command};
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::PathBuf,
+    process::Command,
+};
 
+use chrono::NaiveDate;
 use serde_json::{Value, json};
-use sqlx::Encode;
 
 use crate::{
     config::{Config, default_dir},
-    date::{date_str_format, now_nanos, page_id_for, today_date, today_date_formatted},
-    db::{self, Entity},
-    transact::transact_with_fallback,
+    date::{
+        date_str_format, format_millis, page_id_for, parse_instant, today_date,
+        today_date_formatted,
+    },
+    db::{
+        self, AsOf, Attribute, Cardinality, Db, Encoding, Entity, Pattern, Query, SearchHit,
+        TxFilter, TxInfo, TxMeta, Unique, ValueType, Version,
+    },
+    diff::{DiffLine, line_diff},
+    server::serve,
+    transact::{redo, transact_with_fallback, undo},
 };
 
 fn edit_buffer_path(date: &str) -> io::Result<PathBuf> {
@@ -81,24 +94,124 @@ fn is_bullet_point(line: &str, custom_bullet: &str) -> bool {
 }
 
 fn extract_bullet_content<'a>(line: &'a str, custom_bullet: &str) -> &'a str {
-    if line.starts_with(custom_bullet) {
-        line[custom_bullet.len()..].trim_start()
+    if let Some(rest) = line.strip_prefix(custom_bullet) {
+        rest.trim_start()
     } else {
         line[2..].trim_start()
     }
 }
 
+/// Returns the datoms that add a new block with `note` to a journal page.
+/// `block_id` is usually a tempid.
+fn block_datoms(block_id: &str, note: &str, page_id: &str, title: &str) -> Vec<Value> {
+    let mut datoms = vec![
+        json!(["db/add", block_id, "block/title", title]),
+        json!(["db/add", block_id, "block/content", note]),
+        json!(["db/add", block_id, "block/page", page_id]),
+    ];
+    let tags = db::extract_tags(note);
+    if !tags.is_empty() {
+        datoms.push(json!(["db/add", block_id, "block/tags", tags]));
+    }
+    datoms
+}
+
+/// Removes `flag` and the value that follows it from the arguments.
+/// Returns the value if the flag was present.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    args.remove(pos);
+    if pos < args.len() {
+        Some(args.remove(pos))
+    } else {
+        None
+    }
+}
+
+/// tx_meta() is the metadata of a transaction made by the CLI command `command`.
+fn tx_meta(config: &Config, command: &str, message: &Option<String>) -> TxMeta {
+    TxMeta {
+        source: Some(format!("cli:{}", command)),
+        author: config.author.clone(),
+        message: message.clone(),
+        ..TxMeta::default()
+    }
+}
+
+fn print_log(txs: Vec<TxInfo>) {
+    if txs.is_empty() {
+        println!("No transactions");
+        return;
+    }
+    for info in txs {
+        let meta = &info.meta;
+        let mut line = format!(
+            "tx {}  {}  {:<4} {:>3} datoms",
+            info.tx,
+            format_millis(info.tx_instant),
+            meta.kind.as_str(),
+            info.datoms
+        );
+        if let Some(author) = &meta.author {
+            line.push_str(&format!("  by {}", author));
+        }
+        if let Some(source) = &meta.source {
+            line.push_str(&format!("  via {}", source));
+        }
+        println!("{}", line);
+        if let Some(message) = &meta.message {
+            println!("    {}", message);
+        }
+    }
+}
+
+/// Parses the value of `--as-of`: a tx id or a point in time.
+fn parse_as_of(value: &str) -> Option<AsOf> {
+    if let Ok(tx) = value.parse::<i64>() {
+        return Some(AsOf::Tx(tx));
+    }
+    parse_instant(value).map(AsOf::Instant)
+}
+
 /// Prints the help message for the CLI application.
 fn print_help() {
     println!("Usage:");
     println!("  jottty add \"note text\"");
-    println!("  jottty list");
+    println!("  jottty list [--as-of TIME]");
     println!("  jottty view");
-    println!("  jottty view [YYYY-MM-DD]");
+    println!("  jottty view [YYYY-MM-DD] [--as-of TIME]");
     println!("  jottty edit");
     println!("  jottty edit [YYYY-MM-DD]");
-    println!("  jottty search \"term\"");
-    println!("  jottty tag --filter \"TERM\"");
+    println!("  jottty search \"term\" [--as-of TIME]");
+    println!("      term: words, \"a phrase\", prefix*, AND / OR / NOT");
+    println!("  jottty tag --filter \"TAG\" [--as-of TIME]");
+    println!("      TAG: a #tag or a leading marker like TODO: in a note");
+    println!("  jottty history [YYYY-MM-DD|BLOCK-ID]");
+    println!("  jottty schema");
+    println!("  jottty schema add IDENT TYPE [--many] [--unique identity|value]");
+    println!("      TYPE: string, ref, instant, long or boolean");
+    println!("  jottty pull PATTERN [YYYY-MM-DD|ID]");
+    println!(
+        "      PATTERN: like \"[* {{block/page [page/name]}}]\" or \"[page/name {{block/_page [*]}}]\""
+    );
+    println!("  jottty query QUERY");
+    println!("      QUERY: EDN or JSON, like '{{:find [?c] :where [[?b :block/content ?c]]}}'");
+    println!("  jottty transact [FILE]");
+    println!("      FILE: a transaction as JSON (see notes/api.md), read from stdin if omitted");
+    println!("  jottty serve [--bind ADDR] [--memory]");
+    println!("  jottty log [N] [--author NAME] [--source SOURCE]");
+    println!("      SOURCE: cli:add, cli:edit, cli:transact, cli:undo or cli:redo");
+    println!("  jottty undo [N]");
+    println!("  jottty redo [N]");
+    println!("  jottty db migrate [--status]");
+    println!("  jottty db check");
+    println!("  jottty db reencode [json|msgpack|transit]");
+    println!("  jottty gc [--dry-run]");
+    println!();
+    println!(
+        "add, edit, transact, undo and redo take --message TEXT to record with the transaction."
+    );
+    println!("TIME is a tx id, YYYY-MM-DD, YYYY-MM-DDTHH:MM or an RFC 3339 timestamp.");
 }
 
 //TODO@chico: add tests for this function
@@ -127,19 +240,95 @@ fn page_title_from_id(page_id: &str) -> &str {
     page_id.strip_prefix("page:").unwrap_or(page_id)
 }
 
-fn print_search_results(entities: Vec<Entity>) {
-    for entity in entities {
-        let title = entity
+/// Renders the highlight markers of a search snippet as bold text on a
+/// terminal, and drops them otherwise.
+fn highlight(snippet: &str) -> String {
+    let (start, end) = if io::stdout().is_terminal() {
+        ("\x1b[1m", "\x1b[0m")
+    } else {
+        ("", "")
+    };
+    snippet
+        .replace(db::HIGHLIGHT_START, start)
+        .replace(db::HIGHLIGHT_END, end)
+}
+
+fn print_tagged_blocks(blocks: Vec<Entity>) {
+    for block in blocks {
+        let page_id = block
             .attrs
-            .get("block/content")
+            .get("block/page")
             .and_then(|val| val.as_str())
             .unwrap_or("-");
-        let page_id = entity
+        let content = block
+            .attrs
+            .get("block/content")
+            .and_then(|val| val.as_str())
+            .unwrap_or("");
+        println!("{}: {}", page_title_from_id(page_id), content);
+    }
+}
+
+fn print_search_results(hits: Vec<SearchHit>) {
+    for hit in hits {
+        let page_id = hit
+            .entity
             .attrs
             .get("block/page")
             .and_then(|val| val.as_str())
             .unwrap_or("-");
-        println!("{}: {}", page_title_from_id(page_id), title);
+        println!(
+            "{}: {}",
+            page_title_from_id(page_id),
+            highlight(&hit.snippet)
+        );
+    }
+}
+
+/// Returns the "block/content" of the target entity, or of the blocks on it
+/// when the target is a page, one line per block.
+fn content_of(target: &str, entities: &[Entity]) -> String {
+    let mut lines = Vec::new();
+    for entity in entities {
+        let on_target = entity.id == target
+            || matches!(entity.attrs.get("block/page"), Some(Value::String(page)) if page == target);
+        if !on_target {
+            continue;
+        }
+        if let Some(Value::String(content)) = entity.attrs.get("block/content") {
+            lines.push(content.as_str());
+        }
+    }
+    lines.join("\n")
+}
+
+fn print_history(target: &str, versions: Vec<Version>) {
+    if versions.is_empty() {
+        println!("No history for {}", target);
+        return;
+    }
+    for version in versions {
+        let mut header = format!("tx {}  {}", version.tx, format_millis(version.tx_instant));
+        if let Some(author) = &version.meta.author {
+            header.push_str(&format!("  by {}", author));
+        }
+        if let Some(source) = &version.meta.source {
+            header.push_str(&format!("  via {}", source));
+        }
+        println!("{}", header);
+        let before = content_of(target, &version.before);
+        let after = content_of(target, &version.after);
+        if before == after {
+            println!("    (no content changes)");
+            continue;
+        }
+        for line in line_diff(&before, &after) {
+            match line {
+                DiffLine::Same(text) => println!("    {}", text),
+                DiffLine::Removed(text) => println!("  - {}", text),
+                DiffLine::Added(text) => println!("  + {}", text),
+            }
+        }
     }
 }
 
@@ -147,8 +336,8 @@ fn print_search_results(entities: Vec<Entity>) {
 //
 /// Entry point for the CLI application.
 /// Handles command-line arguments and executes corresponding actions.
-pub async fn run() {
-    let args: Vec<String> = std::env::args().skip(1).collect();
+pub async fn run(db: &Db) {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
     let config = match Config::load() {
         Ok(cfg) => cfg,
         Err(e) => {
@@ -157,11 +346,31 @@ pub async fn run() {
         }
     };
 
+    let as_of = match take_flag(&mut args, "--as-of") {
+        Some(value) => match parse_as_of(&value) {
+            Some(as_of) => Some(as_of),
+            None => {
+                eprintln!("Error: invalid --as-of value '{}'.", value);
+                print_help();
+                return;
+            }
+        },
+        None => None,
+    };
+    let message = take_flag(&mut args, "--message");
+
     if args.is_empty() {
         print_help();
         return;
     }
 
+    // only the commands that read the vault support --as-of
+    if as_of.is_some() && !matches!(args[0].as_str(), "list" | "view" | "search" | "tag") {
+        eprintln!("Error: '{}' does not support --as-of.", args[0]);
+        print_help();
+        return;
+    }
+
     match args[0].as_str() {
         // TODO@chico: add test for the "add" command
         // TODO@chico: refactor this function to make it more modular and testable
@@ -179,15 +388,13 @@ pub async fn run() {
             let page_id = page_id_for(&date);
             let title = today_date_formatted();
 
-            let block_id = format!("block:{}-{}", date, now_nanos());
-            let datoms = vec![
-                json!(["db/add", &block_id, "block/title", title]),
-                json!(["db/add", &block_id, "block/content", note]),
-                json!(["db/add", &block_id, "block/page", page_id.clone()]),
-                json!(["db/add", &page_id, "page/name", date]),
-            ];
-            if let Err(err) = transact_with_fallback(datoms).await {
+            // "tmp/new-block" is a tempid, the block gets a fresh id
+            let mut datoms = block_datoms("tmp/new-block", &note, &page_id, &title);
+            datoms.push(json!(["db/add", &page_id, "page/name", date]));
+            let meta = tx_meta(&config, "add", &message);
+            if let Err(err) = transact_with_fallback(db, datoms, meta).await {
                 eprintln!("Failed to add note: {}", err);
+                std::process::exit(err.exit_code());
             }
         }
         // TODO@chico: add test for the "list" command
@@ -199,7 +406,7 @@ pub async fn run() {
                 &today_date()
             };
             let page_id = page_id_for(date);
-            let entities = match db::list_page_blocks(&page_id).await {
+            let entities = match db::list_page_blocks(db, &page_id, as_of).await {
                 Ok(entities) => entities,
                 Err(err) => {
                     eprintln!("Failed to view journal: {}", err);
@@ -215,7 +422,7 @@ pub async fn run() {
                 &today_date()
             };
             let page_id = page_id_for(date);
-            let entities = match db::list_page_blocks(&page_id).await {
+            let entities = match db::list_page_blocks(db, &page_id, None).await {
                 Ok(entities) => entities,
                 Err(err) => {
                     eprintln!("Failed to edit journal: {}", err);
@@ -259,36 +466,29 @@ pub async fn run() {
 
             //TODO@chico: to improve this we need to retract only changed contents
             for entity in &entities {
-                if let Some(Value::String(title)) = entity.attrs.get("block/title") {
-                    datoms.push(json!(["db/retract", entity.id, "block/title", title]));
-                }
-                if let Some(Value::String(content)) = entity.attrs.get("block/content") {
-                    datoms.push(json!(["db/retract", entity.id, "block/content", content]));
-                }
-                if let Some(Value::String(page)) = entity.attrs.get("block/page") {
-                    datoms.push(json!(["db/retract", entity.id, "block/page", page]));
-                }
+                datoms.push(json!(["db/retractEntity", entity.id]));
             }
 
-            for note in notes {
-                let block_id = format!("block:{}-{}", date, now_nanos());
-                datoms.push(json!([
-                    "db/add",
+            // tempids: the blocks get fresh ids, in the order of the notes
+            for (i, note) in notes.iter().enumerate() {
+                let block_id = format!("tmp/block-{}", i);
+                datoms.extend(block_datoms(
                     &block_id,
-                    "block/title",
-                    date_str_format(date)
-                ]));
-                datoms.push(json!(["db/add", &block_id, "block/content", note]));
-                datoms.push(json!(["db/add", &block_id, "block/page", page_id.clone()]));
+                    note,
+                    &page_id,
+                    &date_str_format(date),
+                ));
             }
             datoms.push(json!(["db/add", &page_id, "page/name", date]));
 
-            if let Err(err) = transact_with_fallback(datoms).await {
+            let meta = tx_meta(&config, "edit", &message);
+            if let Err(err) = transact_with_fallback(db, datoms, meta).await {
                 eprintln!("Failed to save journal: {}", err);
+                std::process::exit(err.exit_code());
             }
         }
         "list" => {
-            let pages = match db::list_pages().await {
+            let pages = match db::list_pages(db, as_of).await {
                 Ok(pages) => pages,
                 Err(err) => {
                     eprintln!("Failed to list journals: {}", err);
@@ -312,33 +512,314 @@ pub async fn run() {
             }
             // TODO@chico: implement pagination for search results
             // currently it fetches all matching blocks which can be slow for large datasets
-            let entities = match db::search_blocks(&args[1]).await {
-                Ok(entities) => entities,
+            let hits = match db::search_blocks(db, &args[1], as_of).await {
+                Ok(hits) => hits,
                 Err(err) => {
                     eprintln!("Failed to search journals: {}", err);
                     return;
                 }
             };
-            print_search_results(entities);
+            print_search_results(hits);
         }
         // TODO@chico: add test for the "tag" command
         "tag" => {
-            // in the future tags need to be add in the block/tags [] as a list of tags
-            // so this way we can filter by tags more easily and efficient
-            // also we can create a sqlite of tags for faster searching
+            // tags live in block/tags, looked up in the attribute index
             if args.len() >= 3 && args[1] == "--filter" {
-                let entities = match db::search_blocks(&args[2]).await {
-                    Ok(entities) => entities,
+                let tag = args[2].trim_start_matches('#').trim_end_matches(':');
+                let blocks = match db::tagged_blocks(db, tag, as_of).await {
+                    Ok(blocks) => blocks,
                     Err(err) => {
                         eprintln!("Failed to filter tags: {}", err);
                         return;
                     }
                 };
-                print_search_results(entities);
+                print_tagged_blocks(blocks);
             } else {
                 print_help();
             }
         }
+        "history" => {
+            let target = if args.len() > 1 {
+                args[1].clone()
+            } else {
+                today_date()
+            };
+            // a date means the journal page, anything else is an entity id
+            let entity_id = if NaiveDate::parse_from_str(&target, "%Y-%m-%d").is_ok() {
+                page_id_for(&target)
+            } else {
+                target
+            };
+            let versions = match db::history(db, &entity_id).await {
+                Ok(versions) => versions,
+                Err(err) => {
+                    eprintln!("Failed to load history: {}", err);
+                    return;
+                }
+            };
+            print_history(&entity_id, versions);
+        }
+        "undo" | "redo" => {
+            let n = match args.get(1).map(|n| n.parse::<usize>()) {
+                None => 1,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => {
+                    eprintln!("Error: '{}' expects a number of transactions.", args[0]);
+                    print_help();
+                    return;
+                }
+            };
+            let meta = tx_meta(&config, &args[0], &message);
+            let result = if args[0] == "undo" {
+                undo(db, n, &meta).await
+            } else {
+                redo(db, n, &meta).await
+            };
+            let done = match result {
+                Ok(done) => done,
+                Err(err) => {
+                    eprintln!("Failed to {}: {}", args[0], err);
+                    std::process::exit(err.exit_code());
+                }
+            };
+            if done.is_empty() {
+                println!("Nothing to {}", args[0]);
+            }
+            for (reverted, tx) in done {
+                println!("Reverted tx {} (tx {})", reverted, tx);
+            }
+        }
+        "schema" if args.get(1).map(String::as_str) == Some("add") => {
+            let mut args = args.clone();
+            let many = args.iter().any(|arg| arg == "--many");
+            args.retain(|arg| arg != "--many");
+            let unique = match take_flag(&mut args, "--unique") {
+                Some(name) => match Unique::parse(&name) {
+                    Some(unique) => Some(unique),
+                    None => {
+                        eprintln!("Error: Unknown uniqueness '{}'.", name);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let (Some(ident), Some(type_name)) = (args.get(2), args.get(3)) else {
+                print_help();
+                return;
+            };
+            let Some(value_type) = ValueType::parse(type_name) else {
+                eprintln!("Error: Unknown value type '{}'.", type_name);
+                return;
+            };
+            let attr = Attribute {
+                ident: ident.clone(),
+                value_type,
+                cardinality: if many {
+                    Cardinality::Many
+                } else {
+                    Cardinality::One
+                },
+                unique,
+            };
+            match db::declare_attribute(db, attr.clone()).await {
+                Ok(()) => println!("{}", attr),
+                Err(err) => eprintln!("Failed to declare {}: {}", ident, err),
+            }
+        }
+        "schema" => match db::attributes(db).await {
+            Ok(attrs) => {
+                for attr in attrs {
+                    println!("{}", attr);
+                }
+            }
+            Err(err) => eprintln!("Failed to read the schema: {}", err),
+        },
+        "pull" => {
+            let Some(pattern) = args.get(1) else {
+                eprintln!("Error: 'pull' requires a pattern like \"[*]\".");
+                print_help();
+                return;
+            };
+            let pattern = match Pattern::parse(pattern) {
+                Ok(pattern) => pattern,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    return;
+                }
+            };
+            let target = args.get(2).cloned().unwrap_or_else(today_date);
+            // a date means the journal page, anything else is an entity id
+            let entity_id = if NaiveDate::parse_from_str(&target, "%Y-%m-%d").is_ok() {
+                page_id_for(&target)
+            } else {
+                target
+            };
+            match db::pull(db, &pattern, &json!(entity_id)).await {
+                Ok(Some(entity)) => match serde_json::to_string_pretty(&entity) {
+                    Ok(text) => println!("{}", text),
+                    Err(err) => eprintln!("Failed to print {}: {}", entity_id, err),
+                },
+                Ok(None) => println!("No entity {}", entity_id),
+                Err(err) => eprintln!("Failed to pull {}: {}", entity_id, err),
+            }
+        }
+        "query" => {
+            let Some(text) = args.get(1) else {
+                eprintln!("Error: 'query' requires a query like '{{:find [?c] :where [...]}}'.");
+                print_help();
+                return;
+            };
+            let query = match Query::parse(text) {
+                Ok(query) => query,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    return;
+                }
+            };
+            match db::query(db, &query).await {
+                Ok(rows) => {
+                    for row in rows {
+                        println!("{}", Value::Array(row));
+                    }
+                }
+                Err(err) => eprintln!("Failed to run the query: {}", err),
+            }
+        }
+        "transact" => {
+            let text = match args.get(1) {
+                Some(path) => fs::read_to_string(path),
+                None => io::read_to_string(io::stdin()),
+            };
+            let text = match text {
+                Ok(text) => text,
+                Err(err) => {
+                    eprintln!("Failed to read the transaction: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            let datoms = match serde_json::from_str::<Vec<Value>>(&text) {
+                Ok(datoms) => datoms,
+                Err(err) => {
+                    eprintln!("Invalid transaction, expected a JSON list: {}", err);
+                    std::process::exit(2);
+                }
+            };
+            let meta = tx_meta(&config, "transact", &message);
+            match transact_with_fallback(db, datoms, meta).await {
+                Ok(report) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).unwrap_or_default()
+                ),
+                Err(err) => {
+                    eprintln!("Failed to transact: {}", err);
+                    std::process::exit(err.exit_code());
+                }
+            }
+        }
+        "serve" => {
+            let mut args = args.clone();
+            let addr =
+                take_flag(&mut args, "--bind").unwrap_or_else(|| "127.0.0.1:7878".to_string());
+            let memory = args.iter().any(|arg| arg == "--memory");
+            if memory {
+                println!("Serving an in-memory copy of the vault on http://{}", addr);
+            } else {
+                println!("Serving the jottty API on http://{}", addr);
+            }
+            if let Err(err) = serve(db, &addr, memory).await {
+                eprintln!("Failed to serve on {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        }
+        "log" => {
+            let mut args = args.clone();
+            let author = take_flag(&mut args, "--author");
+            let source = take_flag(&mut args, "--source");
+            let limit = match args.get(1).map(|n| n.parse::<usize>()) {
+                None => 20,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => {
+                    eprintln!("Error: 'log' expects a number of transactions.");
+                    print_help();
+                    return;
+                }
+            };
+            let filter = TxFilter {
+                author,
+                source,
+                limit: Some(limit),
+            };
+            match db::transactions(db, &filter).await {
+                Ok(txs) => print_log(txs),
+                Err(err) => eprintln!("Failed to read the tx log: {}", err),
+            }
+        }
+        "gc" => {
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            match db::gc(db, dry_run).await {
+                Ok(removed) if dry_run => {
+                    for id in &removed {
+                        println!("{}", id);
+                    }
+                    println!("Would remove {} unreachable entities", removed.len());
+                }
+                Ok(removed) => println!("Removed {} unreachable entities", removed.len()),
+                Err(err) => eprintln!("Failed to collect garbage: {}", err),
+            }
+        }
+        "db" => match args.get(1).map(String::as_str) {
+            Some("migrate") if args.get(2).map(String::as_str) == Some("--status") => {
+                let status = match db::migration_status(db).await {
+                    Ok(status) => status,
+                    Err(err) => {
+                        eprintln!("Failed to read migration status: {}", err);
+                        return;
+                    }
+                };
+                println!("schema version {}", db::schema_version(&status));
+                for migration in status {
+                    let state = migration
+                        .installed_on
+                        .unwrap_or_else(|| "pending".to_string());
+                    println!(
+                        "  {:04} {:<24} {}",
+                        migration.version, migration.description, state
+                    );
+                }
+            }
+            Some("check") => match db::check_collisions(db).await {
+                Ok(collisions) if collisions.is_empty() => println!("No collisions found"),
+                Ok(collisions) => {
+                    for collision in collisions {
+                        println!("{}", collision);
+                    }
+                }
+                Err(err) => eprintln!("Failed to check the vault: {}", err),
+            },
+            Some("reencode") => {
+                let encoding = match args.get(2) {
+                    Some(name) => match Encoding::parse(name) {
+                        Some(encoding) => encoding,
+                        None => {
+                            eprintln!("Error: Unknown encoding '{}'.", name);
+                            return;
+                        }
+                    },
+                    None => db::vault_encoding(),
+                };
+                match db::reencode(db, encoding).await {
+                    Ok(rewritten) => {
+                        println!("Reencoded {} entities as {}", rewritten, encoding.as_str())
+                    }
+                    Err(err) => eprintln!("Failed to reencode the vault: {}", err),
+                }
+            }
+            Some("migrate") => match db::migrate(db).await {
+                Ok(version) => println!("Schema is up to date (version {})", version),
+                Err(err) => eprintln!("Failed to migrate database: {}", err),
+            },
+            _ => print_help(),
+        },
         _ => {
             eprintln!("Error: Unknown command '{}'.", args[0]);
             print_help();
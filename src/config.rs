@@ -10,12 +10,15 @@ use std::{
 struct ConfigFile {
     bullet: Option<String>,
     editor: Option<String>,
+    author: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub bullet: String,
     pub editor: String,
+    /// The name recorded as the author of the transactions made from the CLI.
+    pub author: Option<String>,
 }
 
 pub fn default_dir() -> PathBuf {
@@ -65,11 +68,13 @@ impl Config {
         let parsed: ConfigFile = toml::from_str(&contents).unwrap_or(ConfigFile {
             bullet: None,
             editor: None,
+            author: None,
         });
 
         Ok(Config {
             bullet: parsed.bullet.unwrap_or_else(default_bullet),
             editor: parsed.editor.unwrap_or_else(default_editor),
+            author: parsed.author,
         })
     }
 }
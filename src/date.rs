@@ -1,6 +1,6 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use chrono::{Local, NaiveDate};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
 
 ///today_date returns the current date in "YYYY-MM-DD" format.
 pub fn today_date() -> String {
@@ -15,6 +15,14 @@ pub fn now_nanos() -> u128 {
         .unwrap_or(0)
 }
 
+/// now_millis returns the current time in milliseconds since the UNIX epoch.
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
 /// page_id_for generates a page ID string for a given date.
 pub fn page_id_for(date: &str) -> String {
     format!("page:{}", date)
@@ -31,3 +39,40 @@ pub fn date_str_format(date: &str) -> String {
         date.to_string()
     }
 }
+
+/// format_millis formats milliseconds since the UNIX epoch as a local
+/// "YYYY-MM-DD HH:MM:SS" timestamp.
+pub fn format_millis(millis: i64) -> String {
+    Local
+        .timestamp_millis_opt(millis)
+        .single()
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| millis.to_string())
+}
+
+/// parse_instant parses a point in time given on the command line into
+/// milliseconds since the UNIX epoch.
+/// It accepts RFC 3339 timestamps, "YYYY-MM-DDTHH:MM[:SS]" in local time,
+/// and plain "YYYY-MM-DD", which means the end of that day.
+pub fn parse_instant(input: &str) -> Option<i64> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(input) {
+        return Some(dt.timestamp_millis());
+    }
+    let naive = [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|fmt| NaiveDateTime::parse_from_str(input, fmt).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(input, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_milli_opt(23, 59, 59, 999))
+    })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|dt| dt.timestamp_millis())
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+
+use sqlx::{Row, SqliteConnection};
+
+use crate::db::Db;
+use crate::db::datom::addr_for_entity_id;
+use crate::db::transit;
+
+/// An addr problem found in the vaults table.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Collision {
+    /// The entity collided with another one and lives at a probed addr.
+    Probed {
+        entity_id: String,
+        home: i64,
+        addr: i64,
+    },
+    /// The row's content belongs to another entity than its entity_id column.
+    Mismatch {
+        addr: i64,
+        entity_id: String,
+        content_id: String,
+    },
+    /// The entity is in the log or the index but has no row, and its home
+    /// addr holds another entity: it was overwritten by a collision.
+    Lost {
+        entity_id: String,
+        addr: i64,
+        holder: String,
+    },
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Collision::Probed {
+                entity_id,
+                home,
+                addr,
+            } => write!(
+                f,
+                "{} collides at addr {} and is stored at addr {}",
+                entity_id, home, addr
+            ),
+            Collision::Mismatch {
+                addr,
+                entity_id,
+                content_id,
+            } => write!(
+                f,
+                "addr {} is stored for {} but holds {}",
+                addr, entity_id, content_id
+            ),
+            Collision::Lost {
+                entity_id,
+                addr,
+                holder,
+            } => write!(
+                f,
+                "{} was overwritten by {} at addr {}",
+                entity_id, holder, addr
+            ),
+        }
+    }
+}
+
+/// check_collisions_on() scans the vaults table, the tx log and the datoms
+/// index for entities affected by addr collisions.
+async fn check_collisions_on(conn: &mut SqliteConnection) -> Result<Vec<Collision>, sqlx::Error> {
+    let mut collisions = Vec::new();
+
+    let rows = sqlx::query("SELECT addr, entity_id, content FROM vaults ORDER BY addr;")
+        .fetch_all(&mut *conn)
+        .await?;
+    for row in &rows {
+        let addr: i64 = row.get("addr");
+        let Some(entity_id) = row.get::<Option<String>, _>("entity_id") else {
+            continue;
+        };
+        let content_id = transit::decode_value(row.get("content"))
+            .ok()
+            .and_then(|value| value.get("id")?.as_str().map(str::to_string));
+        if let Some(content_id) = content_id.filter(|id| *id != entity_id) {
+            collisions.push(Collision::Mismatch {
+                addr,
+                entity_id: entity_id.clone(),
+                content_id,
+            });
+        }
+        let home = addr_for_entity_id(&entity_id);
+        if home != addr {
+            collisions.push(Collision::Probed {
+                entity_id,
+                home,
+                addr,
+            });
+        }
+    }
+
+    let lost = sqlx::query(
+        "SELECT e FROM (SELECT e FROM tx_datoms UNION SELECT e FROM datoms)
+        WHERE e NOT IN (SELECT entity_id FROM vaults WHERE entity_id IS NOT NULL)
+        ORDER BY e;",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for row in lost {
+        let entity_id: String = row.get("e");
+        let addr = addr_for_entity_id(&entity_id);
+        let holder = sqlx::query("SELECT entity_id FROM vaults WHERE addr = ?;")
+            .bind(addr)
+            .fetch_optional(&mut *conn)
+            .await?
+            .and_then(|row| row.get::<Option<String>, _>("entity_id"));
+        if let Some(holder) = holder.filter(|holder| *holder != entity_id) {
+            collisions.push(Collision::Lost {
+                entity_id,
+                addr,
+                holder,
+            });
+        }
+    }
+
+    Ok(collisions)
+}
+
+/// check_collisions() reports every entity of the vault affected by an addr
+/// collision. An empty result means no collisions were found.
+pub async fn check_collisions(db: &Db) -> Result<Vec<Collision>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    check_collisions_on(&mut conn).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_conn;
+
+    async fn insert_row(conn: &mut SqliteConnection, addr: i64, entity_id: &str, content_id: &str) {
+        sqlx::query(
+            "INSERT INTO vaults (addr, entity_id, content, addresses) VALUES (?, ?, ?, '[]');",
+        )
+        .bind(addr)
+        .bind(entity_id)
+        .bind(format!(r#"{{"id":"{}","attrs":{{}}}}"#, content_id))
+        .execute(conn)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn clean_vault_has_no_collisions() {
+        let mut conn = test_conn().await;
+        insert_row(
+            &mut conn,
+            addr_for_entity_id("block:1"),
+            "block:1",
+            "block:1",
+        )
+        .await;
+        assert!(check_collisions_on(&mut conn).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_probed_mismatched_and_lost_entities() {
+        let mut conn = test_conn().await;
+        let home = addr_for_entity_id("block:2");
+        insert_row(&mut conn, home, "block:1", "block:1").await;
+        insert_row(&mut conn, home.wrapping_add(1), "block:3", "block:4").await;
+        sqlx::query(
+            "INSERT INTO datoms (e, a, v) VALUES ('block:2', 'block/content', '\"lost\"');",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+        let collisions = check_collisions_on(&mut conn).await.unwrap();
+        assert!(collisions.contains(&Collision::Probed {
+            entity_id: "block:1".to_string(),
+            home: addr_for_entity_id("block:1"),
+            addr: home,
+        }));
+        assert!(collisions.contains(&Collision::Mismatch {
+            addr: home.wrapping_add(1),
+            entity_id: "block:3".to_string(),
+            content_id: "block:4".to_string(),
+        }));
+        assert!(collisions.contains(&Collision::Lost {
+            entity_id: "block:2".to_string(),
+            addr: home,
+            holder: "block:1".to_string(),
+        }));
+    }
+}
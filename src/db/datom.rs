@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
@@ -6,8 +6,15 @@ use sqlx::Connection;
 use sqlx::Row;
 use sqlx::SqliteConnection;
 
-use crate::db::conn;
+use crate::db::error::TxError;
+use crate::db::index::{self, SearchHit};
+use crate::db::log::{self, TxKind, TxMeta};
+use crate::db::lookup;
+use crate::db::schema::{self, Schema};
+use crate::db::storage::Storage;
+use crate::db::tempid;
 use crate::db::transit;
+use crate::db::{Db, retry_busy, vault_encoding};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entity {
@@ -15,12 +22,23 @@ pub struct Entity {
     pub attrs: Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DatomOp {
     Add,
     Retract,
 }
 
+impl DatomOp {
+    /// as_str() returns the op name used in transactions and in the log.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DatomOp::Add => "db/add",
+            DatomOp::Retract => "db/retract",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Datom {
     pub op: DatomOp,
     pub e: String,
@@ -28,6 +46,56 @@ pub struct Datom {
     pub v: Value,
 }
 
+/// An operation of a transaction: a datom to add or retract, or one of the
+/// ops that are expanded into datoms against the entities' current state.
+#[derive(Debug, Clone)]
+pub enum TxOp {
+    Datom(Datom),
+    /// `db/retractEntity`: retracts every attribute of the entity and every
+    /// ref attribute pointing at it.
+    RetractEntity(String),
+    /// `db/cas`: sets `a` to `new` only if its current value is `old`
+    /// (None: not set). Fails the transaction otherwise.
+    Cas {
+        e: String,
+        a: String,
+        old: Option<Value>,
+        new: Value,
+    },
+    /// An op on the entity given by the lookup ref `[a, v]`: the entity that
+    /// holds the unique value `v` of attribute `a`. The entity slot of `op`
+    /// is filled in once the lookup ref is resolved.
+    Lookup {
+        a: String,
+        v: Value,
+        op: Box<TxOp>,
+    },
+}
+
+impl TxOp {
+    pub(crate) fn entity_id(&self) -> &str {
+        match self {
+            TxOp::Datom(datom) => &datom.e,
+            TxOp::RetractEntity(e) | TxOp::Cas { e, .. } => e,
+            TxOp::Lookup { op, .. } => op.entity_id(),
+        }
+    }
+
+    /// with_entity() returns the op with `e` in its entity slot.
+    pub fn with_entity(self, e: String) -> TxOp {
+        match self {
+            TxOp::Datom(datom) => TxOp::Datom(Datom { e, ..datom }),
+            TxOp::RetractEntity(_) => TxOp::RetractEntity(e),
+            TxOp::Cas { a, old, new, .. } => TxOp::Cas { e, a, old, new },
+            TxOp::Lookup { a, v, op } => TxOp::Lookup {
+                a,
+                v,
+                op: Box::new(op.with_entity(e)),
+            },
+        }
+    }
+}
+
 /// FNV-1a 64-bit hash function
 /// See: https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
 /// That block computes a stable address (addr) for each entity ID so it can be stored in the vaults table:
@@ -41,7 +109,9 @@ fn fnv1a_hash64(input: &str) -> u64 {
 }
 
 /// addr_for_entity_id() converts an entity ID string (like block:...) into an i64 address.
-fn addr_for_entity_id(entity_id: &str) -> i64 {
+/// This is the entity's home addr; if another entity already lives there the
+/// entity is stored at the next free addr instead, see resolve_addrs().
+pub(crate) fn addr_for_entity_id(entity_id: &str) -> i64 {
     fnv1a_hash64(entity_id) as i64
 }
 
@@ -52,68 +122,233 @@ fn entity_to_value(entity: &Entity) -> Value {
     Value::Object(map)
 }
 
-fn value_to_entity(value: Value) -> Option<Entity> {
+pub(crate) fn value_to_entity(value: Value) -> Option<Entity> {
     let obj = value.as_object()?;
     let id = obj.get("id")?.as_str()?.to_string();
     let attrs = obj.get("attrs")?.as_object()?.clone();
     Some(Entity { id, attrs })
 }
-/// write_entity() writes an Entity into the vaults table.
-/// It encodes the entity as a transit value and stores it in the content column.
-/// The addresses column is set to an empty array for now.
+
+/// The outcome of apply_ops(): the id of the transaction recorded in the log,
+/// the entities as they are after it and the ids the tempids resolved to.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxReport {
+    pub tx: i64,
+    pub entities: Vec<Entity>,
+    pub tempids: BTreeMap<String, String>,
+}
+/// Maximum number of rows written by a single multi-row INSERT, so a batch
+/// stays well under SQLite's limit on bound parameters.
+pub(crate) const BATCH_SIZE: usize = 200;
+
+/// entity_refs() returns the ids of the entities `entity` refers to through
+/// one of the ref attributes of the schema.
+pub(crate) fn entity_refs<'a>(entity: &'a Entity, schema: &Schema) -> Vec<&'a str> {
+    schema
+        .ref_attrs()
+        .filter_map(|attr| entity.attrs.get(attr))
+        .flat_map(|v| match v {
+            Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+            v => v.as_str().into_iter().collect::<Vec<_>>(),
+        })
+        .collect()
+}
+
+/// encode_addresses() encodes the addrs of the entities `entity` refers to as
+/// the JSON array stored in the addresses column. Refs to entities without an
+/// addr in `addrs` are left out.
+pub(crate) fn encode_addresses(
+    entity: &Entity,
+    addrs: &HashMap<String, i64>,
+    schema: &Schema,
+) -> String {
+    let mut refs: Vec<i64> = entity_refs(entity, schema)
+        .into_iter()
+        .filter_map(|id| addrs.get(id).copied())
+        .collect();
+    refs.sort();
+    refs.dedup();
+    Value::from(refs).to_string()
+}
+
+/// existing_addrs() returns the addrs the given entities are stored at.
+/// Entities without a row in the vaults table are left out.
+/// # Errors
+/// Returns an error if the SQL query fails.
+pub(crate) async fn existing_addrs(
+    conn: &mut SqliteConnection,
+    entity_ids: &[&str],
+) -> Result<HashMap<String, i64>, sqlx::Error> {
+    let mut addrs = HashMap::with_capacity(entity_ids.len());
+    for chunk in entity_ids.chunks(BATCH_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!(
+            "SELECT entity_id, addr FROM vaults WHERE entity_id IN ({});",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for id in chunk {
+            query = query.bind(*id);
+        }
+        for row in query.fetch_all(&mut *conn).await? {
+            addrs.insert(row.get::<String, _>("entity_id"), row.get::<i64, _>("addr"));
+        }
+    }
+    Ok(addrs)
+}
+
+/// resolve_addrs() returns the addr every entity is stored at.
+/// Entities already in the vaults table keep their addr. New entities get
+/// their home addr from addr_for_entity_id(), or, when that addr holds a
+/// different entity (an FNV collision), the next free addr found by linear
+/// probing. Addrs claimed by earlier ids in the same call are skipped too.
+/// # Errors
+/// Returns an error if the SQL query fails.
+async fn resolve_addrs(
+    conn: &mut SqliteConnection,
+    entity_ids: &[&str],
+) -> Result<HashMap<String, i64>, sqlx::Error> {
+    let mut addrs = existing_addrs(conn, entity_ids).await?;
+    let mut claimed: HashSet<i64> = addrs.values().copied().collect();
+    for id in entity_ids {
+        if addrs.contains_key(*id) {
+            continue;
+        }
+        let mut addr = addr_for_entity_id(id);
+        loop {
+            let taken = claimed.contains(&addr)
+                || sqlx::query("SELECT 1 FROM vaults WHERE addr = ?;")
+                    .bind(addr)
+                    .fetch_optional(&mut *conn)
+                    .await?
+                    .is_some();
+            if !taken {
+                break;
+            }
+            addr = addr.wrapping_add(1);
+        }
+        claimed.insert(addr);
+        addrs.insert(id.to_string(), addr);
+    }
+    Ok(addrs)
+}
+
+/// write_entities() writes Entities into the vaults table.
+/// It encodes each entity with the configured vault_encoding() and stores it in the content column,
+/// and the addrs of the entities it refers to in the addresses column.
+/// Entities are written with one multi-row INSERT per BATCH_SIZE entities, at
+/// the addrs picked by resolve_addrs(). A row is only ever overwritten by the
+/// entity it belongs to; anything else is reported as a collision error.
 ///
 /// Returns Result<(), sqlx::Error>
 /// # Arguments
 /// * `conn` - A mutable reference to a SqliteConnection.
-/// * `entity` - A reference to the Entity to write.
+/// * `entities` - The Entities to write.
+/// * `schema` - The schema, to tell which attributes are refs.
 /// # Errors
-/// Returns an error if the SQL query fails or if encoding fails.
-async fn write_entity(conn: &mut SqliteConnection, entity: &Entity) -> Result<(), sqlx::Error> {
-    let addr = addr_for_entity_id(&entity.id);
-    let content = match transit::encode_value(&entity_to_value(entity)) {
-        Ok(value) => value,
-        Err(err) => return Err(sqlx::Error::Protocol(err.to_string())),
-    };
-    let addresses = "[]";
-
-    sqlx::query(
-        "INSERT INTO vaults (addr, content, addresses)
-        VALUES (?, ?, ?)
-        ON CONFLICT(addr) DO UPDATE SET content = excluded.content, addresses = excluded.addresses;",
-    )
-    .bind(addr)
-    .bind(content)
-    .bind(addresses)
-    .execute(conn)
-    .await?;
+/// Returns an error if the SQL query fails, if encoding fails or if an addr
+/// holds a different entity.
+pub(crate) async fn write_entities(
+    conn: &mut SqliteConnection,
+    entities: &[Entity],
+    schema: &Schema,
+) -> Result<(), sqlx::Error> {
+    let ids: Vec<&str> = entities.iter().map(|entity| entity.id.as_str()).collect();
+    let mut addrs = resolve_addrs(conn, &ids).await?;
+    let refs: Vec<&str> = entities
+        .iter()
+        .flat_map(|entity| entity_refs(entity, schema))
+        .filter(|id| !addrs.contains_key(*id))
+        .collect();
+    addrs.extend(existing_addrs(conn, &refs).await?);
+
+    let encoding = vault_encoding();
+    for chunk in entities.chunks(BATCH_SIZE) {
+        let placeholders = vec!["(?, ?, ?, ?)"; chunk.len()].join(", ");
+        let sql = format!(
+            "INSERT INTO vaults (addr, entity_id, content, addresses)
+            VALUES {}
+            ON CONFLICT(addr) DO UPDATE SET content = excluded.content, addresses = excluded.addresses
+            WHERE vaults.entity_id = excluded.entity_id;",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for entity in chunk {
+            let content = match transit::encode_value(&entity_to_value(entity), encoding, schema) {
+                Ok(value) => value,
+                Err(err) => return Err(sqlx::Error::Protocol(err.to_string())),
+            };
+            query = query
+                .bind(addrs[&entity.id])
+                .bind(&entity.id)
+                .bind(content)
+                .bind(encode_addresses(entity, &addrs, schema));
+        }
+        let written = query.execute(&mut *conn).await?.rows_affected();
+        if written != chunk.len() as u64 {
+            return Err(sqlx::Error::Protocol(
+                "Entity addr collision: a vault row holds a different entity".to_string(),
+            ));
+        }
+    }
 
     Ok(())
 }
 
-/// load_entity() loads an Entity from the vaults table by its entity ID.
-/// If the entity is not found, it returns an empty Entity with the given ID.
-/// # Arguments
-/// * `conn` - A mutable reference to a SqliteConnection.
-/// * `entity_id` - A string slice representing the entity ID.
+/// decode_row_entity() decodes the content of a vaults row and checks that it
+/// holds the entity its entity_id column says it does.
+fn decode_row_entity(entity_id: &str, content: &[u8]) -> Result<Option<Entity>, sqlx::Error> {
+    let Some(entity) = transit::decode_value(content)
+        .ok()
+        .and_then(value_to_entity)
+    else {
+        return Ok(None);
+    };
+    if entity.id != entity_id {
+        return Err(sqlx::Error::Protocol(format!(
+            "Vault row for {} holds entity {}",
+            entity_id, entity.id
+        )));
+    }
+    Ok(Some(entity))
+}
+
+/// load_entities() loads several Entities from the vaults table by id, in one
+/// query per BATCH_SIZE ids.
+/// Entities that are not found are returned empty.
 /// # Errors
-/// Returns an error if the SQL query fails or if decoding fails.
-async fn load_entity(conn: &mut SqliteConnection, entity_id: &str) -> Result<Entity, sqlx::Error> {
-    let addr = addr_for_entity_id(entity_id);
-    let row = sqlx::query("SELECT content FROM vaults WHERE addr = ?;")
-        .bind(addr)
-        .fetch_optional(conn)
-        .await?;
+/// Returns an error if the SQL query fails or if a row holds another entity
+/// than the one it is stored for.
+pub(crate) async fn load_entities(
+    conn: &mut SqliteConnection,
+    entity_ids: &[&str],
+) -> Result<HashMap<String, Entity>, sqlx::Error> {
+    let mut entities = HashMap::with_capacity(entity_ids.len());
+    for chunk in entity_ids.chunks(BATCH_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!(
+            "SELECT entity_id, content FROM vaults WHERE entity_id IN ({});",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for id in chunk {
+            query = query.bind(*id);
+        }
+        for row in query.fetch_all(&mut *conn).await? {
+            let entity_id: String = row.get("entity_id");
+            if let Some(entity) = decode_row_entity(&entity_id, row.get("content"))? {
+                entities.insert(entity.id.clone(), entity);
+            }
+        }
+    }
 
-    Ok(row
-        .and_then(|row| {
-            transit::decode_value(&row.get::<String, _>("content"))
-                .ok()
-                .and_then(value_to_entity)
-        })
-        .unwrap_or_else(|| Entity {
-            id: entity_id.to_string(),
+    for id in entity_ids {
+        entities.entry(id.to_string()).or_insert_with(|| Entity {
+            id: id.to_string(),
             attrs: Map::new(),
-        }))
+        });
+    }
+    Ok(entities)
 }
 
 //TODO@chico: write tests for scan_entities
@@ -122,7 +357,7 @@ async fn load_entity(conn: &mut SqliteConnection, entity_id: &str) -> Result<Ent
 /// It decodes each entity and collects them into a vector.
 /// # Arguments
 /// * `conn` - A mutable reference to a SqliteConnection.
-async fn scan_entities(conn: &mut SqliteConnection) -> Result<Vec<Entity>, sqlx::Error> {
+pub(crate) async fn scan_entities(conn: &mut SqliteConnection) -> Result<Vec<Entity>, sqlx::Error> {
     let rows = sqlx::query("SELECT content FROM vaults;")
         .fetch_all(conn)
         .await?;
@@ -130,8 +365,7 @@ async fn scan_entities(conn: &mut SqliteConnection) -> Result<Vec<Entity>, sqlx:
     let entities: Vec<Entity> = rows
         .iter()
         .filter_map(|row| {
-            let content: String = row.get("content");
-            transit::decode_value(&content)
+            transit::decode_value(row.get("content"))
                 .ok()
                 .and_then(value_to_entity)
         })
@@ -140,67 +374,523 @@ async fn scan_entities(conn: &mut SqliteConnection) -> Result<Vec<Entity>, sqlx:
     Ok(entities)
 }
 
-//// apply_datoms() applies a list of Datoms to the database.
-/// It groups the datoms by entity ID, loads each entity, applies the datoms,
-/// and writes the updated entity back to the database.
-/// Returns a Result containing a vector of updated Entities or an error.
-///
-/// # Arguments
-/// * `datoms` - A slice of Datoms to apply.
-///     - Each Datom specifies an operation (Add or Retract), an entity ID (e),
-///     an attribute (a), and a value (v).
-///     - For Add operations, the attribute-value pair is added to the entity.
-///     - For Retract operations, the attribute is removed from the entity.
-///     - If an entity does not exist, it is created with the given ID.
-///     - After applying all datoms, the updated entities are written back to the database.
-///     - The function returns a vector of the updated entities.
-///     - Datom examples:
-///     - Add datom: Datom { op: DatomOp::Add, e: "block:page-id".to_string(), a: "block/title".to_string(), v: Value::String("Journal".to_string()) }
+/// values_of() returns the values of a cardinality-many attribute, which are
+/// stored as an array. A single value (written before the attribute was
+/// declared many) counts as a set of one.
+fn values_of(value: Option<&Value>) -> Vec<Value> {
+    match value {
+        None => Vec::new(),
+        Some(Value::Array(values)) => values.clone(),
+        Some(value) => vec![value.clone()],
+    }
+}
+
+/// set_values() stores the values of a cardinality-many attribute, removing
+/// the attribute when there are none left.
+fn set_values(entity: &mut Entity, a: &str, values: Vec<Value>) {
+    if values.is_empty() {
+        entity.attrs.remove(a);
+    } else {
+        entity.attrs.insert(a.to_string(), Value::Array(values));
+    }
+}
+
+/// apply_many() applies a datom on a cardinality-many attribute: an Add puts
+/// the value in the set and a Retract takes only that value out.
+fn apply_many(entity: &mut Entity, datom: &Datom) -> Vec<Datom> {
+    let mut values = values_of(entity.attrs.get(&datom.a));
+    let changed = match datom.op {
+        DatomOp::Add if !values.contains(&datom.v) => {
+            values.push(datom.v.clone());
+            true
+        }
+        DatomOp::Retract if values.contains(&datom.v) => {
+            values.retain(|v| *v != datom.v);
+            true
+        }
+        _ => false,
+    };
+    if !changed {
+        return Vec::new();
+    }
+    set_values(entity, &datom.a, values);
+    vec![Datom {
+        e: entity.id.clone(),
+        ..datom.clone()
+    }]
+}
+
+/// apply_to_entity() applies a single datom to an in-memory entity.
+/// It returns the datoms that actually changed the entity, which is what gets
+/// written to the transaction log: an Add that replaces a value logs a Retract
+/// of the old value followed by the Add, a Retract only removes the value if
+/// it is the current one, and datoms that change nothing log nothing.
+/// Cardinality-many attributes hold a set of values, see apply_many().
+fn apply_to_entity(entity: &mut Entity, datom: &Datom, schema: &Schema) -> Vec<Datom> {
+    if schema.is_many(&datom.a) {
+        return apply_many(entity, datom);
+    }
+    let mut changes = Vec::new();
+    match datom.op {
+        DatomOp::Add => match entity.attrs.insert(datom.a.clone(), datom.v.clone()) {
+            Some(old) if old == datom.v => {}
+            Some(old) => {
+                changes.push(Datom {
+                    op: DatomOp::Retract,
+                    e: entity.id.clone(),
+                    a: datom.a.clone(),
+                    v: old,
+                });
+                changes.push(Datom {
+                    op: DatomOp::Add,
+                    e: entity.id.clone(),
+                    a: datom.a.clone(),
+                    v: datom.v.clone(),
+                });
+            }
+            None => changes.push(Datom {
+                op: DatomOp::Add,
+                e: entity.id.clone(),
+                a: datom.a.clone(),
+                v: datom.v.clone(),
+            }),
+        },
+        DatomOp::Retract => {
+            if entity.attrs.get(&datom.a) == Some(&datom.v) {
+                entity.attrs.remove(&datom.a);
+                changes.push(Datom {
+                    op: DatomOp::Retract,
+                    e: entity.id.clone(),
+                    a: datom.a.clone(),
+                    v: datom.v.clone(),
+                });
+            }
+        }
+    }
+    changes
+}
+
+/// The entities a transaction works on, loaded on first use and kept in the
+/// order they were first touched.
+struct WorkingSet {
+    order: Vec<String>,
+    entities: HashMap<String, Entity>,
+}
+
+impl WorkingSet {
+    async fn entity<S: Storage>(
+        &mut self,
+        storage: &mut S,
+        entity_id: &str,
+    ) -> Result<&mut Entity, sqlx::Error> {
+        if !self.entities.contains_key(entity_id) {
+            let entity = storage.load_entity(entity_id).await?;
+            self.entities.insert(entity_id.to_string(), entity);
+        }
+        if !self.order.iter().any(|id| id == entity_id) {
+            self.order.push(entity_id.to_string());
+        }
+        Ok(self
+            .entities
+            .get_mut(entity_id)
+            .expect("entity was just loaded"))
+    }
+
+    fn into_entities(mut self) -> Vec<Entity> {
+        self.order
+            .iter()
+            .filter_map(|id| self.entities.remove(id))
+            .collect()
+    }
+}
+
+/// retract_entity() retracts every attribute of `entity_id` and every ref
+/// attribute of another entity that points at it.
+async fn retract_entity<S: Storage>(
+    storage: &mut S,
+    working: &mut WorkingSet,
+    schema: &Schema,
+    entity_id: &str,
+) -> Result<Vec<Datom>, sqlx::Error> {
+    let target = Value::from(entity_id);
+    let ref_attrs: Vec<&str> = schema.ref_attrs().collect();
+
+    // refs as they are stored, plus the ones added earlier in this transaction
+    let mut refs = Vec::new();
+    for a in &ref_attrs {
+        for e in storage.entities_with(a, &target).await? {
+            refs.push((e, a.to_string()));
+        }
+    }
+    for entity in working.entities.values() {
+        for a in &ref_attrs {
+            if values_of(entity.attrs.get(*a)).contains(&target) {
+                refs.push((entity.id.clone(), a.to_string()));
+            }
+        }
+    }
+    refs.sort();
+    refs.dedup();
+
+    let mut changes = Vec::new();
+    for (e, a) in refs {
+        let datom = Datom {
+            op: DatomOp::Retract,
+            e,
+            a,
+            v: target.clone(),
+        };
+        let entity = working.entity(storage, &datom.e).await?;
+        changes.extend(apply_to_entity(entity, &datom, schema));
+    }
+
+    let entity = working.entity(storage, entity_id).await?;
+    for (a, v) in entity.attrs.clone() {
+        let values = if schema.is_many(&a) {
+            values_of(Some(&v))
+        } else {
+            vec![v]
+        };
+        for v in values {
+            let datom = Datom {
+                op: DatomOp::Retract,
+                e: entity_id.to_string(),
+                a: a.clone(),
+                v,
+            };
+            changes.extend(apply_to_entity(entity, &datom, schema));
+        }
+    }
+    Ok(changes)
+}
+
+/// compare_and_set() applies a `db/cas` op: `a` is set to `new` if its
+/// current value is `old`.
 /// # Errors
-/// Returns an error if any SQL query fails or if loading/writing entities fails.
-pub async fn apply_datoms(datoms: &[Datom]) -> Result<Vec<Entity>, sqlx::Error> {
-    // we are assuming the db was create and schema ensured at startup.
-    // TODO@chico: can improve the apply_datoms performance by batching the writes in a transaction.
-    // TODO@chico: this functions can receive a &mut SqliteConnection to avoid opening a new connection each time.
-    let mut conn = conn().await;
-
-    let mut grouped: HashMap<String, Vec<&Datom>> = HashMap::new();
-    for datom in datoms {
-        grouped.entry(datom.e.clone()).or_default().push(datom);
-    }
-    let mut updated = Vec::with_capacity(grouped.len());
-    for (entity_id, entity_datoms) in grouped {
-        let mut entity = load_entity(&mut conn, &entity_id).await?;
-        for datom in entity_datoms {
-            match datom.op {
-                DatomOp::Add => {
-                    entity.attrs.insert(datom.a.clone(), datom.v.clone());
-                }
-                DatomOp::Retract => {
-                    entity.attrs.remove(&datom.a);
+/// Returns a TxError::Constraint when the current value is not the expected
+/// one, and a TxError::Schema when the attribute is cardinality-many or when
+/// `new` violates the schema. The errors have index 0, see TxError::at().
+fn compare_and_set(
+    entity: &mut Entity,
+    schema: &Schema,
+    a: &str,
+    old: Option<&Value>,
+    new: &Value,
+) -> Result<Vec<Datom>, TxError> {
+    if schema.is_many(a) {
+        return Err(TxError::Schema {
+            index: 0,
+            message: format!(
+                "db/cas is not supported on cardinality-many attribute {}",
+                a
+            ),
+        });
+    }
+    let current = entity.attrs.get(a);
+    if current != old {
+        let show = |v: Option<&Value>| v.map_or("unset".to_string(), Value::to_string);
+        return Err(TxError::Constraint {
+            index: 0,
+            message: format!(
+                "CAS failed: {} {} is {}, expected {}",
+                entity.id,
+                a,
+                show(current),
+                show(old)
+            ),
+        });
+    }
+    let add = Datom {
+        op: DatomOp::Add,
+        e: entity.id.clone(),
+        a: a.to_string(),
+        v: new.clone(),
+    };
+    let mut changes = Vec::new();
+    let conformed = schema
+        .conform(&add)
+        .map_err(|message| TxError::Schema { index: 0, message })?;
+    for datom in conformed {
+        changes.extend(apply_to_entity(entity, &datom, schema));
+    }
+    Ok(changes)
+}
+
+/// transact_on() runs the ops of a transaction against `storage` and returns
+/// its report. It writes as it goes, so the caller must drop the writes when
+/// it fails: apply_ops_on() runs it in an SQL transaction and
+/// MemoryStorage::apply_ops() on a copy of the vault.
+pub(crate) async fn transact_on<S: Storage>(
+    storage: &mut S,
+    ops: &[TxOp],
+    meta: &TxMeta,
+) -> Result<TxReport, TxError> {
+    let schema = storage.load_schema().await?;
+    let ops = lookup::resolve_lookup_refs(storage, &schema, ops).await?;
+    tempid::check_unprefixed_ids(storage, &schema, &ops).await?;
+    let (ops, tempids) = tempid::resolve_tempids(storage, &schema, &ops).await?;
+    let ids: Vec<&str> = ops.iter().map(TxOp::entity_id).collect();
+    let mut working = WorkingSet {
+        order: Vec::new(),
+        entities: storage.load_entities(&ids).await?,
+    };
+
+    // ops are applied in order, so later ops see the effect of earlier ones;
+    // change_ops[i] is the index of the op that made changes[i]
+    let mut changes = Vec::new();
+    let mut change_ops = Vec::new();
+    for (index, op) in ops.iter().enumerate() {
+        match op {
+            TxOp::Datom(datom) => {
+                let conformed = schema
+                    .conform(datom)
+                    .map_err(|message| TxError::Schema { index, message })?;
+                for datom in conformed {
+                    let entity = working.entity(storage, &datom.e).await?;
+                    changes.extend(apply_to_entity(entity, &datom, &schema));
                 }
             }
+            TxOp::RetractEntity(e) => {
+                changes.extend(retract_entity(storage, &mut working, &schema, e).await?);
+            }
+            TxOp::Cas { e, a, old, new } => {
+                let entity = working.entity(storage, e).await?;
+                changes.extend(
+                    compare_and_set(entity, &schema, a, old.as_ref(), new)
+                        .map_err(|err| err.at(index))?,
+                );
+            }
+            TxOp::Lookup { .. } => unreachable!("lookup refs are resolved above"),
         }
-        write_entity(&mut conn, &entity).await?;
-        updated.push(entity);
+        change_ops.resize(changes.len(), index);
     }
+    let updated = working.into_entities();
+
+    storage.write_entities(&updated, &schema).await?;
+    schema::check_unique(storage, &schema, &changes)
+        .await
+        .map_err(|err| match err.index() {
+            Some(change) => err.at(change_ops[change]),
+            None => err,
+        })?;
+    let tx = storage.append_tx(&changes, meta).await?;
 
-    let _ = conn.close().await;
-    Ok(updated)
+    Ok(TxReport {
+        tx,
+        entities: updated,
+        tempids,
+    })
 }
 
-//TODO@chico: improve the filtering performance
-///TODO@chico: add pagination support
-///TODO@chico: add tests for list_pages
-/// list_pages() lists all unique page names from the entities.
-/// It scans all entities and collects the "page/name" attributes,
-/// sorts them, removes duplicates, and returns the list.
-/// Returns a Result containing a vector of page names or an error.
-pub async fn list_pages() -> Result<Vec<String>, sqlx::Error> {
-    let mut conn = conn().await;
-    let entities = scan_entities(&mut conn).await?;
-    let _ = conn.close().await;
+pub(crate) async fn apply_ops_on(
+    conn: &mut SqliteConnection,
+    ops: &[TxOp],
+    meta: &TxMeta,
+) -> Result<TxReport, TxError> {
+    // everything runs in one SQL transaction: if any step fails the
+    // transaction is dropped without commit and SQLite rolls it back.
+    // It takes the write lock up front, so a busy vault is waited for here
+    // instead of failing halfway when the reads turn into writes.
+    let mut sql_tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    let report = transact_on(&mut *sql_tx, ops, meta).await?;
+    sql_tx.commit().await?;
+    Ok(report)
+}
+
+/// apply_datoms_on() applies a list of datoms as one transaction, see
+/// apply_ops().
+#[cfg(test)]
+pub(crate) async fn apply_datoms_on(
+    conn: &mut SqliteConnection,
+    datoms: &[Datom],
+    meta: &TxMeta,
+) -> Result<TxReport, TxError> {
+    let ops: Vec<TxOp> = datoms.iter().cloned().map(TxOp::Datom).collect();
+    apply_ops_on(conn, &ops, meta).await
+}
 
+/// apply_ops() applies the ops of a transaction to the database.
+/// It loads the entities the ops are about, applies the ops in order, and
+/// writes the updated entities back to the database.
+/// Every call is also recorded as a new transaction in the transaction log,
+/// and the `datoms` index is updated for the entities it touched.
+/// Datoms are checked against the schema first: values of declared attributes
+/// must have the attribute's type, and values of unique attributes may only be
+/// held by one entity.
+/// Besides datoms it runs `db/retractEntity` and `db/cas` ops, against the
+/// state left by the ops before them. Lookup refs and tempids are resolved
+/// first, see lookup::resolve_lookup_refs() and tempid::resolve_tempids().
+/// The whole transaction is applied in a single SQLite transaction: either
+/// every op is applied and logged, or nothing is.
+///
+/// # Arguments
+/// * `ops` - The ops to apply.
+///   - An Add datom adds the attribute-value pair to the entity.
+///   - A Retract datom removes the value from the entity if it is the
+///     attribute's current value (or one of its values, for
+///     cardinality-many attributes); otherwise nothing happens.
+///   - If an entity does not exist, it is created with the given ID.
+///   - The datoms that changed something are appended to the log under a new tx id.
+/// * `meta` - The metadata to record with the transaction.
+/// # Errors
+/// Returns a TxError::Storage if any SQL query fails, a TxError::Schema if a
+/// datom violates the schema and a TxError::Constraint if it breaks a
+/// uniqueness constraint, a `db/cas` finds another value than the expected
+/// one or a lookup ref finds no entity. The index of the error is the
+/// position of the op in `ops`.
+/// In that case the database is left as it was before the call.
+/// When another process is writing to the vault, the call waits for it and
+/// retries, and returns a TxError::Busy if the vault stays locked.
+pub async fn apply_ops(db: &Db, ops: &[TxOp], meta: &TxMeta) -> Result<TxReport, TxError> {
+    retry_busy(TxError::is_busy, || async {
+        let mut conn = db.conn().await?;
+        apply_ops_on(&mut conn, ops, meta).await
+    })
+    .await
+}
+
+/// inverse_datoms() builds the datoms that revert a logged transaction:
+/// its datoms in reverse order, with every Add turned into a Retract and
+/// every Retract into an Add. Since an Add that replaced a value is logged as
+/// a Retract of the old value plus the Add, its inverse retracts the new value
+/// and re-adds the old one.
+fn inverse_datoms(datoms: Vec<Datom>) -> Vec<Datom> {
+    datoms
+        .into_iter()
+        .rev()
+        .map(|datom| Datom {
+            op: match datom.op {
+                DatomOp::Add => DatomOp::Retract,
+                DatomOp::Retract => DatomOp::Add,
+            },
+            ..datom
+        })
+        .collect()
+}
+
+/// revert_latest_on() applies the inverse of the transaction a revert of
+/// `kind` reverts next (see log::revert_target()) as a new transaction,
+/// recorded with the source, author and message of `meta`.
+/// The target is picked inside the same SQL transaction that reverts it, so
+/// two processes undoing at once never revert the same tx twice.
+/// Returns the reverted tx and the report of the new one, or None when there
+/// is nothing to revert.
+pub(crate) async fn revert_latest_on(
+    conn: &mut SqliteConnection,
+    kind: TxKind,
+    meta: &TxMeta,
+) -> Result<Option<(i64, TxReport)>, TxError> {
+    let mut sql_tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    let Some(tx) = log::revert_target(&mut sql_tx, kind).await? else {
+        return Ok(None);
+    };
+    let ops: Vec<TxOp> = inverse_datoms(log::tx_datoms_on(&mut sql_tx, tx).await?)
+        .into_iter()
+        .map(TxOp::Datom)
+        .collect();
+    let meta = TxMeta {
+        kind,
+        reverts: Some(tx),
+        ..meta.clone()
+    };
+    let report = transact_on(&mut *sql_tx, &ops, &meta).await?;
+    sql_tx.commit().await?;
+    Ok(Some((tx, report)))
+}
+
+/// revert_latest() reverts the next transaction for `jottty undo` or
+/// `jottty redo`, see revert_latest_on().
+pub async fn revert_latest(
+    db: &Db,
+    kind: TxKind,
+    meta: &TxMeta,
+) -> Result<Option<(i64, TxReport)>, TxError> {
+    retry_busy(TxError::is_busy, || async {
+        let mut conn = db.conn().await?;
+        revert_latest_on(&mut conn, kind, meta).await
+    })
+    .await
+}
+
+/// A point in the past to read the database at.
+#[derive(Debug, Clone, Copy)]
+pub enum AsOf {
+    /// Right after the given transaction.
+    Tx(i64),
+    /// The given wall-clock time, in milliseconds since the UNIX epoch.
+    Instant(i64),
+}
+
+/// revert_on_entity() undoes a logged datom on an in-memory entity.
+/// It is the inverse of what apply_to_entity() logged: an Add is removed and
+/// a Retract puts the old value back.
+fn revert_on_entity(entity: &mut Entity, datom: &Datom, schema: &Schema) {
+    if schema.is_many(&datom.a) {
+        let mut values = values_of(entity.attrs.get(&datom.a));
+        match datom.op {
+            DatomOp::Add => values.retain(|v| *v != datom.v),
+            DatomOp::Retract if !values.contains(&datom.v) => values.push(datom.v.clone()),
+            DatomOp::Retract => {}
+        }
+        set_values(entity, &datom.a, values);
+        return;
+    }
+    match datom.op {
+        DatomOp::Add => {
+            if entity.attrs.get(&datom.a) == Some(&datom.v) {
+                entity.attrs.remove(&datom.a);
+            }
+        }
+        DatomOp::Retract => {
+            entity.attrs.insert(datom.a.clone(), datom.v.clone());
+        }
+    }
+}
+
+/// entities_at() returns every entity as it was at the given point in time,
+/// or as it is now when `as_of` is None.
+/// Past states are rebuilt from the current ones by reverting, newest first,
+/// every logged datom that came after the as-of transaction, with the schema
+/// its transaction was applied with. Entities written before the log existed
+/// are kept as they are.
+async fn entities_at(
+    conn: &mut SqliteConnection,
+    as_of: Option<AsOf>,
+) -> Result<Vec<Entity>, sqlx::Error> {
+    let entities = conn.scan_entities().await?;
+    let Some(as_of) = as_of else {
+        return Ok(entities);
+    };
+
+    let tx = log::resolve_as_of(conn, as_of).await?;
+    let later = log::datoms_after(conn, tx).await?;
+    let history = schema::load_schema_history(conn).await?;
+
+    let mut by_id: HashMap<String, Entity> = entities
+        .into_iter()
+        .map(|entity| (entity.id.clone(), entity))
+        .collect();
+    let mut schema_tx = None;
+    let mut schema = Schema::builtin();
+    for (tx, datom) in later.iter().rev() {
+        if schema_tx != Some(*tx) {
+            schema_tx = Some(*tx);
+            schema = history.at(*tx);
+        }
+        let entity = by_id.entry(datom.e.clone()).or_insert_with(|| Entity {
+            id: datom.e.clone(),
+            attrs: Map::new(),
+        });
+        revert_on_entity(entity, datom, &schema);
+    }
+
+    Ok(by_id
+        .into_values()
+        .filter(|entity| !entity.attrs.is_empty())
+        .collect())
+}
+
+fn page_names(entities: Vec<Entity>) -> Vec<String> {
     let mut pages = Vec::new();
     for entity in entities {
         if let Some(Value::String(name)) = entity.attrs.get("page/name") {
@@ -209,44 +899,262 @@ pub async fn list_pages() -> Result<Vec<String>, sqlx::Error> {
     }
     pages.sort();
     pages.dedup();
-    Ok(pages)
+    pages
 }
 
-pub async fn search_blocks(term: &str) -> Result<Vec<Entity>, sqlx::Error> {
-    let mut conn = conn().await;
-    let mut entities = scan_entities(&mut conn).await?;
-    let _ = conn.close().await;
-
+fn page_blocks(mut entities: Vec<Entity>, page_id: &str) -> Vec<Entity> {
     entities.retain(|entity| {
-        matches!(entity.attrs.get("block/content"), Some(Value::String(title)) if title.contains(term))
+        matches!(entity.attrs.get("block/page"), Some(Value::String(page)) if page == page_id)
     });
 
-    entities.sort_by(|a, b| a.id.cmp(&b.id));
-    Ok(entities)
+    entities.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    entities
+}
+
+/// current_pages() lists the page names in the index, sorted and without
+/// duplicates.
+pub(crate) async fn current_pages<S: Storage>(storage: &mut S) -> Result<Vec<String>, sqlx::Error> {
+    let datoms = storage.match_datoms(None, Some("page/name"), None).await?;
+    let mut pages: Vec<String> = datoms
+        .into_iter()
+        .filter_map(|(_, _, v)| v.as_str().map(str::to_string))
+        .collect();
+    pages.sort();
+    pages.dedup();
+    Ok(pages)
+}
+
+///TODO@chico: add pagination support
+/// list_pages() lists all unique page names from the entities.
+/// It reads the "page/name" values from the attribute index, sorted and
+/// without duplicates.
+/// With `as_of`, the pages are listed as they were at that point in time,
+/// which needs a scan of all entities.
+/// Returns a Result containing a vector of page names or an error.
+pub async fn list_pages(db: &Db, as_of: Option<AsOf>) -> Result<Vec<String>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    match as_of {
+        Some(_) => entities_at(&mut conn, as_of).await.map(page_names),
+        None => current_pages(&mut *conn).await,
+    }
+}
+
+fn search_hits(
+    matches: Vec<(String, String)>,
+    mut entities: HashMap<String, Entity>,
+) -> Vec<SearchHit> {
+    matches
+        .into_iter()
+        .filter_map(|(e, snippet)| {
+            entities
+                .remove(&e)
+                .map(|entity| SearchHit { entity, snippet })
+        })
+        .collect()
+}
+
+/// current_search_hits() runs a full-text search over the current blocks.
+pub(crate) async fn current_search_hits<S: Storage>(
+    storage: &mut S,
+    term: &str,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let matches = storage.search_fulltext(term).await?;
+    let ids: Vec<&str> = matches.iter().map(|(e, _)| e.as_str()).collect();
+    let entities = storage.load_entities(&ids).await?;
+    Ok(search_hits(matches, entities))
+}
+
+async fn search_blocks_on(
+    conn: &mut SqliteConnection,
+    term: &str,
+    as_of: Option<AsOf>,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    if as_of.is_none() {
+        return current_search_hits(conn, term).await;
+    }
+    let snapshot = entities_at(conn, as_of).await?;
+    let matches = index::search_fulltext_in(conn, &snapshot, term).await?;
+    let entities: HashMap<String, Entity> = snapshot
+        .into_iter()
+        .map(|entity| (entity.id.clone(), entity))
+        .collect();
+    Ok(search_hits(matches, entities))
+}
+
+/// search_blocks() runs a full-text search over "block/content".
+/// The term is an SQLite FTS5 query: words match case-insensitively, and
+/// "phrases", prefix* queries and AND/OR/NOT are supported. A term that is not
+/// valid FTS5 syntax is searched as a phrase.
+/// Hits are ordered by bm25 rank, best first, and carry a highlighted snippet.
+/// With `as_of`, blocks are searched as they were at that point in time.
+pub async fn search_blocks(
+    db: &Db,
+    term: &str,
+    as_of: Option<AsOf>,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    search_blocks_on(&mut conn, term, as_of).await
+}
+
+/// current_page_blocks() loads the blocks on a page through the
+/// attribute→value→entity index, sorted by id.
+pub(crate) async fn current_page_blocks<S: Storage>(
+    storage: &mut S,
+    page_id: &str,
+) -> Result<Vec<Entity>, sqlx::Error> {
+    let ids = storage
+        .entities_with("block/page", &Value::from(page_id))
+        .await?;
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let loaded = storage.load_entities(&ids).await?;
+    Ok(page_blocks(loaded.into_values().collect(), page_id))
 }
 
-//TODO@chico: improve the filtering performance
 //TODO@chico: add pagination support
-//TOODO@chico: add tests for list_page_blocks
 /// list_page_blocks() lists all blocks associated with a given page ID.
-/// It scans all entities and filters those that have a "block/page" attribute
-/// matching the provided page ID.
-pub async fn list_page_blocks(page_id: &str) -> Result<Vec<Entity>, sqlx::Error> {
-    let mut conn = conn().await;
-    let mut entities = scan_entities(&mut conn).await?;
-    let _ = conn.close().await;
+/// It looks up the entities whose "block/page" attribute matches the page ID
+/// in the attribute index and loads only those.
+/// With `as_of`, the blocks are listed as they were at that point in time,
+/// which needs a scan of all entities.
+pub async fn list_page_blocks(
+    db: &Db,
+    page_id: &str,
+    as_of: Option<AsOf>,
+) -> Result<Vec<Entity>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    match as_of {
+        Some(_) => entities_at(&mut conn, as_of)
+            .await
+            .map(|entities| page_blocks(entities, page_id)),
+        None => current_page_blocks(&mut *conn, page_id).await,
+    }
+}
 
-    entities.retain(|entity| {
-        matches!(entity.attrs.get("block/page"), Some(Value::String(page)) if page == page_id)
-    });
+/// current_tagged_blocks() returns the blocks whose "block/tags" contain
+/// `tag`, sorted by id, through the attribute→value→entity index.
+pub(crate) async fn current_tagged_blocks<S: Storage>(
+    storage: &mut S,
+    tag: &str,
+) -> Result<Vec<Entity>, sqlx::Error> {
+    let ids = storage
+        .entities_with("block/tags", &Value::from(tag))
+        .await?;
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    let mut blocks: Vec<Entity> = storage.load_entities(&ids).await?.into_values().collect();
+    blocks.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    Ok(blocks)
+}
+
+/// tagged_blocks_on() returns the blocks whose "block/tags" contain `tag`,
+/// sorted by id.
+async fn tagged_blocks_on(
+    conn: &mut SqliteConnection,
+    tag: &str,
+    as_of: Option<AsOf>,
+) -> Result<Vec<Entity>, sqlx::Error> {
+    if as_of.is_none() {
+        return current_tagged_blocks(conn, tag).await;
+    }
+    let tag = Value::from(tag);
+    let mut blocks: Vec<Entity> = entities_at(conn, as_of)
+        .await?
+        .into_iter()
+        .filter(|entity| values_of(entity.attrs.get("block/tags")).contains(&tag))
+        .collect();
+    blocks.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    Ok(blocks)
+}
 
+/// tagged_blocks() lists the blocks tagged with `tag`, using the
+/// attribute→value→entity index.
+/// With `as_of`, blocks are listed as they were at that point in time.
+pub async fn tagged_blocks(
+    db: &Db,
+    tag: &str,
+    as_of: Option<AsOf>,
+) -> Result<Vec<Entity>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    tagged_blocks_on(&mut conn, tag, as_of).await
+}
+
+/// The state of an entity and of the blocks on it before and after a transaction.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub tx: i64,
+    pub tx_instant: i64,
+    /// Who made the transaction and through which command or client.
+    pub meta: TxMeta,
+    pub before: Vec<Entity>,
+    pub after: Vec<Entity>,
+}
+
+fn sorted_states(states: &HashMap<String, Entity>) -> Vec<Entity> {
+    let mut entities: Vec<Entity> = states.values().cloned().collect();
     entities.sort_unstable_by(|a, b| a.id.cmp(&b.id));
-    Ok(entities)
+    entities
+}
+
+async fn history_on(
+    conn: &mut SqliteConnection,
+    entity_id: &str,
+) -> Result<Vec<Version>, sqlx::Error> {
+    // the entity itself plus every block that is or ever was on it
+    let mut ids = vec![entity_id.to_string()];
+    ids.extend(log::logged_referrers(conn, "block/page", entity_id).await?);
+    ids.extend(index::entities_with(conn, "block/page", &Value::from(entity_id)).await?);
+    ids.sort();
+    ids.dedup();
+
+    let mut states = HashMap::new();
+    for id in &ids {
+        states.insert(id.clone(), conn.load_entity(id).await?);
+    }
+
+    let logged = log::entity_datoms(conn, &ids).await?;
+    let history = schema::load_schema_history(conn).await?;
+
+    // walk the log backwards, reverting each transaction to get the state before it
+    let mut versions = Vec::new();
+    let mut end = logged.len();
+    while end > 0 {
+        let tx = logged[end - 1].tx;
+        let mut start = end;
+        while start > 0 && logged[start - 1].tx == tx {
+            start -= 1;
+        }
+        let after = sorted_states(&states);
+        let schema = history.at(tx);
+        for entry in logged[start..end].iter().rev() {
+            if let Some(entity) = states.get_mut(&entry.datom.e) {
+                revert_on_entity(entity, &entry.datom, &schema);
+            }
+        }
+        versions.push(Version {
+            tx,
+            tx_instant: logged[start].tx_instant,
+            meta: logged[start].meta.clone(),
+            before: sorted_states(&states),
+            after,
+        });
+        end = start;
+    }
+
+    versions.reverse();
+    Ok(versions)
+}
+
+/// history() lists every transaction that touched an entity, oldest first.
+/// For a page, the transactions that touched any block on it are included too.
+/// Each Version holds the entity and those blocks as they were before and
+/// after the transaction.
+pub async fn history(db: &Db, entity_id: &str) -> Result<Vec<Version>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    history_on(&mut conn, entity_id).await
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::db::init_db;
+    use crate::db::{MemoryStorage, test_conn};
 
     use super::*;
 
@@ -303,26 +1211,9 @@ mod tests {
         );
     }
 
-    //TODO@chico: reuse this function
-    fn unique_test_db_path(test_name: &str) -> std::path::PathBuf {
-        let mut path = std::env::temp_dir();
-        let pid = std::process::id();
-        let ts = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        path.push(format!("jottty_{}_{}_{}.db", test_name, pid, ts));
-        path
-    }
-
-    #[tokio::test]
-    async fn test_apply_datoms() {
-        let db_path = unique_test_db_path("apply_datoms");
-        unsafe {
-            std::env::set_var("JOTTTY_DB_PATH", db_path.to_string_lossy().to_string());
-        }
-        init_db().await;
-
+    /// check_apply_datoms() applies two datoms to `storage` and checks the
+    /// entity they made, on any backend.
+    async fn check_apply_datoms<S: Storage>(storage: &mut S) {
         let datoms = vec![
             Datom {
                 op: DatomOp::Add,
@@ -337,20 +1228,682 @@ mod tests {
                 v: Value::String("This is a bullet in the journal".into()),
             },
         ];
-        let result = apply_datoms(&datoms).await;
+        let ops: Vec<TxOp> = datoms.into_iter().map(TxOp::Datom).collect();
+        let report = transact_on(storage, &ops, &TxMeta::default())
+            .await
+            .unwrap();
 
-        for r in result.as_ref().unwrap() {
-            if r.id == "block:page-id" {
-                assert_eq!(
-                    r.attrs.get("block/title").unwrap(),
-                    &Value::String("Journal".to_string())
-                );
-                assert_eq!(
-                    r.attrs.get("block/content").unwrap(),
-                    &Value::String("This is a bullet in the journal".into())
-                );
-            }
+        assert_eq!(report.tx, 1);
+        assert_eq!(report.entities.len(), 1);
+        let entity = storage.load_entity("block:page-id").await.unwrap();
+        assert_eq!(
+            entity.attrs.get("block/title").unwrap(),
+            &Value::String("Journal".to_string())
+        );
+        assert_eq!(
+            entity.attrs.get("block/content").unwrap(),
+            &Value::String("This is a bullet in the journal".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_datoms() {
+        check_apply_datoms(&mut test_conn().await).await;
+        check_apply_datoms(&mut MemoryStorage::default()).await;
+    }
+
+    fn datom(op: DatomOp, e: &str, a: &str, v: &str) -> Datom {
+        Datom {
+            op,
+            e: e.to_string(),
+            a: a.to_string(),
+            v: Value::String(v.to_string()),
         }
-        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn apply_datoms_appends_to_tx_log() {
+        let mut conn = test_conn().await;
+
+        let first = apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "block/content", "draft")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        let second = apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "block/content", "final")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert!(second.tx > first.tx);
+
+        let rows = sqlx::query("SELECT tx, op, e, a, v FROM tx_datoms ORDER BY tx, idx;")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+        let logged: Vec<(i64, String, String)> = rows
+            .iter()
+            .map(|row| (row.get("tx"), row.get("op"), row.get("v")))
+            .collect();
+        assert_eq!(
+            logged,
+            vec![
+                (first.tx, "db/add".to_string(), "\"draft\"".to_string()),
+                (second.tx, "db/retract".to_string(), "\"draft\"".to_string()),
+                (second.tx, "db/add".to_string(), "\"final\"".to_string()),
+            ]
+        );
+
+        let instant: i64 = sqlx::query("SELECT tx_instant FROM transactions WHERE tx = ?;")
+            .bind(second.tx)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("tx_instant");
+        assert!(instant > 0);
+    }
+
+    #[tokio::test]
+    async fn retract_only_removes_the_current_value() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "block/content", "keep me")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        // a retract of another value changes nothing and logs nothing
+        let report = apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Retract, "block:1", "block/content", "")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.entities[0].attrs["block/content"], "keep me");
+        assert!(
+            log::tx_datoms_on(&mut conn, report.tx)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let report = apply_datoms_on(
+            &mut conn,
+            &[datom(
+                DatomOp::Retract,
+                "block:1",
+                "block/content",
+                "keep me",
+            )],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert!(report.entities[0].attrs.is_empty());
+        let row = sqlx::query("SELECT op, v FROM tx_datoms WHERE tx = ?;")
+            .bind(report.tx)
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("op"), "db/retract");
+        assert_eq!(row.get::<String, _>("v"), "\"keep me\"");
+    }
+
+    #[tokio::test]
+    async fn retract_entity_removes_refs_to_it() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Add, "page:1", "page/name", "1"),
+                datom(DatomOp::Add, "block:1", "block/content", "stays"),
+                datom(DatomOp::Add, "block:1", "block/page", "page:1"),
+                datom(DatomOp::Add, "block:2", "block/tags", "x"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let report = apply_ops_on(
+            &mut conn,
+            &[
+                TxOp::Datom(datom(DatomOp::Add, "block:3", "block/page", "page:1")),
+                TxOp::RetractEntity("page:1".to_string()),
+                TxOp::RetractEntity("block:2".to_string()),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let by_id: HashMap<&str, &Entity> = report
+            .entities
+            .iter()
+            .map(|entity| (entity.id.as_str(), entity))
+            .collect();
+        assert!(by_id["page:1"].attrs.is_empty());
+        assert!(by_id["block:2"].attrs.is_empty());
+        assert!(by_id["block:3"].attrs.is_empty());
+        assert_eq!(
+            by_id["block:1"].attrs.keys().collect::<Vec<_>>(),
+            vec!["block/content"]
+        );
+        assert!(
+            current_page_blocks(&mut conn, "page:1")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn cas_only_swaps_the_expected_value() {
+        let mut conn = test_conn().await;
+        let cas = |old: Option<&str>, new: &str| TxOp::Cas {
+            e: "block:1".to_string(),
+            a: "block/content".to_string(),
+            old: old.map(Value::from),
+            new: Value::from(new),
+        };
+
+        apply_ops_on(&mut conn, &[cas(None, "first")], &TxMeta::default())
+            .await
+            .unwrap();
+        let err = apply_ops_on(
+            &mut conn,
+            &[
+                TxOp::Datom(datom(
+                    DatomOp::Add,
+                    "block:2",
+                    "block/content",
+                    "rolled back",
+                )),
+                cas(Some("stale"), "second"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, TxError::Constraint { index: 1, .. }),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("CAS failed"), "{}", err);
+        assert!(conn.load_entity("block:2").await.unwrap().attrs.is_empty());
+
+        let report = apply_ops_on(
+            &mut conn,
+            &[cas(Some("first"), "second")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.entities[0].attrs["block/content"], "second");
+    }
+
+    fn block_datoms(id: &str, page_id: &str, content: &str) -> Vec<Datom> {
+        vec![
+            datom(DatomOp::Add, id, "block/content", content),
+            datom(DatomOp::Add, id, "block/page", page_id),
+        ]
+    }
+
+    #[tokio::test]
+    async fn list_page_blocks_as_of_past_tx() {
+        let mut conn = test_conn().await;
+        let first = apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:1", "page:1", "draft"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "block/content", "final")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:2", "page:1", "later"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let now = page_blocks(entities_at(&mut conn, None).await.unwrap(), "page:1");
+        assert_eq!(now.len(), 2);
+        assert_eq!(now[0].attrs["block/content"], "final");
+
+        let then = page_blocks(
+            entities_at(&mut conn, Some(AsOf::Tx(first.tx)))
+                .await
+                .unwrap(),
+            "page:1",
+        );
+        assert_eq!(then.len(), 1);
+        assert_eq!(then[0].id, "block:1");
+        assert_eq!(then[0].attrs["block/content"], "draft");
+    }
+
+    #[tokio::test]
+    async fn entities_at_restores_retracted_blocks() {
+        let mut conn = test_conn().await;
+        let first = apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:1", "page:1", "wiped"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Retract, "block:1", "block/content", "wiped"),
+                datom(DatomOp::Retract, "block:1", "block/page", "page:1"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(page_blocks(entities_at(&mut conn, None).await.unwrap(), "page:1").is_empty());
+        assert!(
+            search_blocks_on(&mut conn, "wiped", None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let then = search_blocks_on(&mut conn, "wiped", Some(AsOf::Tx(first.tx)))
+            .await
+            .unwrap();
+        assert_eq!(then.len(), 1);
+        assert_eq!(then[0].entity.id, "block:1");
+        assert_eq!(then[0].entity.attrs["block/page"], "page:1");
+    }
+
+    #[tokio::test]
+    async fn entities_at_before_first_instant_is_empty() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:1", "page:1", "note"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let entities = entities_at(&mut conn, Some(AsOf::Instant(0)))
+            .await
+            .unwrap();
+        assert!(entities.is_empty());
+    }
+
+    #[tokio::test]
+    async fn entities_at_reverts_with_the_schema_of_the_time() {
+        let mut conn = test_conn().await;
+        let mut labels = schema::Attribute {
+            ident: "x/labels".to_string(),
+            value_type: schema::ValueType::String,
+            cardinality: schema::Cardinality::Many,
+            unique: None,
+        };
+        schema::declare_attribute_on(&mut conn, &labels)
+            .await
+            .unwrap();
+        let first = apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "x/labels", "a")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "x/labels", "b")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        // declared cardinality one afterwards: the adds above were still set adds
+        labels.cardinality = schema::Cardinality::One;
+        schema::declare_attribute_on(&mut conn, &labels)
+            .await
+            .unwrap();
+
+        let then = entities_at(&mut conn, Some(AsOf::Tx(first.tx)))
+            .await
+            .unwrap();
+        assert_eq!(then[0].attrs["x/labels"], serde_json::json!(["a"]));
+    }
+
+    #[tokio::test]
+    async fn history_lists_versions_of_page_blocks() {
+        let mut conn = test_conn().await;
+        let first = apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:1", "page:1", "draft"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        let second = apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:1", "block/content", "final")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:2", "page:2", "elsewhere"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let versions = history_on(&mut conn, "page:1").await.unwrap();
+        let txs: Vec<i64> = versions.iter().map(|v| v.tx).collect();
+        assert_eq!(txs, vec![first.tx, second.tx]);
+
+        let content = |entities: &[Entity]| {
+            page_blocks(entities.to_vec(), "page:1")
+                .first()
+                .map(|e| e.attrs["block/content"].clone())
+        };
+        assert_eq!(content(&versions[0].before), None);
+        assert_eq!(content(&versions[0].after), Some(Value::from("draft")));
+        assert_eq!(content(&versions[1].before), Some(Value::from("draft")));
+        assert_eq!(content(&versions[1].after), Some(Value::from("final")));
+
+        let block_versions = history_on(&mut conn, "block:1").await.unwrap();
+        assert_eq!(block_versions.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn apply_datoms_rolls_back_on_error() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &block_datoms("block:1", "page:1", "before"),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        // make the log write fail after the entities were written
+        sqlx::query("DROP TABLE tx_datoms;")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        let result = apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Retract, "block:1", "block/content", "before"),
+                datom(DatomOp::Add, "block:2", "block/content", "after"),
+            ],
+            &TxMeta::default(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        let entities = scan_entities(&mut conn).await.unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].attrs["block/content"], "before");
+        let txs: i64 = sqlx::query("SELECT COUNT(*) AS n FROM transactions;")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(txs, 1);
+    }
+
+    #[tokio::test]
+    async fn apply_datoms_writes_large_batches() {
+        let mut conn = test_conn().await;
+        let datoms: Vec<Datom> = (0..BATCH_SIZE * 2 + 7)
+            .flat_map(|i| block_datoms(&format!("block:{:04}", i), "page:1", "note"))
+            .collect();
+        let report = apply_datoms_on(&mut conn, &datoms, &TxMeta::default())
+            .await
+            .unwrap();
+        assert_eq!(report.entities.len(), BATCH_SIZE * 2 + 7);
+
+        let blocks = current_page_blocks(&mut conn, "page:1").await.unwrap();
+        assert_eq!(blocks.len(), BATCH_SIZE * 2 + 7);
+    }
+
+    #[tokio::test]
+    async fn cardinality_many_attributes_hold_sets() {
+        let mut conn = test_conn().await;
+        let first = apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Add, "block:1", "block/tags", "todo"),
+                datom(DatomOp::Add, "block:1", "block/tags", "rust"),
+                datom(DatomOp::Add, "block:1", "block/tags", "todo"),
+                datom(DatomOp::Add, "block:2", "block/tags", "rust"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            first.entities[0].attrs["block/tags"],
+            serde_json::json!(["todo", "rust"])
+        );
+
+        let second = apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Retract, "block:1", "block/tags", "todo"),
+                datom(DatomOp::Retract, "block:1", "block/tags", "missing"),
+                Datom {
+                    op: DatomOp::Add,
+                    e: "block:2".to_string(),
+                    a: "block/tags".to_string(),
+                    v: serde_json::json!(["idea", "rust"]),
+                },
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            second.entities[0].attrs["block/tags"],
+            serde_json::json!(["rust"])
+        );
+        assert_eq!(
+            second.entities[1].attrs["block/tags"],
+            serde_json::json!(["rust", "idea"])
+        );
+        let logged = log::tx_datoms_on(&mut conn, second.tx).await.unwrap();
+        assert_eq!(logged.len(), 2);
+
+        let ids = |blocks: Vec<Entity>| blocks.into_iter().map(|b| b.id).collect::<Vec<_>>();
+        let rust = tagged_blocks_on(&mut conn, "rust", None).await.unwrap();
+        assert_eq!(ids(rust), vec!["block:1", "block:2"]);
+        let todo = tagged_blocks_on(&mut conn, "todo", None).await.unwrap();
+        assert!(todo.is_empty());
+        let todo_then = tagged_blocks_on(&mut conn, "todo", Some(AsOf::Tx(first.tx)))
+            .await
+            .unwrap();
+        assert_eq!(ids(todo_then), vec!["block:1"]);
+    }
+
+    #[tokio::test]
+    async fn colliding_entities_are_probed_to_the_next_addr() {
+        let mut conn = test_conn().await;
+        // another entity already lives at block:2's home addr
+        let home = addr_for_entity_id("block:2");
+        sqlx::query(
+            "INSERT INTO vaults (addr, entity_id, content, addresses) VALUES (?, ?, ?, '[]');",
+        )
+        .bind(home)
+        .bind("block:1")
+        .bind(r#"{"id":"block:1","attrs":{"block/content":"first"}}"#)
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:2", "block/content", "second")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(
+                DatomOp::Add,
+                "block:2",
+                "block/content",
+                "second, edited",
+            )],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let first = conn.load_entity("block:1").await.unwrap();
+        assert_eq!(first.attrs["block/content"], "first");
+        let second = conn.load_entity("block:2").await.unwrap();
+        assert_eq!(second.attrs["block/content"], "second, edited");
+
+        let addr: i64 = sqlx::query("SELECT addr FROM vaults WHERE entity_id = 'block:2';")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("addr");
+        assert_eq!(addr, home.wrapping_add(1));
+    }
+
+    #[tokio::test]
+    async fn load_entity_rejects_rows_holding_another_entity() {
+        let mut conn = test_conn().await;
+        sqlx::query(
+            "INSERT INTO vaults (addr, entity_id, content, addresses) VALUES (1, ?, ?, '[]');",
+        )
+        .bind("block:1")
+        .bind(r#"{"id":"block:9","attrs":{}}"#)
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+        assert!(conn.load_entity("block:1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn page_blocks_follow_the_index() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                block_datoms("block:2", "page:1", "second"),
+                block_datoms("block:1", "page:1", "first"),
+                block_datoms("block:3", "page:2", "other"),
+            ]
+            .concat(),
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Add, "block:3", "block/page", "page:1")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Retract, "block:2", "block/page", "page:1")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let ids: Vec<String> = current_page_blocks(&mut conn, "page:1")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entity| entity.id)
+            .collect();
+        assert_eq!(ids, vec!["block:1", "block:3"]);
+    }
+
+    #[tokio::test]
+    async fn undo_moves_past_txs_whose_revert_changes_nothing() {
+        let mut conn = test_conn().await;
+        let content = |e: &str, v: &str| datom(DatomOp::Add, e, "block/content", v);
+        let t1 = apply_datoms_on(&mut conn, &[content("block:1", "a")], &TxMeta::default())
+            .await
+            .unwrap()
+            .tx;
+        let t2 = apply_datoms_on(&mut conn, &[content("block:2", "b")], &TxMeta::default())
+            .await
+            .unwrap()
+            .tx;
+        // block:2 loses its content outside of the log, so undoing t2 is a no-op
+        let schema = conn.load_schema().await.unwrap();
+        conn.write_entities(
+            &[Entity {
+                id: "block:2".to_string(),
+                attrs: Map::new(),
+            }],
+            &schema,
+        )
+        .await
+        .unwrap();
+
+        let meta = TxMeta::default();
+        let (first, report) = revert_latest_on(&mut conn, TxKind::Undo, &meta)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, t2);
+        assert!(
+            log::tx_datoms_on(&mut conn, report.tx)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let (second, _) = revert_latest_on(&mut conn, TxKind::Undo, &meta)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second, t1);
+        assert!(conn.load_entity("block:1").await.unwrap().attrs.is_empty());
+
+        // and the empty undo can be redone, after the undo of t1
+        let (redone, _) = revert_latest_on(&mut conn, TxKind::Redo, &meta)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(redone, first);
+        let (redone_too, _) = revert_latest_on(&mut conn, TxKind::Redo, &meta)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(redone_too, redone);
+        assert!(
+            revert_latest_on(&mut conn, TxKind::Redo, &meta)
+                .await
+                .unwrap()
+                .is_none()
+        );
     }
 }
@@ -0,0 +1,321 @@
+use std::fmt;
+
+use serde_json::Value;
+
+/// A value read from EDN text: the notation pull patterns and Datalog queries
+/// are written in. Sets and tagged literals are not needed by either, so
+/// they are not read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edn {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// A keyword, without its leading ':'.
+    Keyword(String),
+    Symbol(String),
+    List(Vec<Edn>),
+    Vector(Vec<Edn>),
+    Map(Vec<(Edn, Edn)>),
+}
+
+impl fmt::Display for Edn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seq = |f: &mut fmt::Formatter<'_>, open, items: &[Edn], close| {
+            write!(f, "{}", open)?;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", item)?;
+            }
+            write!(f, "{}", close)
+        };
+        match self {
+            Edn::Nil => write!(f, "nil"),
+            Edn::Bool(b) => write!(f, "{}", b),
+            Edn::Int(n) => write!(f, "{}", n),
+            Edn::Float(n) => write!(f, "{}", n),
+            Edn::String(s) => write!(f, "{:?}", s),
+            Edn::Keyword(k) => write!(f, ":{}", k),
+            Edn::Symbol(s) => write!(f, "{}", s),
+            Edn::List(items) => seq(f, "(", items, ")"),
+            Edn::Vector(items) => seq(f, "[", items, "]"),
+            Edn::Map(entries) => {
+                let items: Vec<Edn> = entries
+                    .iter()
+                    .flat_map(|(k, v)| [k.clone(), v.clone()])
+                    .collect();
+                seq(f, "{", &items, "}")
+            }
+        }
+    }
+}
+
+impl Edn {
+    /// from_json() reads a JSON value as EDN: strings starting with '?' are
+    /// symbols (query variables) and strings starting with ':' keywords, so
+    /// queries can be written in JSON too. Object keys become keywords.
+    pub fn from_json(value: &Value) -> Edn {
+        match value {
+            Value::Null => Edn::Nil,
+            Value::Bool(b) => Edn::Bool(*b),
+            Value::Number(n) => match n.as_i64() {
+                Some(n) => Edn::Int(n),
+                None => Edn::Float(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(s) if s.starts_with('?') => Edn::Symbol(s.clone()),
+            Value::String(s) if s.starts_with(':') && s.len() > 1 => {
+                Edn::Keyword(s[1..].to_string())
+            }
+            Value::String(s) => Edn::String(s.clone()),
+            Value::Array(items) => Edn::Vector(items.iter().map(Edn::from_json).collect()),
+            Value::Object(map) => Edn::Map(
+                map.iter()
+                    .map(|(k, v)| {
+                        let k = k.trim_start_matches(':');
+                        (Edn::Keyword(k.to_string()), Edn::from_json(v))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// to_json() converts a constant to the JSON value the vault stores:
+    /// keywords become their name, as attribute names are stored.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Edn::Nil => Value::Null,
+            Edn::Bool(b) => Value::from(*b),
+            Edn::Int(n) => Value::from(*n),
+            Edn::Float(n) => Value::from(*n),
+            Edn::String(s) | Edn::Keyword(s) | Edn::Symbol(s) => Value::from(s.as_str()),
+            Edn::List(items) | Edn::Vector(items) => {
+                Value::Array(items.iter().map(Edn::to_json).collect())
+            }
+            Edn::Map(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(k, v)| {
+                        (
+                            k.to_json().as_str().unwrap_or_default().to_string(),
+                            v.to_json(),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// items() returns the elements of a list or a vector.
+    pub fn items(&self) -> Option<&[Edn]> {
+        match self {
+            Edn::List(items) | Edn::Vector(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// name() returns the name of a keyword or a symbol.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Edn::Keyword(name) | Edn::Symbol(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// How deep lists, vectors and maps may nest, as in serde_json. The reader
+/// recurses per level, and reads patterns and queries sent over HTTP.
+const MAX_DEPTH: usize = 128;
+
+struct Reader<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// The number of lists, vectors and maps open at the current position.
+    depth: usize,
+}
+
+/// Characters that end a symbol, keyword or number.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]{},;\"".contains(c)
+}
+
+impl Reader<'_> {
+    /// skip_blank() skips whitespace, commas (whitespace in EDN) and comments.
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || c == ',' {
+                self.chars.next();
+            } else if c == ';' {
+                while self.chars.next().is_some_and(|c| c != '\n') {}
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn read(&mut self) -> Result<Edn, String> {
+        self.skip_blank();
+        let Some(c) = self.chars.next() else {
+            return Err("Unexpected end of EDN input".to_string());
+        };
+        match c {
+            '(' => Ok(Edn::List(self.read_seq(')')?)),
+            '[' => Ok(Edn::Vector(self.read_seq(']')?)),
+            '{' => {
+                let items = self.read_seq('}')?;
+                if items.len() % 2 != 0 {
+                    return Err("EDN map must have an even number of forms".to_string());
+                }
+                let mut entries = Vec::with_capacity(items.len() / 2);
+                let mut items = items.into_iter();
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    entries.push((k, v));
+                }
+                Ok(Edn::Map(entries))
+            }
+            ')' | ']' | '}' => Err(format!("Unexpected '{}' in EDN input", c)),
+            '"' => self.read_string(),
+            c => {
+                let mut token = c.to_string();
+                while let Some(&next) = self.chars.peek() {
+                    if is_delimiter(next) {
+                        break;
+                    }
+                    token.push(next);
+                    self.chars.next();
+                }
+                Ok(atom(&token))
+            }
+        }
+    }
+
+    fn read_seq(&mut self, close: char) -> Result<Vec<Edn>, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("EDN input nests deeper than {} levels", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.chars.peek() {
+                Some(&c) if c == close => {
+                    self.chars.next();
+                    self.depth -= 1;
+                    return Ok(items);
+                }
+                Some(_) => items.push(self.read()?),
+                None => return Err(format!("Missing '{}' in EDN input", close)),
+            }
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Edn, String> {
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(Edn::String(s)),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some(c) => s.push(c),
+                    None => break,
+                },
+                Some(c) => s.push(c),
+                None => break,
+            }
+        }
+        Err("Unterminated string in EDN input".to_string())
+    }
+}
+
+fn atom(token: &str) -> Edn {
+    match token {
+        "nil" => return Edn::Nil,
+        "true" => return Edn::Bool(true),
+        "false" => return Edn::Bool(false),
+        _ => {}
+    }
+    if let Some(keyword) = token.strip_prefix(':') {
+        return Edn::Keyword(keyword.to_string());
+    }
+    if let Ok(n) = token.parse::<i64>() {
+        return Edn::Int(n);
+    }
+    if let Ok(n) = token.parse::<f64>()
+        && token.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+')
+    {
+        return Edn::Float(n);
+    }
+    Edn::Symbol(token.to_string())
+}
+
+/// parse() reads one EDN form from `input`.
+/// # Errors
+/// Returns a message if the input is not a single, complete EDN form.
+pub fn parse(input: &str) -> Result<Edn, String> {
+    let mut reader = Reader {
+        chars: input.chars().peekable(),
+        depth: 0,
+    };
+    let edn = reader.read()?;
+    reader.skip_blank();
+    if reader.chars.peek().is_some() {
+        return Err("Unexpected input after the EDN form".to_string());
+    }
+    Ok(edn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_reads_nested_forms() {
+        let edn = parse(
+            r#"{:find [?c] ; the content
+            :where [[?b "block/page" ?p], [(> ?n 1.5)] [?p :page/name "a \"b\""]]}"#,
+        )
+        .unwrap();
+        let Edn::Map(entries) = &edn else {
+            panic!("not a map: {}", edn);
+        };
+        assert_eq!(entries[0].0, Edn::Keyword("find".to_string()));
+        assert_eq!(
+            entries[0].1,
+            Edn::Vector(vec![Edn::Symbol("?c".to_string())])
+        );
+        assert_eq!(
+            edn.to_string(),
+            r#"{:find [?c] :where [[?b "block/page" ?p] [(> ?n 1.5)] [?p :page/name "a \"b\""]]}"#
+        );
+
+        for bad in ["[1 2", "{:a}", "[1] 2", "\"open", ")"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parse_limits_the_nesting_depth() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&"[".repeat(100_000)).is_err());
+        assert!(parse(&"{:a (".repeat(100_000)).is_err());
+    }
+
+    #[test]
+    fn json_reads_as_edn() {
+        let edn = Edn::from_json(&json!({"find": ["?c"], "where": [["?b", ":block/page", 7]]}));
+        assert_eq!(
+            edn,
+            parse("{:find [?c] :where [[?b :block/page 7]]}").unwrap()
+        );
+        assert_eq!(
+            Edn::Keyword("page/name".to_string()).to_json(),
+            json!("page/name")
+        );
+    }
+}
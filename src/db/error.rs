@@ -0,0 +1,94 @@
+use std::fmt;
+
+/// Why a transaction failed.
+/// Every error caused by an element of the transaction carries its `index`,
+/// the position of the element in the transaction (from 0).
+#[derive(Debug)]
+pub enum TxError {
+    /// The element is not an op in list form or an entity map.
+    Parse { index: usize, message: String },
+    /// The element is a list whose op is not one of the supported ops.
+    UnknownOp { index: usize, op: String },
+    /// A datom doesn't fit the schema: a value of the wrong type, a lookup
+    /// ref on an attribute that is not unique, ...
+    Schema { index: usize, message: String },
+    /// A datom breaks a constraint: a unique value held by another entity, a
+    /// `db/cas` that found another value or a lookup ref that found nothing.
+    Constraint { index: usize, message: String },
+    /// Another process kept the vault locked for longer than the busy timeout,
+    /// even after retrying.
+    Busy,
+    /// The vault could not be read or written.
+    Storage(sqlx::Error),
+}
+
+impl TxError {
+    /// is_busy() tells whether the vault was busy, see TxError::Busy.
+    pub fn is_busy(&self) -> bool {
+        matches!(self, TxError::Busy)
+    }
+
+    /// index() returns the position of the element that caused the error.
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            TxError::Parse { index, .. }
+            | TxError::UnknownOp { index, .. }
+            | TxError::Schema { index, .. }
+            | TxError::Constraint { index, .. } => Some(*index),
+            TxError::Busy | TxError::Storage(_) => None,
+        }
+    }
+
+    /// at() returns the error with its index set to `index`.
+    pub fn at(self, index: usize) -> TxError {
+        match self {
+            TxError::Parse { message, .. } => TxError::Parse { index, message },
+            TxError::UnknownOp { op, .. } => TxError::UnknownOp { index, op },
+            TxError::Schema { message, .. } => TxError::Schema { index, message },
+            TxError::Constraint { message, .. } => TxError::Constraint { index, message },
+            TxError::Busy => TxError::Busy,
+            TxError::Storage(err) => TxError::Storage(err),
+        }
+    }
+
+    /// exit_code() is the process exit code the CLI reports the error with:
+    /// 2 for a malformed transaction, 3 for a schema violation, 4 for a
+    /// failed constraint, 5 for a busy vault and 1 for a storage error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TxError::Parse { .. } | TxError::UnknownOp { .. } => 2,
+            TxError::Schema { .. } => 3,
+            TxError::Constraint { .. } => 4,
+            TxError::Busy => 5,
+            TxError::Storage(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::Parse { index, message } => write!(f, "datom {}: {}", index, message),
+            TxError::UnknownOp { index, op } => {
+                write!(f, "datom {}: unsupported op {}", index, op)
+            }
+            TxError::Schema { index, message } | TxError::Constraint { index, message } => {
+                write!(f, "datom {}: {}", index, message)
+            }
+            TxError::Busy => write!(
+                f,
+                "the vault is busy: another process is writing to it, try again"
+            ),
+            TxError::Storage(err) => write!(f, "storage error: {}", err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for TxError {
+    fn from(err: sqlx::Error) -> TxError {
+        if crate::db::is_busy(&err) {
+            return TxError::Busy;
+        }
+        TxError::Storage(err)
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+
+use sqlx::{Connection, Row, SqliteConnection};
+
+use crate::db::datom::{BATCH_SIZE, Entity, encode_addresses, value_to_entity};
+use crate::db::schema::{Schema, load_schema};
+use crate::db::transit;
+use crate::db::{Db, is_busy, retry_busy};
+
+/// A row of the vaults table, as seen by gc.
+struct VaultRow {
+    addr: i64,
+    entity: Option<Entity>,
+    addresses: String,
+}
+
+/// refresh_addresses() recomputes the addresses column of every row from its
+/// content, so rows written before addresses were recorded are traversed too.
+async fn refresh_addresses(
+    conn: &mut SqliteConnection,
+    rows: &mut [VaultRow],
+    schema: &Schema,
+) -> Result<(), sqlx::Error> {
+    let addrs: HashMap<String, i64> = rows
+        .iter()
+        .filter_map(|row| Some((row.entity.as_ref()?.id.clone(), row.addr)))
+        .collect();
+    for row in rows.iter_mut() {
+        let Some(entity) = &row.entity else {
+            continue;
+        };
+        let addresses = encode_addresses(entity, &addrs, schema);
+        if addresses != row.addresses {
+            sqlx::query("UPDATE vaults SET addresses = ? WHERE addr = ?;")
+                .bind(&addresses)
+                .bind(row.addr)
+                .execute(&mut *conn)
+                .await?;
+            row.addresses = addresses;
+        }
+    }
+    Ok(())
+}
+
+/// is_root() tells whether a row is kept whatever refers to it: a page (an
+/// entity with a `page/` attribute), or a row that cannot be decoded.
+fn is_root(row: &VaultRow) -> bool {
+    row.entity
+        .as_ref()
+        .is_none_or(|entity| entity.attrs.keys().any(|a| a.starts_with("page/")))
+}
+
+/// unreachable_rows() returns the rows no root can reach.
+/// Refs are followed both ways through the addresses column: a block is live
+/// because it refers to a live page, and an entity a live block refers to is
+/// live too. What is left are the empty rows of retracted entities and the
+/// entities cut off from every page, like a block whose page is gone.
+fn unreachable_rows(rows: &[VaultRow]) -> Vec<&VaultRow> {
+    let mut linked: HashMap<i64, Vec<i64>> = HashMap::new();
+    for row in rows {
+        let refs: Vec<i64> = serde_json::from_str(&row.addresses).unwrap_or_default();
+        for addr in refs {
+            linked.entry(row.addr).or_default().push(addr);
+            linked.entry(addr).or_default().push(row.addr);
+        }
+    }
+    let mut live: HashSet<i64> = HashSet::new();
+    let mut stack: Vec<i64> = rows
+        .iter()
+        .filter(|row| is_root(row))
+        .map(|row| row.addr)
+        .collect();
+    while let Some(addr) = stack.pop() {
+        if !live.insert(addr) {
+            continue;
+        }
+        let refs = linked.get(&addr).into_iter().flatten();
+        stack.extend(refs.filter(|addr| !live.contains(addr)));
+    }
+    rows.iter()
+        .filter(|row| !live.contains(&row.addr))
+        .collect()
+}
+
+/// gc_on() removes the entities of the vault that are unreachable, such as
+/// blocks whose attributes were all retracted. They stay in the transaction
+/// log, so `--as-of`, history and undo still see them.
+/// With `dry_run` nothing is changed.
+///
+/// Returns the ids of the removed entities.
+/// # Errors
+/// Returns an error if the SQL query fails.
+async fn gc_on(conn: &mut SqliteConnection, dry_run: bool) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+
+    let mut rows: Vec<VaultRow> = sqlx::query("SELECT addr, content, addresses FROM vaults;")
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(|row| VaultRow {
+            addr: row.get("addr"),
+            entity: transit::decode_value(row.get("content"))
+                .ok()
+                .and_then(value_to_entity),
+            addresses: row
+                .get::<Option<String>, _>("addresses")
+                .unwrap_or_default(),
+        })
+        .collect();
+    let schema = load_schema(&mut tx).await?;
+    refresh_addresses(&mut tx, &mut rows, &schema).await?;
+
+    let garbage = unreachable_rows(&rows);
+    for chunk in garbage.chunks(BATCH_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!("DELETE FROM vaults WHERE addr IN ({});", placeholders);
+        let mut query = sqlx::query(&sql);
+        for row in chunk {
+            query = query.bind(row.addr);
+        }
+        query.execute(&mut *tx).await?;
+    }
+    let mut removed: Vec<String> = garbage
+        .iter()
+        .filter_map(|row| Some(row.entity.as_ref()?.id.clone()))
+        .collect();
+    removed.sort();
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(removed)
+}
+
+/// gc() removes unreachable entities from the vault, see gc_on().
+pub async fn gc(db: &Db, dry_run: bool) -> Result<Vec<String>, sqlx::Error> {
+    retry_busy(is_busy, || async {
+        let mut conn = db.conn().await?;
+        gc_on(&mut conn, dry_run).await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datom::{Datom, DatomOp, addr_for_entity_id, apply_datoms_on};
+    use crate::db::{TxMeta, test_conn};
+
+    fn datom(op: DatomOp, e: &str, a: &str, v: &str) -> Datom {
+        Datom {
+            op,
+            e: e.to_string(),
+            a: a.to_string(),
+            v: v.into(),
+        }
+    }
+
+    async fn addresses_of(conn: &mut SqliteConnection, id: &str) -> String {
+        sqlx::query("SELECT addresses FROM vaults WHERE entity_id = ?;")
+            .bind(id)
+            .fetch_one(conn)
+            .await
+            .unwrap()
+            .get("addresses")
+    }
+
+    #[tokio::test]
+    async fn blocks_record_the_addr_of_their_page() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Add, "page:1", "page/name", "1"),
+                datom(DatomOp::Add, "block:1", "block/page", "page:1"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let page = addr_for_entity_id("page:1");
+        assert_eq!(
+            addresses_of(&mut conn, "block:1").await,
+            format!("[{}]", page)
+        );
+        assert_eq!(addresses_of(&mut conn, "page:1").await, "[]");
+    }
+
+    #[tokio::test]
+    async fn gc_removes_retracted_blocks_only() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Add, "page:1", "page/name", "1"),
+                datom(DatomOp::Add, "block:1", "block/page", "page:1"),
+                datom(DatomOp::Add, "block:2", "block/page", "page:1"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Retract, "block:2", "block/page", "page:1")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        // rows written before addresses were recorded still reach their page
+        sqlx::query("UPDATE vaults SET addresses = '[]';")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(gc_on(&mut conn, true).await.unwrap(), vec!["block:2"]);
+        assert_eq!(gc_on(&mut conn, false).await.unwrap(), vec!["block:2"]);
+        assert!(gc_on(&mut conn, false).await.unwrap().is_empty());
+
+        let ids: Vec<String> = sqlx::query("SELECT entity_id FROM vaults ORDER BY entity_id;")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("entity_id"))
+            .collect();
+        assert_eq!(ids, vec!["block:1", "page:1"]);
+    }
+
+    #[tokio::test]
+    async fn gc_removes_entities_cut_off_from_every_page() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                datom(DatomOp::Add, "page:1", "page/name", "1"),
+                datom(DatomOp::Add, "block:1", "block/content", "kept"),
+                datom(DatomOp::Add, "block:1", "block/page", "page:1"),
+                datom(DatomOp::Add, "page:2", "page/name", "2"),
+                datom(DatomOp::Add, "block:2", "block/content", "orphaned"),
+                datom(DatomOp::Add, "block:2", "block/page", "page:2"),
+                datom(DatomOp::Add, "block:3", "block/content", "never on a page"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        apply_datoms_on(
+            &mut conn,
+            &[datom(DatomOp::Retract, "page:2", "page/name", "2")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            gc_on(&mut conn, false).await.unwrap(),
+            vec!["block:2", "block:3", "page:2"]
+        );
+        let ids: Vec<String> = sqlx::query("SELECT entity_id FROM vaults ORDER BY entity_id;")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("entity_id"))
+            .collect();
+        assert_eq!(ids, vec!["block:1", "page:1"]);
+    }
+}
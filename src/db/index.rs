@@ -0,0 +1,494 @@
+use serde_json::Value;
+use sqlx::{Connection, Row, SqliteConnection};
+
+use crate::db::datom::{BATCH_SIZE, Entity, scan_entities};
+use crate::db::migrate::{backfilled, record_backfill};
+
+fn encode_index_value(value: &Value) -> Result<String, sqlx::Error> {
+    serde_json::to_string(value).map_err(|err| sqlx::Error::Protocol(err.to_string()))
+}
+
+/// index_rows() returns the (attribute, value) rows an entity has in the
+/// `datoms` index table, with values JSON encoded. An array, which is how
+/// cardinality-many attributes are stored, has one row per element.
+fn index_rows(entity: &Entity) -> Result<Vec<(&str, String)>, sqlx::Error> {
+    let mut rows = Vec::new();
+    for (a, v) in &entity.attrs {
+        match v {
+            Value::Array(values) => {
+                for v in values {
+                    rows.push((a.as_str(), encode_index_value(v)?));
+                }
+            }
+            v => rows.push((a.as_str(), encode_index_value(v)?)),
+        }
+    }
+    Ok(rows)
+}
+
+/// reindex_entities() replaces the rows of the given entities in the `datoms`
+/// index table with their current attributes.
+/// Entities without attributes end up with no rows at all.
+/// # Errors
+/// Returns an error if the SQL query fails or if a value cannot be encoded.
+pub(crate) async fn reindex_entities(
+    conn: &mut SqliteConnection,
+    entities: &[Entity],
+) -> Result<(), sqlx::Error> {
+    for chunk in entities.chunks(BATCH_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!("DELETE FROM datoms WHERE e IN ({});", placeholders);
+        let mut query = sqlx::query(&sql);
+        for entity in chunk {
+            query = query.bind(&entity.id);
+        }
+        query.execute(&mut *conn).await?;
+    }
+
+    let mut rows = Vec::new();
+    for entity in entities {
+        for (a, v) in index_rows(entity)? {
+            rows.push((entity.id.as_str(), a, v));
+        }
+    }
+    for chunk in rows.chunks(BATCH_SIZE) {
+        let placeholders = vec!["(?, ?, ?)"; chunk.len()].join(", ");
+        let sql = format!(
+            "INSERT OR IGNORE INTO datoms (e, a, v) VALUES {};",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for (e, a, v) in chunk {
+            query = query.bind(*e).bind(*a).bind(v);
+        }
+        query.execute(&mut *conn).await?;
+    }
+
+    reindex_fulltext(conn, "blocks_fts", entities).await
+}
+
+/// reindex_fulltext() replaces the rows of the given entities in a full-text
+/// table with their current "block/content".
+async fn reindex_fulltext(
+    conn: &mut SqliteConnection,
+    table: &str,
+    entities: &[Entity],
+) -> Result<(), sqlx::Error> {
+    for chunk in entities.chunks(BATCH_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!("DELETE FROM {} WHERE e IN ({});", table, placeholders);
+        let mut query = sqlx::query(&sql);
+        for entity in chunk {
+            query = query.bind(&entity.id);
+        }
+        query.execute(&mut *conn).await?;
+    }
+
+    let rows: Vec<(&str, &str)> = entities
+        .iter()
+        .filter_map(|entity| match entity.attrs.get("block/content") {
+            Some(Value::String(content)) => Some((entity.id.as_str(), content.as_str())),
+            _ => None,
+        })
+        .collect();
+    for chunk in rows.chunks(BATCH_SIZE) {
+        let placeholders = vec!["(?, ?)"; chunk.len()].join(", ");
+        let sql = format!(
+            "INSERT INTO {} (e, content) VALUES {};",
+            table, placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for (e, content) in chunk {
+            query = query.bind(*e).bind(*content);
+        }
+        query.execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Marks the start of a matched term in a search snippet.
+pub const HIGHLIGHT_START: &str = "\u{2}";
+/// Marks the end of a matched term in a search snippet.
+pub const HIGHLIGHT_END: &str = "\u{3}";
+
+/// A block matched by a full-text search.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub entity: Entity,
+    /// The matching part of "block/content", with matched terms wrapped in
+    /// HIGHLIGHT_START and HIGHLIGHT_END.
+    pub snippet: String,
+}
+
+/// quote_phrase() turns arbitrary text into a single FTS5 phrase.
+fn quote_phrase(term: &str) -> String {
+    format!("\"{}\"", term.replace('"', "\"\""))
+}
+
+/// is_query_error() tells whether FTS5 rejected the term of a MATCH: a syntax
+/// error, a `word:` read as a column filter (as in `TODO:`) or an unterminated
+/// quote. Any other error, like a busy vault, is not about the term.
+fn is_query_error(err: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(err) = err else {
+        return false;
+    };
+    let message = err.message();
+    message.starts_with("fts5: syntax error")
+        || message.starts_with("no such column")
+        || message.starts_with("unterminated string")
+}
+
+/// fulltext_matches() runs an FTS5 query against a full-text table and returns
+/// (entity id, snippet) pairs, best bm25 rank first.
+/// The query supports FTS5 syntax: words, "phrases", prefix*, AND, OR and NOT.
+/// If the term is not a valid FTS5 query, as in `TODO:`, it is searched as
+/// a phrase instead.
+async fn fulltext_matches(
+    conn: &mut SqliteConnection,
+    table: &str,
+    term: &str,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let sql = format!(
+        "SELECT e, snippet({table}, 1, ?, ?, '…', 16) AS snippet
+        FROM {table} WHERE {table} MATCH ?
+        ORDER BY bm25({table});"
+    );
+    let run = |query: String| {
+        sqlx::query(&sql)
+            .bind(HIGHLIGHT_START)
+            .bind(HIGHLIGHT_END)
+            .bind(query)
+    };
+    let rows = match run(term.to_string()).fetch_all(&mut *conn).await {
+        Ok(rows) => rows,
+        Err(err) if is_query_error(&err) => run(quote_phrase(term)).fetch_all(&mut *conn).await?,
+        Err(err) => return Err(err),
+    };
+    Ok(rows
+        .iter()
+        .map(|row| (row.get("e"), row.get("snippet")))
+        .collect())
+}
+
+/// search_fulltext() returns the ids and snippets of the blocks matching an
+/// FTS5 query, best match first.
+pub(crate) async fn search_fulltext(
+    conn: &mut SqliteConnection,
+    term: &str,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    fulltext_matches(conn, "blocks_fts", term).await
+}
+
+/// search_fulltext_in() runs the same search as search_fulltext() over the
+/// given entities instead of the current blocks, using a temporary full-text
+/// table that is dropped afterwards.
+pub(crate) async fn search_fulltext_in(
+    conn: &mut SqliteConnection,
+    entities: &[Entity],
+    term: &str,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    sqlx::query(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS temp.blocks_fts_snapshot
+        USING fts5(e UNINDEXED, content);",
+    )
+    .execute(&mut *conn)
+    .await?;
+    let result = match reindex_fulltext(conn, "blocks_fts_snapshot", entities).await {
+        Ok(()) => fulltext_matches(conn, "blocks_fts_snapshot", term).await,
+        Err(err) => Err(err),
+    };
+    sqlx::query("DROP TABLE temp.blocks_fts_snapshot;")
+        .execute(&mut *conn)
+        .await?;
+    result
+}
+
+/// entities_with() returns the ids of the entities whose attribute `a` has
+/// the value `v`, using the attribute→value→entity index.
+pub(crate) async fn entities_with(
+    conn: &mut SqliteConnection,
+    a: &str,
+    v: &Value,
+) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query("SELECT e FROM datoms WHERE a = ? AND v = ? ORDER BY e;")
+        .bind(a)
+        .bind(encode_index_value(v)?)
+        .fetch_all(conn)
+        .await?;
+    Ok(rows.iter().map(|row| row.get("e")).collect())
+}
+
+/// match_datoms() returns the (entity, attribute, value) rows of the `datoms`
+/// index that have the given entity, attribute and value; None matches any.
+/// The attribute→value→entity index serves lookups by value.
+pub(crate) async fn match_datoms(
+    conn: &mut SqliteConnection,
+    e: Option<&str>,
+    a: Option<&str>,
+    v: Option<&Value>,
+) -> Result<Vec<(String, String, Value)>, sqlx::Error> {
+    let v = v.map(encode_index_value).transpose()?;
+    let mut conditions = Vec::new();
+    for (column, bound) in [("e", e.is_some()), ("a", a.is_some()), ("v", v.is_some())] {
+        if bound {
+            conditions.push(format!("{} = ?", column));
+        }
+    }
+    let sql = if conditions.is_empty() {
+        "SELECT e, a, v FROM datoms;".to_string()
+    } else {
+        format!(
+            "SELECT e, a, v FROM datoms WHERE {};",
+            conditions.join(" AND ")
+        )
+    };
+    let mut query = sqlx::query(&sql);
+    for bound in [e, a, v.as_deref()].into_iter().flatten() {
+        query = query.bind(bound);
+    }
+    let rows = query.fetch_all(conn).await?;
+    rows.iter()
+        .map(|row| {
+            let v = serde_json::from_str(&row.get::<String, _>("v"))
+                .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+            Ok((row.get("e"), row.get("a"), v))
+        })
+        .collect()
+}
+
+/// attribute_values() returns the distinct values of attribute `a` across all
+/// entities, using the attribute→value→entity index.
+pub(crate) async fn attribute_values(
+    conn: &mut SqliteConnection,
+    a: &str,
+) -> Result<Vec<Value>, sqlx::Error> {
+    let rows = sqlx::query("SELECT DISTINCT v FROM datoms WHERE a = ? ORDER BY v;")
+        .bind(a)
+        .fetch_all(conn)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            serde_json::from_str(&row.get::<String, _>("v"))
+                .map_err(|err| sqlx::Error::Protocol(err.to_string()))
+        })
+        .collect()
+}
+
+const INDEXES_BACKFILL: &str = "indexes";
+
+/// backfill_indexes() builds the `datoms` and `blocks_fts` indexes from the
+/// vaults table when they are empty, which is the case for vaults written
+/// before they existed. It runs once per vault, as recorded in `backfills`,
+/// so opening a vault doesn't scan the indexes every time.
+pub(crate) async fn backfill_indexes(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    if backfilled(conn, INDEXES_BACKFILL).await? {
+        return Ok(());
+    }
+    let mut sql_tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    // another process may have run it while this one waited for the lock
+    if backfilled(&mut sql_tx, INDEXES_BACKFILL).await? {
+        return Ok(());
+    }
+    let row = sqlx::query(
+        "SELECT
+            EXISTS (SELECT 1 FROM datoms) AS indexed,
+            EXISTS (SELECT 1 FROM datoms WHERE a = 'block/content') AS blocks,
+            EXISTS (SELECT 1 FROM blocks_fts) AS searchable;",
+    )
+    .fetch_one(&mut *sql_tx)
+    .await?;
+    let indexed: bool = row.get("indexed");
+    let blocks: bool = row.get("blocks");
+    let searchable: bool = row.get("searchable");
+    if !indexed || (blocks && !searchable) {
+        let entities = scan_entities(&mut sql_tx).await?;
+        reindex_entities(&mut sql_tx, &entities).await?;
+    }
+    record_backfill(&mut sql_tx, INDEXES_BACKFILL).await?;
+    sql_tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, json};
+
+    use super::*;
+    use crate::db::test_conn;
+
+    fn entity(id: &str, attrs: Value) -> Entity {
+        Entity {
+            id: id.to_string(),
+            attrs: attrs.as_object().cloned().unwrap_or_else(Map::new),
+        }
+    }
+
+    #[tokio::test]
+    async fn reindex_replaces_entity_rows() {
+        let mut conn = test_conn().await;
+        reindex_entities(
+            &mut conn,
+            &[
+                entity("block:1", json!({"block/page": "page:1"})),
+                entity("block:2", json!({"block/page": "page:1"})),
+            ],
+        )
+        .await
+        .unwrap();
+        reindex_entities(
+            &mut conn,
+            &[entity("block:1", json!({"block/page": "page:2"}))],
+        )
+        .await
+        .unwrap();
+
+        let on_page = entities_with(&mut conn, "block/page", &json!("page:1"))
+            .await
+            .unwrap();
+        assert_eq!(on_page, vec!["block:2"]);
+        let pages = attribute_values(&mut conn, "block/page").await.unwrap();
+        assert_eq!(pages, vec![json!("page:1"), json!("page:2")]);
+
+        reindex_entities(&mut conn, &[entity("block:2", json!({}))])
+            .await
+            .unwrap();
+        let on_page = entities_with(&mut conn, "block/page", &json!("page:1"))
+            .await
+            .unwrap();
+        assert!(on_page.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backfill_indexes_existing_vault_rows() {
+        let mut conn = test_conn().await;
+        sqlx::query("INSERT INTO vaults (addr, content, addresses) VALUES (1, ?, '[]');")
+            .bind(r#"{"id":"page:1","attrs":{"page/name":"2026-01-10"}}"#)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        backfill_indexes(&mut conn).await.unwrap();
+
+        let names = attribute_values(&mut conn, "page/name").await.unwrap();
+        assert_eq!(names, vec![json!("2026-01-10")]);
+
+        // it runs once: later opens don't look at the vault again
+        sqlx::query("DELETE FROM datoms;")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        backfill_indexes(&mut conn).await.unwrap();
+        assert!(
+            attribute_values(&mut conn, "page/name")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    fn block(id: &str, content: &str) -> Entity {
+        entity(id, json!({ "block/content": content }))
+    }
+
+    async fn search_ids(conn: &mut SqliteConnection, term: &str) -> Vec<String> {
+        search_fulltext(conn, term)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(e, _)| e)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fulltext_search_supports_fts5_queries() {
+        let mut conn = test_conn().await;
+        reindex_entities(
+            &mut conn,
+            &[
+                block("block:1", "TODO: finish the project"),
+                block("block:2", "Project review with the team"),
+                block("block:3", "projection math"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(search_ids(&mut conn, "todo").await, vec!["block:1"]);
+        assert_eq!(search_ids(&mut conn, "TODO:").await, vec!["block:1"]);
+        assert_eq!(search_ids(&mut conn, "\"the team\"").await, vec!["block:2"]);
+        assert_eq!(
+            search_ids(&mut conn, "project NOT todo").await,
+            vec!["block:2"]
+        );
+        let mut prefixed = search_ids(&mut conn, "proj*").await;
+        prefixed.sort();
+        assert_eq!(prefixed, vec!["block:1", "block:2", "block:3"]);
+
+        let hits = search_fulltext(&mut conn, "review").await.unwrap();
+        assert_eq!(
+            hits[0].1,
+            format!(
+                "Project {}review{} with the team",
+                HIGHLIGHT_START, HIGHLIGHT_END
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn only_malformed_terms_are_searched_as_phrases() {
+        let path = std::env::temp_dir().join(format!(
+            "jottty_fts_errors_{}_{}.db",
+            std::process::id(),
+            crate::date::now_nanos()
+        ));
+        let options = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .busy_timeout(std::time::Duration::ZERO);
+        let mut conn = sqlx::SqliteConnection::connect_with(&options)
+            .await
+            .unwrap();
+        crate::db::migrate::run_migrations(&mut conn).await.unwrap();
+        reindex_entities(&mut conn, &[block("block:1", "(project) \"the team")])
+            .await
+            .unwrap();
+        for term in ["(project", "project AND", "\"the team", "TODO:"] {
+            let hits = search_fulltext(&mut conn, term).await;
+            assert!(hits.is_ok(), "{}: {:?}", term, hits);
+        }
+
+        // a locked vault is an error, not a reason to search for something else
+        let mut other = sqlx::SqliteConnection::connect_with(&options)
+            .await
+            .unwrap();
+        sqlx::query("BEGIN EXCLUSIVE;")
+            .execute(&mut other)
+            .await
+            .unwrap();
+        let err = search_fulltext(&mut conn, "(project").await.unwrap_err();
+        assert!(crate::db::is_busy(&err), "{}", err);
+        assert!(!is_query_error(&err));
+        drop(other);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn fulltext_search_follows_reindex() {
+        let mut conn = test_conn().await;
+        reindex_entities(&mut conn, &[block("block:1", "old words")])
+            .await
+            .unwrap();
+        reindex_entities(&mut conn, &[block("block:1", "new words")])
+            .await
+            .unwrap();
+
+        assert!(search_ids(&mut conn, "old").await.is_empty());
+        assert_eq!(search_ids(&mut conn, "new").await, vec!["block:1"]);
+
+        let snapshot = [block("block:1", "old words")];
+        let hits = search_fulltext_in(&mut conn, &snapshot, "old")
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert!(search_ids(&mut conn, "old").await.is_empty());
+    }
+}
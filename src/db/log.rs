@@ -0,0 +1,506 @@
+use sqlx::{Row, SqliteConnection, sqlite::SqliteRow};
+
+use crate::date::now_millis;
+use crate::db::Db;
+use crate::db::datom::{AsOf, BATCH_SIZE, Datom, DatomOp};
+
+/// What a transaction was recorded as, for undo and redo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TxKind {
+    #[default]
+    Tx,
+    Undo,
+    Redo,
+}
+
+impl TxKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxKind::Tx => "tx",
+            TxKind::Undo => "undo",
+            TxKind::Redo => "redo",
+        }
+    }
+
+    fn parse(kind: &str) -> Result<TxKind, sqlx::Error> {
+        match kind {
+            "tx" => Ok(TxKind::Tx),
+            "undo" => Ok(TxKind::Undo),
+            "redo" => Ok(TxKind::Redo),
+            _ => Err(sqlx::Error::Protocol(format!(
+                "Unknown tx kind in tx log: {}",
+                kind
+            ))),
+        }
+    }
+}
+
+/// Metadata stored with a transaction in the log.
+#[derive(Debug, Clone, Default)]
+pub struct TxMeta {
+    pub kind: TxKind,
+    /// The transaction an undo or redo reverts.
+    pub reverts: Option<i64>,
+    /// The command or client that made the transaction, like `cli:add`.
+    pub source: Option<String>,
+    /// Who made the transaction, the `author` of the config.
+    pub author: Option<String>,
+    /// Why the transaction was made.
+    pub message: Option<String>,
+}
+
+fn meta_from_row(row: &SqliteRow) -> Result<TxMeta, sqlx::Error> {
+    Ok(TxMeta {
+        kind: TxKind::parse(row.get("kind"))?,
+        reverts: row.get("reverts"),
+        source: row.get("source"),
+        author: row.get("author"),
+        message: row.get("message"),
+    })
+}
+
+/// append_tx() records a new transaction in the log.
+/// It inserts a row in `transactions` with the current wall-clock time and one
+/// row in `tx_datoms` per datom, keeping the order in which they were applied.
+/// The datoms are inserted in batches of BATCH_SIZE rows.
+///
+/// Returns the id of the new transaction.
+/// # Arguments
+/// * `conn` - A mutable reference to a SqliteConnection.
+/// * `datoms` - The datoms changed by the transaction.
+/// * `meta` - The metadata to store with the transaction.
+/// # Errors
+/// Returns an error if the SQL query fails or if a value cannot be encoded.
+pub(crate) async fn append_tx(
+    conn: &mut SqliteConnection,
+    datoms: &[Datom],
+    meta: &TxMeta,
+) -> Result<i64, sqlx::Error> {
+    let tx = sqlx::query(
+        "INSERT INTO transactions (tx_instant, kind, reverts, source, author, message)
+        VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(now_millis())
+    .bind(meta.kind.as_str())
+    .bind(meta.reverts)
+    .bind(&meta.source)
+    .bind(&meta.author)
+    .bind(&meta.message)
+    .execute(&mut *conn)
+    .await?
+    .last_insert_rowid();
+
+    let mut idx = 0i64;
+    for chunk in datoms.chunks(BATCH_SIZE) {
+        let placeholders = vec!["(?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ");
+        let sql = format!(
+            "INSERT INTO tx_datoms (tx, idx, op, e, a, v) VALUES {};",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for datom in chunk {
+            let v = serde_json::to_string(&datom.v)
+                .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+            query = query
+                .bind(tx)
+                .bind(idx)
+                .bind(datom.op.as_str())
+                .bind(&datom.e)
+                .bind(&datom.a)
+                .bind(v);
+            idx += 1;
+        }
+        query.execute(&mut *conn).await?;
+    }
+
+    Ok(tx)
+}
+
+fn op_from_log(op: &str) -> Result<DatomOp, sqlx::Error> {
+    match op {
+        "db/add" => Ok(DatomOp::Add),
+        "db/retract" => Ok(DatomOp::Retract),
+        _ => Err(sqlx::Error::Protocol(format!(
+            "Unknown op in tx log: {}",
+            op
+        ))),
+    }
+}
+
+/// resolve_as_of() turns an AsOf into the id of the last transaction it covers.
+/// An instant resolves to the last transaction recorded at or before it,
+/// or 0 when the log has nothing that old.
+pub(crate) async fn resolve_as_of(
+    conn: &mut SqliteConnection,
+    as_of: AsOf,
+) -> Result<i64, sqlx::Error> {
+    match as_of {
+        AsOf::Tx(tx) => Ok(tx),
+        AsOf::Instant(instant) => {
+            let row = sqlx::query(
+                "SELECT COALESCE(MAX(tx), 0) AS tx FROM transactions WHERE tx_instant <= ?;",
+            )
+            .bind(instant)
+            .fetch_one(conn)
+            .await?;
+            Ok(row.get("tx"))
+        }
+    }
+}
+
+/// datoms_after() returns the logged datoms of every transaction after `tx`,
+/// oldest first, each with its transaction.
+pub(crate) async fn datoms_after(
+    conn: &mut SqliteConnection,
+    tx: i64,
+) -> Result<Vec<(i64, Datom)>, sqlx::Error> {
+    let rows = sqlx::query("SELECT tx, op, e, a, v FROM tx_datoms WHERE tx > ? ORDER BY tx, idx;")
+        .bind(tx)
+        .fetch_all(conn)
+        .await?;
+
+    rows.iter()
+        .map(|row| Ok((row.get("tx"), datom_from_row(row)?)))
+        .collect()
+}
+
+fn datom_from_row(row: &SqliteRow) -> Result<Datom, sqlx::Error> {
+    let v: String = row.get("v");
+    Ok(Datom {
+        op: op_from_log(row.get("op"))?,
+        e: row.get("e"),
+        a: row.get("a"),
+        v: serde_json::from_str(&v).map_err(|err| sqlx::Error::Protocol(err.to_string()))?,
+    })
+}
+
+/// A logged datom together with the transaction it belongs to.
+pub(crate) struct LoggedDatom {
+    pub tx: i64,
+    pub tx_instant: i64,
+    pub meta: TxMeta,
+    pub datom: Datom,
+}
+
+/// entity_datoms() returns every logged datom about the given entities,
+/// oldest first. The log is read with one query per BATCH_SIZE ids.
+pub(crate) async fn entity_datoms(
+    conn: &mut SqliteConnection,
+    entity_ids: &[String],
+) -> Result<Vec<LoggedDatom>, sqlx::Error> {
+    let mut logged = Vec::new();
+    for chunk in entity_ids.chunks(BATCH_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!(
+            "SELECT t.tx, t.tx_instant, t.kind, t.reverts, t.source, t.author, t.message,
+                d.idx, d.op, d.e, d.a, d.v
+            FROM tx_datoms d JOIN transactions t ON t.tx = d.tx
+            WHERE d.e IN ({});",
+            placeholders
+        );
+        let mut query = sqlx::query(&sql);
+        for id in chunk {
+            query = query.bind(id);
+        }
+        for row in query.fetch_all(&mut *conn).await? {
+            let idx: i64 = row.get("idx");
+            let datom = LoggedDatom {
+                tx: row.get("tx"),
+                tx_instant: row.get("tx_instant"),
+                meta: meta_from_row(&row)?,
+                datom: datom_from_row(&row)?,
+            };
+            logged.push((idx, datom));
+        }
+    }
+    // the chunks each come back in their own order
+    logged.sort_by_key(|(idx, logged)| (logged.tx, *idx));
+    Ok(logged.into_iter().map(|(_, logged)| logged).collect())
+}
+
+/// logged_referrers() returns the entities that had `attr` set to `entity_id`
+/// at any point in the log.
+pub(crate) async fn logged_referrers(
+    conn: &mut SqliteConnection,
+    attr: &str,
+    entity_id: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let v =
+        serde_json::to_string(entity_id).map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+    let rows = sqlx::query("SELECT DISTINCT e FROM tx_datoms WHERE a = ? AND v = ?;")
+        .bind(attr)
+        .bind(v)
+        .fetch_all(conn)
+        .await?;
+    Ok(rows.iter().map(|row| row.get("e")).collect())
+}
+
+/// tx_datoms_on() returns the datoms logged for a single transaction, in order.
+pub(crate) async fn tx_datoms_on(
+    conn: &mut SqliteConnection,
+    tx: i64,
+) -> Result<Vec<Datom>, sqlx::Error> {
+    let rows = sqlx::query("SELECT op, e, a, v FROM tx_datoms WHERE tx = ? ORDER BY idx;")
+        .bind(tx)
+        .fetch_all(conn)
+        .await?;
+    rows.iter().map(datom_from_row).collect()
+}
+
+/// A transaction in the log, see transactions().
+#[derive(Debug, Clone)]
+pub struct TxInfo {
+    pub tx: i64,
+    pub tx_instant: i64,
+    pub meta: TxMeta,
+    /// The number of datoms the transaction changed.
+    pub datoms: i64,
+}
+
+/// Which transactions transactions() lists.
+#[derive(Debug, Clone, Default)]
+pub struct TxFilter {
+    pub author: Option<String>,
+    pub source: Option<String>,
+    /// At most this many of the most recent matching transactions.
+    pub limit: Option<usize>,
+}
+
+async fn transactions_on(
+    conn: &mut SqliteConnection,
+    filter: &TxFilter,
+) -> Result<Vec<TxInfo>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT t.tx, t.tx_instant, t.kind, t.reverts, t.source, t.author, t.message,
+            (SELECT COUNT(*) FROM tx_datoms d WHERE d.tx = t.tx) AS datoms
+        FROM transactions t
+        WHERE (?1 IS NULL OR t.author = ?1) AND (?2 IS NULL OR t.source = ?2)
+        ORDER BY t.tx DESC
+        LIMIT ?3;",
+    )
+    .bind(&filter.author)
+    .bind(&filter.source)
+    .bind(filter.limit.map_or(-1, |limit| limit as i64))
+    .fetch_all(conn)
+    .await?;
+
+    let mut txs = rows
+        .iter()
+        .map(|row| {
+            Ok(TxInfo {
+                tx: row.get("tx"),
+                tx_instant: row.get("tx_instant"),
+                meta: meta_from_row(row)?,
+                datoms: row.get("datoms"),
+            })
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    txs.reverse();
+    Ok(txs)
+}
+
+/// transactions() lists the logged transactions that match `filter`, with
+/// their metadata, oldest first.
+pub async fn transactions(db: &Db, filter: &TxFilter) -> Result<Vec<TxInfo>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    transactions_on(&mut conn, filter).await
+}
+
+/// The transactions that undo and redo would revert next, most recent last.
+#[derive(Debug, Default)]
+struct UndoStacks {
+    undo: Vec<i64>,
+    redo: Vec<i64>,
+}
+
+/// undo_stacks() replays the kinds of the logged transactions to find what
+/// can be undone or redone. A regular transaction clears the redo stack, an
+/// undo moves the tx it reverts to the redo stack and a redo moves it back.
+/// Regular transactions that changed nothing are skipped. Undos and redos
+/// count even when they changed nothing, as when the state they reverted to
+/// was already there, so the tx they revert still moves to the other stack.
+async fn undo_stacks(conn: &mut SqliteConnection) -> Result<UndoStacks, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT tx, kind, reverts FROM transactions t
+        WHERE t.reverts IS NOT NULL
+            OR EXISTS (SELECT 1 FROM tx_datoms d WHERE d.tx = t.tx)
+        ORDER BY tx;",
+    )
+    .fetch_all(conn)
+    .await?;
+
+    let mut stacks = UndoStacks::default();
+    for row in rows {
+        let tx: i64 = row.get("tx");
+        let reverts: Option<i64> = row.get("reverts");
+        match row.get::<String, _>("kind").as_str() {
+            "undo" => {
+                stacks.undo.retain(|t| Some(*t) != reverts);
+                stacks.redo.push(tx);
+            }
+            "redo" => {
+                stacks.redo.retain(|t| Some(*t) != reverts);
+                stacks.undo.push(tx);
+            }
+            _ => {
+                stacks.undo.push(tx);
+                stacks.redo.clear();
+            }
+        }
+    }
+    Ok(stacks)
+}
+
+/// revert_target() returns the transaction a revert of the given kind
+/// reverts next: the last undoable tx for `jottty undo` and the last undo tx
+/// for `jottty redo`. A regular transaction reverts nothing.
+pub(crate) async fn revert_target(
+    conn: &mut SqliteConnection,
+    kind: TxKind,
+) -> Result<Option<i64>, sqlx::Error> {
+    let stacks = undo_stacks(conn).await?;
+    Ok(match kind {
+        TxKind::Undo => stacks.undo.last().copied(),
+        TxKind::Redo => stacks.redo.last().copied(),
+        TxKind::Tx => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_conn;
+
+    fn add(e: &str, v: &str) -> Datom {
+        Datom {
+            op: DatomOp::Add,
+            e: e.to_string(),
+            a: "block/content".to_string(),
+            v: v.into(),
+        }
+    }
+
+    fn meta(kind: TxKind, reverts: i64) -> TxMeta {
+        TxMeta {
+            kind,
+            reverts: Some(reverts),
+            ..TxMeta::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn undo_stacks_follow_undo_and_redo() {
+        let mut conn = test_conn().await;
+        let t1 = append_tx(&mut conn, &[add("block:1", "a")], &TxMeta::default())
+            .await
+            .unwrap();
+        let t2 = append_tx(&mut conn, &[add("block:2", "b")], &TxMeta::default())
+            .await
+            .unwrap();
+        // an empty transaction is neither undone nor clears the redo stack
+        append_tx(&mut conn, &[], &TxMeta::default()).await.unwrap();
+
+        let u2 = append_tx(&mut conn, &[add("block:2", "x")], &meta(TxKind::Undo, t2))
+            .await
+            .unwrap();
+        let stacks = undo_stacks(&mut conn).await.unwrap();
+        assert_eq!(stacks.undo, vec![t1]);
+        assert_eq!(stacks.redo, vec![u2]);
+
+        let r2 = append_tx(&mut conn, &[add("block:2", "b")], &meta(TxKind::Redo, u2))
+            .await
+            .unwrap();
+        let stacks = undo_stacks(&mut conn).await.unwrap();
+        assert_eq!(stacks.undo, vec![t1, r2]);
+        assert!(stacks.redo.is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_tx_clears_redo_stack() {
+        let mut conn = test_conn().await;
+        let t1 = append_tx(&mut conn, &[add("block:1", "a")], &TxMeta::default())
+            .await
+            .unwrap();
+        append_tx(&mut conn, &[add("block:1", "x")], &meta(TxKind::Undo, t1))
+            .await
+            .unwrap();
+        let t3 = append_tx(&mut conn, &[add("block:3", "c")], &TxMeta::default())
+            .await
+            .unwrap();
+
+        let stacks = undo_stacks(&mut conn).await.unwrap();
+        assert_eq!(stacks.undo, vec![t3]);
+        assert!(stacks.redo.is_empty());
+    }
+
+    #[tokio::test]
+    async fn transactions_keep_their_metadata() {
+        let mut conn = test_conn().await;
+        let by = |author: &str, source: &str| TxMeta {
+            source: Some(source.to_string()),
+            author: Some(author.to_string()),
+            ..TxMeta::default()
+        };
+        append_tx(&mut conn, &[add("block:1", "a")], &by("ana", "cli:add"))
+            .await
+            .unwrap();
+        let t2 = append_tx(
+            &mut conn,
+            &[add("block:1", "b"), add("block:2", "c")],
+            &TxMeta {
+                message: Some("fix typo".to_string()),
+                ..by("bo", "api")
+            },
+        )
+        .await
+        .unwrap();
+        let t3 = append_tx(&mut conn, &[add("block:2", "d")], &by("ana", "cli:edit"))
+            .await
+            .unwrap();
+
+        let all = transactions_on(&mut conn, &TxFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1].tx, t2);
+        assert_eq!(all[1].datoms, 2);
+        assert_eq!(all[1].meta.source.as_deref(), Some("api"));
+        assert_eq!(all[1].meta.message.as_deref(), Some("fix typo"));
+
+        let ana = TxFilter {
+            author: Some("ana".to_string()),
+            limit: Some(1),
+            ..TxFilter::default()
+        };
+        let txs = transactions_on(&mut conn, &ana).await.unwrap();
+        assert_eq!(txs.iter().map(|t| t.tx).collect::<Vec<_>>(), vec![t3]);
+        assert_eq!(txs[0].meta.source.as_deref(), Some("cli:edit"));
+
+        let logged = entity_datoms(&mut conn, &["block:2".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(logged[0].meta.author.as_deref(), Some("bo"));
+    }
+
+    #[tokio::test]
+    async fn entity_datoms_reads_more_ids_than_a_batch() {
+        let mut conn = test_conn().await;
+        let ids: Vec<String> = (0..BATCH_SIZE * 2 + 3)
+            .map(|i| format!("block:{}", i))
+            .collect();
+        let datoms: Vec<Datom> = ids.iter().rev().map(|id| add(id, "x")).collect();
+        let first = append_tx(&mut conn, &datoms, &TxMeta::default())
+            .await
+            .unwrap();
+        let second = append_tx(&mut conn, &[add("block:0", "y")], &TxMeta::default())
+            .await
+            .unwrap();
+
+        let logged = entity_datoms(&mut conn, &ids).await.unwrap();
+        assert_eq!(logged.len(), ids.len() + 1);
+        // oldest first, in log order within a transaction
+        assert!(logged[..ids.len()].iter().all(|l| l.tx == first));
+        assert_eq!(logged[0].datom.e, format!("block:{}", ids.len() - 1));
+        assert_eq!(logged[ids.len()].tx, second);
+    }
+}
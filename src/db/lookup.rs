@@ -0,0 +1,196 @@
+use crate::db::datom::{Datom, TxOp};
+use crate::db::error::TxError;
+use crate::db::schema::{Schema, ValueType};
+use crate::db::storage::Storage;
+use serde_json::Value;
+
+fn is_unique(schema: &Schema, a: &str) -> bool {
+    schema.get(a).is_some_and(|attr| attr.unique.is_some())
+}
+
+/// as_lookup_ref() reads a value as a lookup ref `[a, v]`: a two item list
+/// whose first item is a unique attribute of the schema.
+fn as_lookup_ref<'a>(schema: &Schema, value: &'a Value) -> Option<(&'a str, &'a Value)> {
+    match value.as_array()?.as_slice() {
+        [Value::String(a), v] if is_unique(schema, a) => Some((a.as_str(), v)),
+        _ => None,
+    }
+}
+
+/// lookup() returns the entity holding the unique value `v` of attribute `a`,
+/// for the op at `index`.
+/// # Errors
+/// Returns a TxError::Schema if `a` is not a unique attribute, a
+/// TxError::Constraint if no entity holds `v` or a TxError::Storage if the
+/// storage fails.
+async fn lookup<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    index: usize,
+    a: &str,
+    v: &Value,
+) -> Result<String, TxError> {
+    if !is_unique(schema, a) {
+        return Err(TxError::Schema {
+            index,
+            message: format!("Lookup ref attribute {} is not unique", a),
+        });
+    }
+    storage
+        .entities_with(a, v)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| TxError::Constraint {
+            index,
+            message: format!("No entity with {} {}", a, v),
+        })
+}
+
+/// resolve_value() resolves the value of a ref attribute if it is a lookup
+/// ref, or a set of them for a cardinality-many attribute.
+async fn resolve_value<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    index: usize,
+    a: &str,
+    v: &Value,
+) -> Result<Value, TxError> {
+    if !schema
+        .get(a)
+        .is_some_and(|attr| attr.value_type == ValueType::Ref)
+    {
+        return Ok(v.clone());
+    }
+    if let Some((la, lv)) = as_lookup_ref(schema, v) {
+        return Ok(Value::from(lookup(storage, schema, index, la, lv).await?));
+    }
+    match v {
+        Value::Array(items) if schema.is_many(a) => {
+            let mut resolved = Vec::with_capacity(items.len());
+            for item in items {
+                resolved.push(match as_lookup_ref(schema, item) {
+                    Some((la, lv)) => Value::from(lookup(storage, schema, index, la, lv).await?),
+                    None => item.clone(),
+                });
+            }
+            Ok(Value::Array(resolved))
+        }
+        v => Ok(v.clone()),
+    }
+}
+
+/// resolve_lookup_refs() replaces the lookup refs of a transaction by the
+/// entities they point at. Lookup refs are found in the entity slot of an op
+/// (`TxOp::Lookup`) and in the values of ref attributes, and resolve against
+/// the state before the transaction.
+///
+/// Returns the ops with entity ids in place of the lookup refs.
+/// # Errors
+/// Returns an error if a lookup ref does not resolve, see lookup().
+pub(crate) async fn resolve_lookup_refs<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    ops: &[TxOp],
+) -> Result<Vec<TxOp>, TxError> {
+    let mut resolved = Vec::with_capacity(ops.len());
+    for (index, op) in ops.iter().enumerate() {
+        let mut op = op.clone();
+        while let TxOp::Lookup { a, v, op: inner } = op {
+            op = inner.with_entity(lookup(storage, schema, index, &a, &v).await?);
+        }
+        resolved.push(match op {
+            TxOp::Datom(datom) => TxOp::Datom(Datom {
+                v: resolve_value(storage, schema, index, &datom.a, &datom.v).await?,
+                ..datom
+            }),
+            TxOp::Cas { e, a, old, new } => TxOp::Cas {
+                old: match old {
+                    Some(old) => Some(resolve_value(storage, schema, index, &a, &old).await?),
+                    None => None,
+                },
+                new: resolve_value(storage, schema, index, &a, &new).await?,
+                e,
+                a,
+            },
+            op => op,
+        });
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datom::{DatomOp, apply_ops_on};
+    use crate::db::{TxMeta, test_conn};
+
+    fn add(e: &str, a: &str, v: Value) -> TxOp {
+        TxOp::Datom(Datom {
+            op: DatomOp::Add,
+            e: e.to_string(),
+            a: a.to_string(),
+            v,
+        })
+    }
+
+    fn page_ref(name: &str) -> Value {
+        serde_json::json!(["page/name", name])
+    }
+
+    #[tokio::test]
+    async fn lookup_refs_resolve_in_entity_slot_and_refs() {
+        let mut conn = test_conn().await;
+        apply_ops_on(
+            &mut conn,
+            &[add("page:1", "page/name", Value::from("2026-01-10"))],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let report = apply_ops_on(
+            &mut conn,
+            &[
+                TxOp::Lookup {
+                    a: "page/name".to_string(),
+                    v: Value::from("2026-01-10"),
+                    op: Box::new(add("", "page/color", Value::from("blue"))),
+                },
+                add("block:1", "block/page", page_ref("2026-01-10")),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let ids: Vec<&str> = report.entities.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["page:1", "block:1"]);
+        assert_eq!(report.entities[0].attrs["page/color"], "blue");
+        assert_eq!(report.entities[1].attrs["block/page"], "page:1");
+    }
+
+    #[tokio::test]
+    async fn lookup_refs_must_resolve() {
+        let mut conn = test_conn().await;
+        let missing = apply_ops_on(
+            &mut conn,
+            &[add("block:1", "block/page", page_ref("2026-01-10"))],
+            &TxMeta::default(),
+        )
+        .await;
+        assert!(missing.unwrap_err().to_string().contains("No entity with"));
+
+        let not_unique = apply_ops_on(
+            &mut conn,
+            &[TxOp::Lookup {
+                a: "block/content".to_string(),
+                v: Value::from("x"),
+                op: Box::new(TxOp::RetractEntity(String::new())),
+            }],
+            &TxMeta::default(),
+        )
+        .await;
+        assert!(not_unique.unwrap_err().to_string().contains("not unique"));
+    }
+}
@@ -0,0 +1,185 @@
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::{Row, SqliteConnection};
+
+use crate::date::now_millis;
+use crate::db::Db;
+
+/// The schema migrations embedded from `./migrations`, applied in version order.
+/// Applied versions are recorded by sqlx in the `_sqlx_migrations` table.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// The state of one embedded migration in the current vault.
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    /// When the migration was applied, or None if it is pending.
+    pub installed_on: Option<String>,
+}
+
+/// run_migrations() applies every pending migration.
+/// Vaults created before migrations existed have no record of what was
+/// applied, so every migration is written to be a no-op on tables that
+/// already exist.
+/// # Errors
+/// Returns an error if a migration fails; the failing migration is rolled back.
+pub(crate) async fn run_migrations(
+    conn: &mut SqliteConnection,
+) -> Result<(), sqlx::migrate::MigrateError> {
+    MIGRATOR.run(conn).await
+}
+
+/// backfilled() tells whether the backfill `name` already ran on this vault.
+pub(crate) async fn backfilled(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("SELECT 1 FROM backfills WHERE name = ?;")
+        .bind(name)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// record_backfill() records that the backfill `name` ran, so it doesn't run
+/// again when the vault is next opened.
+pub(crate) async fn record_backfill(
+    conn: &mut SqliteConnection,
+    name: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO backfills (name, done_at) VALUES (?, ?);")
+        .bind(name)
+        .bind(now_millis())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn migration_status_on(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<MigrationStatus>, sqlx::Error> {
+    conn.ensure_migrations_table()
+        .await
+        .map_err(|err| sqlx::Error::Migrate(Box::new(err)))?;
+    let rows = sqlx::query(
+        "SELECT version, installed_on FROM _sqlx_migrations WHERE success = 1 ORDER BY version;",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let applied: Vec<(i64, String)> = rows
+        .iter()
+        .map(|row| (row.get("version"), row.get("installed_on")))
+        .collect();
+
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            installed_on: applied
+                .iter()
+                .find(|(version, _)| *version == migration.version)
+                .map(|(_, installed_on)| installed_on.clone()),
+        })
+        .collect())
+}
+
+/// migration_status() lists every embedded migration and whether it has been
+/// applied to the vault.
+pub async fn migration_status(db: &Db) -> Result<Vec<MigrationStatus>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    migration_status_on(&mut conn).await
+}
+
+/// migrate() applies every pending migration to the vault.
+/// Returns the schema version after the upgrade.
+pub async fn migrate(db: &Db) -> Result<i64, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    let result = match run_migrations(&mut conn).await {
+        Ok(()) => migration_status_on(&mut conn).await,
+        Err(err) => Err(sqlx::Error::Migrate(Box::new(err))),
+    };
+    Ok(schema_version(&result?))
+}
+
+/// schema_version() returns the highest applied migration version, or 0.
+pub fn schema_version(status: &[MigrationStatus]) -> i64 {
+    status
+        .iter()
+        .filter(|migration| migration.installed_on.is_some())
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::ConnectOptions;
+    use sqlx::sqlite::SqliteConnectOptions;
+
+    use super::*;
+
+    async fn memory_conn() -> SqliteConnection {
+        SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn status_reports_pending_then_applied() {
+        let mut conn = memory_conn().await;
+
+        let before = migration_status_on(&mut conn).await.unwrap();
+        assert!(!before.is_empty());
+        assert!(before.iter().all(|m| m.installed_on.is_none()));
+        assert_eq!(schema_version(&before), 0);
+
+        run_migrations(&mut conn).await.unwrap();
+
+        let after = migration_status_on(&mut conn).await.unwrap();
+        assert!(after.iter().all(|m| m.installed_on.is_some()));
+        assert_eq!(schema_version(&after), after.last().unwrap().version);
+    }
+
+    #[tokio::test]
+    async fn upgrades_a_version_0_1_0_vault() {
+        let mut conn = memory_conn().await;
+        // the schema created by the 0.1.0 release, without any migration record
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS vaults (
+                addr INTEGER PRIMARY KEY,
+                content TEXT,
+                addresses JSON
+            );",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO vaults (addr, content, addresses) VALUES (1, ?, '[]');")
+            .bind(r#"{"id":"page:1","attrs":{"page/name":"2026-01-10"}}"#)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        run_migrations(&mut conn).await.unwrap();
+
+        let kept: i64 = sqlx::query("SELECT COUNT(*) AS n FROM vaults;")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(kept, 1);
+        for table in ["transactions", "tx_datoms", "datoms", "blocks_fts"] {
+            let found = sqlx::query("SELECT name FROM sqlite_master WHERE name = ?;")
+                .bind(table)
+                .fetch_optional(&mut conn)
+                .await
+                .unwrap();
+            assert!(found.is_some(), "missing table {}", table);
+        }
+    }
+}
@@ -1,18 +1,62 @@
+mod check;
 mod datom;
+mod edn;
+mod error;
+mod gc;
+mod index;
+mod log;
+mod lookup;
+mod migrate;
+mod pull;
+mod query;
+mod reencode;
+mod schema;
+mod storage;
+mod tags;
+mod tempid;
 mod transit;
-pub use datom::{Datom, DatomOp, Entity, apply_datoms, list_page_blocks, list_pages, search_blocks};
+pub use check::check_collisions;
+pub use datom::{
+    AsOf, Datom, DatomOp, Entity, TxOp, TxReport, Version, apply_ops, history, list_page_blocks,
+    list_pages, revert_latest, search_blocks, tagged_blocks,
+};
+pub use error::TxError;
+pub use gc::gc;
+pub use index::{HIGHLIGHT_END, HIGHLIGHT_START, SearchHit};
+pub use log::{TxFilter, TxInfo, TxKind, TxMeta, transactions};
+pub use migrate::{migrate, migration_status, schema_version};
+pub use pull::{Pattern, pull};
+pub use query::{Query, query};
+pub use reencode::reencode;
+pub use schema::{Attribute, Cardinality, Unique, ValueType, attributes, declare_attribute};
+pub use storage::MemoryStorage;
+pub use tags::extract_tags;
+pub use transit::Encoding;
 
-use std::str::FromStr;
-use std::{env, fs, path::PathBuf};
+use std::time::Duration;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
 use serde::Deserialize;
-use sqlx::{ConnectOptions, Connection, SqliteConnection, sqlite::SqliteConnectOptions};
+use sqlx::Sqlite;
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
 
 const DB_FILENAME: &str = "db.sqlite";
+/// How long a statement waits for a lock held by another connection (or
+/// another process) before failing with SQLITE_BUSY.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How many more times a write that found the vault busy is tried.
+const BUSY_RETRIES: u32 = 2;
+/// How many connections a Db keeps open.
+const POOL_SIZE: u32 = 4;
 #[derive(Debug, Deserialize)]
 struct DbConfigFile {
     db_path: Option<String>,
     db_dir: Option<String>,
+    encoding: Option<String>,
 }
 
 fn default_db_dir() -> PathBuf {
@@ -55,69 +99,142 @@ fn resolve_db_path() -> PathBuf {
         return PathBuf::from(db_path);
     }
 
-    let config_path = config_file_path();
-    fs::read_to_string(config_path)
-        .ok()
-        .and_then(|c| toml::from_str::<DbConfigFile>(&c).ok())
+    read_db_config()
         .and_then(db_path_from_config)
         .unwrap_or_else(default_db_path)
 }
 
-fn db_url() -> String {
-    let path = resolve_db_path();
-    if let Some(parent) = path.parent() {
-        let _ = fs::create_dir_all(parent);
-    }
-    format!("sqlite://{}", path.to_string_lossy())
+fn read_db_config() -> Option<DbConfigFile> {
+    fs::read_to_string(config_file_path())
+        .ok()
+        .and_then(|c| toml::from_str::<DbConfigFile>(&c).ok())
 }
 
-/// Connect to SQLite database.
-///
-/// # Errors
-/// Returns an error if the connection fails.
-async fn conn() -> sqlx::SqliteConnection {
-    let url = db_url();
-    SqliteConnectOptions::from_str(&url)
-        .expect("Failed to create connection string")
-        .create_if_missing(true)
-        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-        .locking_mode(sqlx::sqlite::SqliteLockingMode::Exclusive)
-        .connect()
-        .await
-        .expect("Failed to connect to database")
+/// vault_encoding() returns the encoding new vault rows are written with,
+/// from `encoding = "json" | "msgpack"` in the config file (msgpack by default).
+pub fn vault_encoding() -> Encoding {
+    read_db_config()
+        .and_then(|cfg| cfg.encoding)
+        .and_then(|name| Encoding::parse(&name))
+        .unwrap_or_default()
+}
+
+/// A handle on a vault: a pool of connections to its SQLite database.
+/// It is opened once, with Db::from_config() or Db::open(), and passed to
+/// every function that reads or writes the vault. Cloning it is cheap and
+/// shares the pool.
+#[derive(Debug, Clone)]
+pub struct Db {
+    pool: SqlitePool,
+}
+
+impl Db {
+    /// from_config() opens the vault at the path set by `JOTTTY_DB_PATH` or
+    /// the config file, see Db::open().
+    /// # Errors
+    /// Returns an error if the database can't be opened or migrated.
+    pub async fn from_config() -> Result<Db, sqlx::Error> {
+        Db::open(&resolve_db_path()).await
+    }
+
+    /// open() opens the vault at `path`, creating it if it doesn't exist.
+    /// Any pending schema migration is applied, missing indexes are built and
+    /// older blocks get their tags, so vaults created by older versions are
+    /// upgraded automatically.
+    /// The vault uses WAL with normal locking, so other processes can open
+    /// it at the same time: readers never wait, and a writer waits up to
+    /// BUSY_TIMEOUT for the one before it.
+    /// # Errors
+    /// Returns an error if the database can't be opened or migrated.
+    pub async fn open(path: &Path) -> Result<Db, sqlx::Error> {
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(POOL_SIZE)
+            .connect_with(options)
+            .await?;
+        let db = Db { pool };
+        let mut conn = db.conn().await?;
+        migrate::run_migrations(&mut conn)
+            .await
+            .map_err(|err| sqlx::Error::Migrate(Box::new(err)))?;
+        index::backfill_indexes(&mut conn).await?;
+        tags::backfill_block_tags(&mut conn).await?;
+        drop(conn);
+        Ok(db)
+    }
+
+    /// conn() takes a connection from the pool. It goes back to the pool
+    /// when dropped.
+    pub(crate) async fn conn(&self) -> Result<PoolConnection<Sqlite>, sqlx::Error> {
+        self.pool.acquire().await
+    }
+
+    /// close() waits for the connections in use and closes the pool.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
 }
 
-async fn create_vaults_table(conn: &mut SqliteConnection) {
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS vaults (
-            addr INTEGER PRIMARY KEY,
-            content TEXT,
-            addresses JSON
-        );",
-    )
-    .execute(conn)
-    .await
-    .expect("Failed to create table");
+/// is_busy() tells whether `err` means another connection held a lock on
+/// the vault for longer than BUSY_TIMEOUT.
+pub fn is_busy(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|err| err.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        // the primary result code is the low byte of the extended one:
+        // SQLITE_BUSY (5) or SQLITE_LOCKED (6)
+        .is_some_and(|code| matches!(code & 0xff, 5 | 6))
 }
 
-async fn ensure_schema(conn: &mut SqliteConnection) {
-    create_vaults_table(conn).await;
+/// retry_busy() runs `write` and runs it again, after a short pause, while
+/// it fails with an error `busy` accepts, up to BUSY_RETRIES more times.
+/// `write` must leave the vault unchanged when it fails, as a rolled back
+/// transaction does.
+pub(crate) async fn retry_busy<T, E, F, Fut>(busy: fn(&E) -> bool, mut write: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut retries = 0;
+    loop {
+        match write().await {
+            Err(err) if busy(&err) && retries < BUSY_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(Duration::from_millis(100 * u64::from(retries))).await;
+            }
+            result => return result,
+        }
+    }
 }
 
-/// Initialize the database.
-///
-/// This function gets an connection ensure the database schema is set up.
-/// This function is called on cli startup.
-pub async fn init_db() {
-    let mut conn = conn().await;
-    ensure_schema(&mut conn).await;
-    let _ = conn.close().await;
+/// Opens an in-memory database with the schema in place, for tests that
+/// should not touch `JOTTTY_DB_PATH`.
+#[cfg(test)]
+pub(crate) async fn test_conn() -> sqlx::SqliteConnection {
+    use sqlx::ConnectOptions;
+    use std::str::FromStr;
+
+    let mut conn = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    migrate::run_migrations(&mut conn).await.unwrap();
+    conn
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sqlx::Row;
+    use sqlx::{ConnectOptions, Connection, Row};
+    use std::str::FromStr;
 
     //TODO@chico: reuse this function
     fn unique_test_db_path(test_name: &str) -> std::path::PathBuf {
@@ -132,14 +249,125 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn init_db_creates_db_file() {
-        let db_path = unique_test_db_path("apply_datoms");
-        unsafe {
-            std::env::set_var("JOTTTY_DB_PATH", db_path.to_string_lossy().to_string());
-        }
-        init_db().await;
+    async fn open_creates_db_file() {
+        let db_path = unique_test_db_path("open");
+        let db = Db::open(&db_path).await.unwrap();
 
         assert!(db_path.exists());
+
+        let datom = Datom {
+            op: DatomOp::Add,
+            e: "page:journal".to_string(),
+            a: "page/name".to_string(),
+            v: serde_json::json!("Journal"),
+        };
+        apply_ops(&db, &[TxOp::Datom(datom)], &TxMeta::default())
+            .await
+            .unwrap();
+        assert_eq!(list_pages(&db, None).await.unwrap(), vec!["Journal"]);
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn open_backfills_tags_of_older_blocks() {
+        let db_path = unique_test_db_path("tags");
+        // a vault written before tags were extracted: no index, no block/tags
+        let mut conn = SqliteConnectOptions::new()
+            .filename(&db_path)
+            .create_if_missing(true)
+            .connect()
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE vaults (addr INTEGER PRIMARY KEY, content TEXT, addresses JSON);",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO vaults (addr, content, addresses) VALUES (1, ?, '[]'), (2, ?, '[]');",
+        )
+        .bind(r#"{"id":"page:1","attrs":{"page/name":"2026-01-10"}}"#)
+        .bind(
+            r#"{"id":"block:1","attrs":{"block/content":"TODO: fix #rust","block/page":"page:1"}}"#,
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        conn.close().await.unwrap();
+
+        let db = Db::open(&db_path).await.unwrap();
+        let blocks = tagged_blocks(&db, "rust", None).await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].id, "block:1");
+        assert_eq!(tagged_blocks(&db, "TODO", None).await.unwrap().len(), 1);
+        db.close().await;
+
+        // the backfill runs once
+        let db = Db::open(&db_path).await.unwrap();
+        assert_eq!(tagged_blocks(&db, "rust", None).await.unwrap().len(), 1);
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_undos_revert_different_txs() {
+        let db_path = unique_test_db_path("undo");
+        let first = Db::open(&db_path).await.unwrap();
+        let second = Db::open(&db_path).await.unwrap();
+        for content in ["a", "b"] {
+            let datom = Datom {
+                op: DatomOp::Add,
+                e: format!("block:{}", content),
+                a: "block/content".to_string(),
+                v: serde_json::json!(content),
+            };
+            apply_ops(&first, &[TxOp::Datom(datom)], &TxMeta::default())
+                .await
+                .unwrap();
+        }
+
+        let meta = TxMeta::default();
+        let (a, b) = tokio::join!(
+            revert_latest(&first, TxKind::Undo, &meta),
+            revert_latest(&second, TxKind::Undo, &meta)
+        );
+        let mut reverted = vec![a.unwrap().unwrap().0, b.unwrap().unwrap().0];
+        reverted.sort();
+        assert_eq!(reverted, vec![1, 2]);
+        let nothing = revert_latest(&first, TxKind::Undo, &meta).await.unwrap();
+        assert!(nothing.is_none());
+        first.close().await;
+        second.close().await;
+    }
+
+    #[tokio::test]
+    async fn writers_wait_for_each_other() {
+        let db_path = unique_test_db_path("writers");
+        let first = Db::open(&db_path).await.unwrap();
+        // a second handle stands in for another process on the same vault
+        let second = Db::open(&db_path).await.unwrap();
+
+        let mut conn = first.conn().await.unwrap();
+        // holds the write lock until it commits
+        let sql_tx = conn.begin_with("BEGIN IMMEDIATE").await.unwrap();
+
+        let writer = tokio::spawn(async move {
+            let datom = Datom {
+                op: DatomOp::Add,
+                e: "page:journal".to_string(),
+                a: "page/name".to_string(),
+                v: serde_json::json!("Journal"),
+            };
+            apply_ops(&second, &[TxOp::Datom(datom)], &TxMeta::default()).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!writer.is_finished());
+        sql_tx.commit().await.unwrap();
+        drop(conn);
+
+        assert_eq!(writer.await.unwrap().unwrap().tx, 1);
+        assert_eq!(list_pages(&first, None).await.unwrap(), vec!["Journal"]);
+        first.close().await;
     }
 
     #[tokio::test]
@@ -151,7 +379,7 @@ mod tests {
             .await
             .unwrap();
 
-        ensure_schema(&mut conn).await;
+        migrate::run_migrations(&mut conn).await.unwrap();
 
         let row =
             sqlx::query("SELECT name FROM sqlite_master WHERE type='table' AND name='vaults';")
@@ -165,4 +393,27 @@ mod tests {
 
         assert!(row.is_some());
     }
+
+    #[tokio::test]
+    async fn db_creates_tx_log_tables() {
+        let mut conn = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        migrate::run_migrations(&mut conn).await.unwrap();
+
+        let names: Vec<String> = sqlx::query(
+            "SELECT name FROM sqlite_master WHERE type='table' AND name IN ('transactions', 'tx_datoms') ORDER BY name;",
+        )
+        .fetch_all(&mut conn)
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| r.get::<String, _>("name"))
+        .collect();
+
+        assert_eq!(names, vec!["transactions", "tx_datoms"]);
+    }
 }
@@ -0,0 +1,336 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use serde_json::{Map, Value};
+
+use crate::db::Db;
+use crate::db::edn::{self, Edn};
+use crate::db::schema::{Schema, ValueType};
+use crate::db::storage::Storage;
+
+/// A pull pattern: which attributes of an entity to read, and how to follow
+/// its references. It is written in the EDN syntax Datomic uses:
+/// `[* {block/page [page/name]}]` reads every attribute of an entity and the
+/// name of its page, `[block/content {block/_page [*]}]` reads the content of
+/// an entity and every block whose `block/page` refers to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern(Vec<AttrSpec>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttrSpec {
+    /// `*`: every attribute of the entity, refs as `{"db/id": ...}`.
+    Wildcard,
+    /// An attribute, or with `reverse` the entities that refer to this one
+    /// through it (`block/_page`), followed with `pattern` if there is one.
+    Attr {
+        name: String,
+        reverse: bool,
+        pattern: Option<Pattern>,
+    },
+}
+
+/// attr_spec() reads an attribute name, a keyword or a symbol. A name
+/// starting with '_' (`block/_page`) is a reverse reference.
+fn attr_spec(form: &Edn, pattern: Option<Pattern>) -> Result<AttrSpec, String> {
+    let Some((ns, attr)) = form.name().and_then(|name| name.split_once('/')) else {
+        return Err(format!("Invalid attribute in pull pattern: {}", form));
+    };
+    let (attr, reverse) = match attr.strip_prefix('_') {
+        Some(attr) => (attr, true),
+        None => (attr, false),
+    };
+    Ok(AttrSpec::Attr {
+        name: format!("{}/{}", ns, attr),
+        reverse,
+        pattern,
+    })
+}
+
+impl Pattern {
+    /// parse() reads a pull pattern, see Pattern.
+    /// # Errors
+    /// Returns a message if the pattern is not a vector of attribute specs.
+    pub fn parse(input: &str) -> Result<Pattern, String> {
+        Pattern::from_edn(&edn::parse(input)?)
+    }
+
+    fn from_edn(form: &Edn) -> Result<Pattern, String> {
+        let Edn::Vector(items) = form else {
+            return Err(format!(
+                "A pull pattern must be a vector like [*], not {}",
+                form
+            ));
+        };
+        let mut specs = Vec::new();
+        for item in items {
+            match item {
+                Edn::Symbol(symbol) if symbol == "*" => specs.push(AttrSpec::Wildcard),
+                Edn::Map(entries) => {
+                    for (attr, pattern) in entries {
+                        specs.push(attr_spec(attr, Some(Pattern::from_edn(pattern)?))?);
+                    }
+                }
+                item => specs.push(attr_spec(item, None)?),
+            }
+        }
+        Ok(Pattern(specs))
+    }
+}
+
+type PullFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<Value>, sqlx::Error>> + Send + 'a>>;
+
+fn id_map(id: &str) -> Value {
+    let mut map = Map::new();
+    map.insert("db/id".to_string(), Value::from(id));
+    Value::Object(map)
+}
+
+/// follow() pulls `pattern` for each id in `ids`, skipping the ids that hold
+/// no entity.
+async fn follow<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    pattern: &Pattern,
+    ids: &[String],
+) -> Result<Vec<Value>, sqlx::Error> {
+    let mut pulled = Vec::with_capacity(ids.len());
+    for id in ids {
+        if let Some(value) = pull_entity(storage, schema, pattern, id).await? {
+            pulled.push(value);
+        }
+    }
+    Ok(pulled)
+}
+
+/// pull_entity() reads `pattern` from one entity; it is boxed since nested
+/// patterns make it recursive.
+fn pull_entity<'a, S: Storage>(
+    storage: &'a mut S,
+    schema: &'a Schema,
+    pattern: &'a Pattern,
+    id: &'a str,
+) -> PullFuture<'a> {
+    Box::pin(async move {
+        let entity = storage.load_entity(id).await?;
+        if entity.attrs.is_empty() {
+            return Ok(None);
+        }
+        let is_ref = |a: &str| {
+            schema
+                .get(a)
+                .is_some_and(|attr| attr.value_type == ValueType::Ref)
+        };
+        let ref_ids = |v: &Value| -> Vec<String> {
+            match v {
+                Value::Array(items) => items
+                    .iter()
+                    .filter_map(|item| item.as_str().map(str::to_string))
+                    .collect(),
+                v => v.as_str().map(str::to_string).into_iter().collect(),
+            }
+        };
+
+        let mut out = Map::new();
+        out.insert("db/id".to_string(), Value::from(id));
+        for spec in &pattern.0 {
+            match spec {
+                AttrSpec::Wildcard => {
+                    for (a, v) in &entity.attrs {
+                        if out.contains_key(a) {
+                            continue;
+                        }
+                        let v = match v {
+                            Value::String(id) if is_ref(a) => id_map(id),
+                            Value::Array(ids) if is_ref(a) => Value::Array(
+                                ids.iter().filter_map(Value::as_str).map(id_map).collect(),
+                            ),
+                            v => v.clone(),
+                        };
+                        out.insert(a.clone(), v);
+                    }
+                }
+                AttrSpec::Attr {
+                    name,
+                    reverse: false,
+                    pattern,
+                } => {
+                    let Some(v) = entity.attrs.get(name) else {
+                        continue;
+                    };
+                    let v = match pattern {
+                        Some(pattern) if is_ref(name) => {
+                            let pulled = follow(storage, schema, pattern, &ref_ids(v)).await?;
+                            if schema.is_many(name) {
+                                Value::Array(pulled)
+                            } else {
+                                pulled.into_iter().next().unwrap_or(Value::Null)
+                            }
+                        }
+                        _ => v.clone(),
+                    };
+                    out.insert(name.clone(), v);
+                }
+                AttrSpec::Attr {
+                    name,
+                    reverse: true,
+                    pattern,
+                } => {
+                    let referrers = storage.entities_with(name, &Value::from(id)).await?;
+                    if referrers.is_empty() {
+                        continue;
+                    }
+                    let (ns, attr) = name.split_once('/').unwrap_or(("", name));
+                    let v = match pattern {
+                        Some(pattern) => {
+                            Value::Array(follow(storage, schema, pattern, &referrers).await?)
+                        }
+                        None => Value::Array(referrers.iter().map(|id| id_map(id)).collect()),
+                    };
+                    out.insert(format!("{}/_{}", ns, attr), v);
+                }
+            }
+        }
+        Ok(Some(Value::Object(out)))
+    })
+}
+
+pub(crate) async fn pull_on<S: Storage>(
+    storage: &mut S,
+    pattern: &Pattern,
+    eid: &Value,
+) -> Result<Option<Value>, sqlx::Error> {
+    let entity_id = match eid {
+        Value::String(id) => id.clone(),
+        // a lookup ref [a, v]
+        Value::Array(lookup) => match lookup.as_slice() {
+            [Value::String(a), v] => {
+                let a = a.trim_start_matches(':');
+                match storage.entities_with(a, v).await?.into_iter().next() {
+                    Some(id) => id,
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let schema = storage.load_schema().await?;
+    pull_entity(storage, &schema, pattern, &entity_id).await
+}
+
+/// pull() reads the entity `eid` as a nested JSON object shaped by `pattern`:
+/// the attributes it names (every attribute for `*`), with refs followed into
+/// their own patterns and reverse refs listed under `ns/_attr`.
+/// Every object carries its entity id as `db/id`.
+/// `eid` is an entity id or a lookup ref `[a, v]`.
+/// Returns None if there is no such entity.
+/// # Errors
+/// Returns an error if the SQL query fails.
+pub async fn pull(db: &Db, pattern: &Pattern, eid: &Value) -> Result<Option<Value>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    pull_on(&mut *conn, pattern, eid).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datom::{Datom, DatomOp, apply_datoms_on};
+    use crate::db::{TxMeta, test_conn};
+    use serde_json::json;
+
+    fn add(e: &str, a: &str, v: Value) -> Datom {
+        Datom {
+            op: DatomOp::Add,
+            e: e.to_string(),
+            a: a.to_string(),
+            v,
+        }
+    }
+
+    #[test]
+    fn patterns_parse_like_edn() {
+        let pattern = Pattern::parse("[* {:block/page [page/name]}, {block/_page [*]}]").unwrap();
+        assert_eq!(pattern.0.len(), 3);
+        assert_eq!(pattern.0[0], AttrSpec::Wildcard);
+        assert_eq!(
+            pattern.0[2],
+            AttrSpec::Attr {
+                name: "block/page".to_string(),
+                reverse: true,
+                pattern: Some(Pattern(vec![AttrSpec::Wildcard])),
+            }
+        );
+
+        for bad in [
+            "*",
+            "[block/content",
+            "[content]",
+            "[{block/page}]",
+            "[*] [*]",
+        ] {
+            assert!(Pattern::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn pull_follows_refs_and_reverse_refs() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                add("page:1", "page/name", json!("2026-01-10")),
+                add("block:1", "block/content", json!("first")),
+                add("block:1", "block/page", json!("page:1")),
+                add("block:1", "block/tags", json!(["idea", "work"])),
+                add("block:2", "block/content", json!("second")),
+                add("block:2", "block/page", json!("page:1")),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let pattern = Pattern::parse("[* {block/page [page/name]}]").unwrap();
+        let block = pull_on(&mut conn, &pattern, &json!("block:1"))
+            .await
+            .unwrap();
+        assert_eq!(
+            block,
+            Some(json!({
+                "db/id": "block:1",
+                "block/content": "first",
+                "block/page": {"db/id": "page:1", "page/name": "2026-01-10"},
+                "block/tags": ["idea", "work"],
+            }))
+        );
+
+        let pattern = Pattern::parse("[page/name {block/_page [block/content]}]").unwrap();
+        let page = pull_on(&mut conn, &pattern, &json!(["page/name", "2026-01-10"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            page,
+            Some(json!({
+                "db/id": "page:1",
+                "page/name": "2026-01-10",
+                "block/_page": [
+                    {"db/id": "block:1", "block/content": "first"},
+                    {"db/id": "block:2", "block/content": "second"},
+                ],
+            }))
+        );
+
+        let pattern = Pattern::parse("[*]").unwrap();
+        let refs = pull_on(&mut conn, &pattern, &json!("block:2"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(refs["block/page"], json!({"db/id": "page:1"}));
+        assert_eq!(
+            pull_on(&mut conn, &pattern, &json!("block:9"))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,850 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::db::Db;
+use crate::db::edn::{self, Edn};
+use crate::db::storage::Storage;
+
+/// A term of a clause: a variable (`?b`), the blank `_` or a constant.
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Var(String),
+    Blank,
+    Const(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Clause {
+    /// `[e a v]`: a datom of the vault.
+    Pattern([Term; 3]),
+    /// `[(op args...)]`, like `[(> ?n 3)]` or `[(starts-with? ?c "TODO")]`.
+    Predicate { op: String, args: Vec<Term> },
+    /// `(name args...)`: a call to a rule.
+    Rule { name: String, args: Vec<Term> },
+}
+
+/// A rule `[(name ?a ?b) clauses...]`. Several rules with the same name are
+/// alternatives: a call matches if any of them does.
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Clause>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aggregate {
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Distinct,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FindElem {
+    Var(String),
+    Aggregate(Aggregate, String),
+}
+
+/// A Datalog query over the datoms of the vault, DataScript style:
+///
+/// ```text
+/// {:find [?c]
+///  :where [[?b :block/page ?p] [?p :page/name "2026-01-10"] [?b :block/content ?c]]}
+/// ```
+///
+/// `:where` clauses are data patterns `[e a v]`, predicates `[(op args...)]`
+/// and rule calls `(rule args...)`, joined on their variables. `:rules` holds
+/// the rule definitions, `:find` may aggregate (`(count ?b)`), grouping by the
+/// other variables it finds, and `:with` keeps variables in the set aggregated
+/// over without returning them.
+#[derive(Debug, Clone)]
+pub struct Query {
+    find: Vec<FindElem>,
+    with: Vec<String>,
+    clauses: Vec<Clause>,
+    rules: Vec<Rule>,
+}
+
+const PREDICATES: &[&str] = &[
+    "=",
+    "!=",
+    "not=",
+    "<",
+    ">",
+    "<=",
+    ">=",
+    "starts-with?",
+    "ends-with?",
+    "includes?",
+];
+
+fn term(form: &Edn) -> Term {
+    match form {
+        Edn::Symbol(s) if s == "_" => Term::Blank,
+        Edn::Symbol(s) if s.starts_with('?') => Term::Var(s.clone()),
+        form => Term::Const(form.to_json()),
+    }
+}
+
+fn var(form: &Edn, what: &str) -> Result<String, String> {
+    match form {
+        Edn::Symbol(s) if s.starts_with('?') => Ok(s.clone()),
+        form => Err(format!("Expected a variable in {}, got {}", what, form)),
+    }
+}
+
+/// clause() reads a `:where` clause. `rule_names` tells rule calls written as
+/// vectors (as JSON queries must) from data patterns.
+fn clause(form: &Edn, rule_names: &HashSet<String>) -> Result<Clause, String> {
+    let items = form
+        .items()
+        .ok_or_else(|| format!("A clause must be a list or a vector, got {}", form))?;
+    match items {
+        [expr] if expr.items().is_some() => {
+            let expr = expr.items().unwrap_or_default();
+            let Some((op, args)) = expr.split_first() else {
+                return Err("Empty predicate".to_string());
+            };
+            let op = symbol_name(op).ok_or_else(|| format!("Invalid predicate {}", form))?;
+            // clojure.string/starts-with? and starts-with? are the same
+            let op = op.rsplit_once('/').map_or(op, |(_, name)| name);
+            if !PREDICATES.contains(&op) {
+                return Err(format!("Unknown predicate {}", op));
+            }
+            Ok(Clause::Predicate {
+                op: op.to_string(),
+                args: args.iter().map(term).collect(),
+            })
+        }
+        [call, _] if call.items().is_some() => {
+            Err(format!("Function bindings are not supported: {}", form))
+        }
+        [Edn::Symbol(name), args @ ..] if !name.starts_with('?') && name != "_" => {
+            Ok(Clause::Rule {
+                name: name.clone(),
+                args: args.iter().map(term).collect(),
+            })
+        }
+        [Edn::String(name), args @ ..] if rule_names.contains(name) => Ok(Clause::Rule {
+            name: name.clone(),
+            args: args.iter().map(term).collect(),
+        }),
+        [e, a] => Ok(Clause::Pattern([term(e), term(a), Term::Blank])),
+        [e, a, v] => Ok(Clause::Pattern([term(e), term(a), term(v)])),
+        [e, a, v, Edn::Symbol(tx)] if tx == "_" => Ok(Clause::Pattern([term(e), term(a), term(v)])),
+        _ => Err(format!("Invalid clause {}", form)),
+    }
+}
+
+fn find_elem(form: &Edn) -> Result<FindElem, String> {
+    let Some(items) = form.items() else {
+        return Ok(FindElem::Var(var(form, ":find")?));
+    };
+    let [fun, arg] = items else {
+        return Err(format!("Invalid aggregate {}", form));
+    };
+    let fun = match symbol_name(fun) {
+        Some("count") => Aggregate::Count,
+        Some("count-distinct") => Aggregate::CountDistinct,
+        Some("sum") => Aggregate::Sum,
+        Some("avg") => Aggregate::Avg,
+        Some("min") => Aggregate::Min,
+        Some("max") => Aggregate::Max,
+        Some("distinct") => Aggregate::Distinct,
+        _ => return Err(format!("Unknown aggregate {}", fun)),
+    };
+    Ok(FindElem::Aggregate(fun, var(arg, ":find")?))
+}
+
+/// symbol_name() returns the name of a symbol, or of a string standing for
+/// one in a JSON query.
+fn symbol_name(form: &Edn) -> Option<&str> {
+    match form {
+        Edn::Symbol(name) | Edn::String(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// rule_head() reads the head of a rule, `[(name ?a ?b) clauses...]`, and
+/// returns its name, its parameters and the forms of its body.
+fn rule_head(form: &Edn) -> Result<(String, Vec<String>, &[Edn]), String> {
+    let Some((head, body)) = form.items().and_then(<[Edn]>::split_first) else {
+        return Err(format!("Invalid rule {}", form));
+    };
+    let Some((name, params)) = head.items().and_then(<[Edn]>::split_first) else {
+        return Err(format!("Invalid rule head in {}", form));
+    };
+    let name = symbol_name(name).ok_or_else(|| format!("Invalid rule name {}", name))?;
+    let params = params
+        .iter()
+        .map(|param| var(param, "a rule head"))
+        .collect::<Result<_, _>>()?;
+    Ok((name.to_string(), params, body))
+}
+
+/// sections() splits a query in vector form, `[:find ?c :where ...]`, into
+/// its keyword sections, as the map form has them.
+fn sections(form: &Edn) -> Result<Vec<(String, Vec<Edn>)>, String> {
+    match form {
+        Edn::Map(entries) => entries
+            .iter()
+            .map(|(k, v)| {
+                let k = k.name().ok_or_else(|| format!("Invalid query key {}", k))?;
+                let v = match v.items() {
+                    Some(items) => items.to_vec(),
+                    None => vec![v.clone()],
+                };
+                Ok((k.to_string(), v))
+            })
+            .collect(),
+        Edn::Vector(items) => {
+            let mut sections: Vec<(String, Vec<Edn>)> = Vec::new();
+            for item in items {
+                match (item, sections.last_mut()) {
+                    (Edn::Keyword(k), _) => sections.push((k.clone(), Vec::new())),
+                    (item, Some((_, forms))) => forms.push(item.clone()),
+                    (item, None) => return Err(format!("Unexpected {} before :find", item)),
+                }
+            }
+            Ok(sections)
+        }
+        form => Err(format!("A query must be a map or a vector, got {}", form)),
+    }
+}
+
+/// bind_check() fails if a predicate or a rule head uses a variable no clause
+/// before it binds, given the variables `bound` on entry.
+fn bind_check(clauses: &[Clause], bound: &mut HashSet<String>) -> Result<(), String> {
+    for clause in clauses {
+        match clause {
+            Clause::Pattern(terms) => {
+                for term in terms {
+                    if let Term::Var(v) = term {
+                        bound.insert(v.clone());
+                    }
+                }
+            }
+            Clause::Rule { args, .. } => {
+                for arg in args {
+                    if let Term::Var(v) = arg {
+                        bound.insert(v.clone());
+                    }
+                }
+            }
+            Clause::Predicate { op, args } => {
+                for arg in args {
+                    if let Term::Var(v) = arg
+                        && !bound.contains(v)
+                    {
+                        return Err(format!("Variable {} of ({} ...) is not bound", v, op));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Query {
+    /// parse() reads a query written in EDN or in JSON. In JSON, variables
+    /// are strings starting with '?', and keys and keywords may drop the ':':
+    /// `{"find": ["?c"], "where": [["?b", "block/content", "?c"]]}`.
+    /// # Errors
+    /// Returns a message if the query is malformed, uses an unknown predicate
+    /// or aggregate, or uses a variable in a predicate before binding it.
+    pub fn parse(input: &str) -> Result<Query, String> {
+        let trimmed = input.trim_start();
+        if trimmed.starts_with('{')
+            && let Ok(json) = serde_json::from_str::<Value>(trimmed)
+        {
+            return Query::from_json(&json);
+        }
+        Query::from_edn(&edn::parse(input)?)
+    }
+
+    /// from_json() reads a query in JSON, see parse().
+    pub fn from_json(json: &Value) -> Result<Query, String> {
+        Query::from_edn(&Edn::from_json(json))
+    }
+
+    fn from_edn(form: &Edn) -> Result<Query, String> {
+        let mut find = Vec::new();
+        let mut with = Vec::new();
+        let mut wheres = Vec::new();
+        let mut rule_forms = Vec::new();
+        for (key, forms) in sections(form)? {
+            match key.as_str() {
+                "find" => {
+                    for form in &forms {
+                        find.push(find_elem(form)?);
+                    }
+                }
+                "with" => {
+                    for form in &forms {
+                        with.push(var(form, ":with")?);
+                    }
+                }
+                "where" => wheres = forms,
+                "rules" => rule_forms = forms,
+                // the database and the rules are the only inputs
+                "in" => {
+                    for form in &forms {
+                        if !matches!(form, Edn::Symbol(s) if s == "$" || s == "%") {
+                            return Err(format!("Unsupported :in binding {}", form));
+                        }
+                    }
+                }
+                key => return Err(format!("Unknown query key :{}", key)),
+            }
+        }
+        if find.is_empty() {
+            return Err("A query needs a :find".to_string());
+        }
+
+        let heads = rule_forms
+            .iter()
+            .map(rule_head)
+            .collect::<Result<Vec<_>, _>>()?;
+        let rule_names: HashSet<String> = heads.iter().map(|(name, ..)| name.clone()).collect();
+        let mut rules = Vec::with_capacity(heads.len());
+        for (name, params, body) in heads {
+            let body: Vec<Clause> = body
+                .iter()
+                .map(|form| clause(form, &rule_names))
+                .collect::<Result<_, _>>()?;
+            let mut bound = HashSet::new();
+            bind_check(&body, &mut bound)?;
+            if let Some(param) = params.iter().find(|p| !bound.contains(*p)) {
+                return Err(format!("Variable {} of rule {} is not bound", param, name));
+            }
+            rules.push(Rule { name, params, body });
+        }
+        let clauses: Vec<Clause> = wheres
+            .iter()
+            .map(|form| clause(form, &rule_names))
+            .collect::<Result<_, _>>()?;
+        for clause in &clauses {
+            if let Clause::Rule { name, .. } = clause
+                && !rule_names.contains(name)
+            {
+                return Err(format!("Unknown rule {}", name));
+            }
+        }
+        let mut bound = HashSet::new();
+        bind_check(&clauses, &mut bound)?;
+        for v in find
+            .iter()
+            .map(|elem| match elem {
+                FindElem::Var(v) | FindElem::Aggregate(_, v) => v,
+            })
+            .chain(&with)
+        {
+            if !bound.contains(v) {
+                return Err(format!("Variable {} is not bound by :where", v));
+            }
+        }
+
+        Ok(Query {
+            find,
+            with,
+            clauses,
+            rules,
+        })
+    }
+}
+
+type Binding = HashMap<String, Value>;
+
+/// compare_values() orders values for predicates, min/max and the order of
+/// results: numbers by value, strings alphabetically, and values of
+/// different types by type.
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(v: &Value) -> u8 {
+        match v {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x
+            .as_f64()
+            .partial_cmp(&y.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        _ => rank(a)
+            .cmp(&rank(b))
+            .then_with(|| a.to_string().cmp(&b.to_string())),
+    }
+}
+
+fn resolve<'a>(term: &'a Term, binding: &'a Binding) -> Option<&'a Value> {
+    match term {
+        Term::Var(v) => binding.get(v),
+        Term::Const(c) => Some(c),
+        Term::Blank => None,
+    }
+}
+
+fn predicate(op: &str, args: &[Value]) -> bool {
+    let same_type = |a: &Value, b: &Value| {
+        matches!(
+            (a, b),
+            (Value::Number(_), Value::Number(_)) | (Value::String(_), Value::String(_))
+        )
+    };
+    let compare = |want: fn(Ordering) -> bool| {
+        args.windows(2)
+            .all(|w| same_type(&w[0], &w[1]) && want(compare_values(&w[0], &w[1])))
+    };
+    let strings = |test: fn(&str, &str) -> bool| match args {
+        [Value::String(s), Value::String(sub)] => test(s, sub),
+        _ => false,
+    };
+    match op {
+        "=" => args.windows(2).all(|w| w[0] == w[1]),
+        "!=" | "not=" => args.windows(2).any(|w| w[0] != w[1]),
+        "<" => compare(Ordering::is_lt),
+        ">" => compare(Ordering::is_gt),
+        "<=" => compare(Ordering::is_le),
+        ">=" => compare(Ordering::is_ge),
+        "starts-with?" => strings(|s, sub| s.starts_with(sub)),
+        "ends-with?" => strings(|s, sub| s.ends_with(sub)),
+        "includes?" => strings(|s, sub| s.contains(sub)),
+        _ => false,
+    }
+}
+
+/// unify() extends `binding` with the values of `terms`, or returns None if
+/// a bound variable or a constant disagrees with a value.
+fn unify(binding: &Binding, terms: &[Term], values: &[&Value]) -> Option<Binding> {
+    let mut out = binding.clone();
+    for (term, value) in terms.iter().zip(values) {
+        match term {
+            Term::Blank => {}
+            Term::Const(c) => {
+                if c != *value {
+                    return None;
+                }
+            }
+            Term::Var(v) => match out.get(v) {
+                Some(bound) if bound != *value => return None,
+                Some(_) => {}
+                None => {
+                    out.insert(v.clone(), (*value).clone());
+                }
+            },
+        }
+    }
+    Some(out)
+}
+
+/// The derived facts of each rule: the tuples of values of its parameters.
+type Derived = HashMap<String, Vec<Vec<Value>>>;
+
+/// eval_clauses() joins the clauses in order, starting from `bindings`.
+/// Data patterns read the index of the storage, once for every distinct set of
+/// bound values.
+async fn eval_clauses<S: Storage>(
+    storage: &mut S,
+    derived: &Derived,
+    clauses: &[Clause],
+    mut bindings: Vec<Binding>,
+) -> Result<Vec<Binding>, sqlx::Error> {
+    for clause in clauses {
+        let mut next = Vec::new();
+        match clause {
+            Clause::Pattern(terms) => {
+                let mut reads: HashMap<String, Vec<(String, String, Value)>> = HashMap::new();
+                // entities and attributes are strings: any other value matches nothing
+                let id = |value: Option<&Value>| match value {
+                    None => Some(None),
+                    Some(Value::String(s)) => Some(Some(s.clone())),
+                    Some(_) => None,
+                };
+                for binding in &bindings {
+                    let (Some(e), Some(a)) = (
+                        id(resolve(&terms[0], binding)),
+                        id(resolve(&terms[1], binding)),
+                    ) else {
+                        continue;
+                    };
+                    let v = resolve(&terms[2], binding);
+                    let key = serde_json::to_string(&(&e, &a, v))
+                        .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+                    if !reads.contains_key(&key) {
+                        let rows = storage.match_datoms(e.as_deref(), a.as_deref(), v).await?;
+                        reads.insert(key.clone(), rows);
+                    }
+                    for (e, a, v) in &reads[&key] {
+                        let (e, a) = (Value::from(e.as_str()), Value::from(a.as_str()));
+                        if let Some(b) = unify(binding, terms, &[&e, &a, v]) {
+                            next.push(b);
+                        }
+                    }
+                }
+            }
+            Clause::Predicate { op, args } => {
+                for binding in bindings {
+                    let values: Option<Vec<Value>> = args
+                        .iter()
+                        .map(|arg| resolve(arg, &binding).cloned())
+                        .collect();
+                    if values.is_some_and(|values| predicate(op, &values)) {
+                        next.push(binding);
+                    }
+                }
+            }
+            Clause::Rule { name, args } => {
+                let facts = derived.get(name).map(Vec::as_slice).unwrap_or_default();
+                for binding in &bindings {
+                    for fact in facts {
+                        let values: Vec<&Value> = fact.iter().collect();
+                        if let Some(b) = unify(binding, args, &values) {
+                            next.push(b);
+                        }
+                    }
+                }
+            }
+        }
+        bindings = next;
+    }
+    Ok(bindings)
+}
+
+/// derive_rules() computes the facts of every rule bottom-up: each round
+/// evaluates every rule body against the facts derived so far, until a round
+/// derives nothing new. Recursive rules (like an ancestor rule) reach their
+/// fixpoint this way.
+async fn derive_rules<S: Storage>(storage: &mut S, rules: &[Rule]) -> Result<Derived, sqlx::Error> {
+    let mut derived = Derived::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    loop {
+        let mut new_facts = Vec::new();
+        for rule in rules {
+            for binding in eval_clauses(storage, &derived, &rule.body, vec![Binding::new()]).await?
+            {
+                let fact: Vec<Value> = rule.params.iter().map(|p| binding[p].clone()).collect();
+                let key = serde_json::to_string(&(&rule.name, &fact))
+                    .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+                if seen.insert(key) {
+                    new_facts.push((rule.name.clone(), fact));
+                }
+            }
+        }
+        if new_facts.is_empty() {
+            return Ok(derived);
+        }
+        for (name, fact) in new_facts {
+            derived.entry(name).or_default().push(fact);
+        }
+    }
+}
+
+fn aggregate(fun: Aggregate, values: &[&Value]) -> Value {
+    let numbers = || values.iter().filter_map(|v| v.as_f64());
+    let distinct = || {
+        let mut distinct: Vec<&Value> = Vec::new();
+        for v in values {
+            if !distinct.contains(v) {
+                distinct.push(v);
+            }
+        }
+        distinct
+    };
+    match fun {
+        Aggregate::Count => Value::from(values.len()),
+        Aggregate::CountDistinct => Value::from(distinct().len()),
+        Aggregate::Sum if values.iter().all(|v| v.is_i64()) => {
+            Value::from(values.iter().filter_map(|v| v.as_i64()).sum::<i64>())
+        }
+        Aggregate::Sum => Value::from(numbers().sum::<f64>()),
+        Aggregate::Avg => match numbers().count() {
+            0 => Value::Null,
+            n => Value::from(numbers().sum::<f64>() / n as f64),
+        },
+        Aggregate::Min => values
+            .iter()
+            .min_by(|a, b| compare_values(a, b))
+            .map_or(Value::Null, |v| (*v).clone()),
+        Aggregate::Max => values
+            .iter()
+            .max_by(|a, b| compare_values(a, b))
+            .map_or(Value::Null, |v| (*v).clone()),
+        Aggregate::Distinct => {
+            let mut distinct: Vec<Value> = distinct().into_iter().cloned().collect();
+            distinct.sort_by(compare_values);
+            Value::Array(distinct)
+        }
+    }
+}
+
+fn compare_rows(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| compare_values(x, y))
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// find_rows() turns the bindings of the `:where` clauses into the result:
+/// the set of tuples of the `:find` and `:with` variables, aggregated per
+/// group of the non-aggregated `:find` variables, sorted.
+fn find_rows(query: &Query, bindings: &[Binding]) -> Result<Vec<Vec<Value>>, sqlx::Error> {
+    let vars: Vec<&String> = query
+        .find
+        .iter()
+        .map(|elem| match elem {
+            FindElem::Var(v) | FindElem::Aggregate(_, v) => v,
+        })
+        .chain(&query.with)
+        .collect();
+    let mut seen = BTreeSet::new();
+    let mut tuples: Vec<Vec<&Value>> = Vec::new();
+    for binding in bindings {
+        let tuple: Vec<&Value> = vars.iter().map(|v| &binding[*v]).collect();
+        let key =
+            serde_json::to_string(&tuple).map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+        if seen.insert(key) {
+            tuples.push(tuple);
+        }
+    }
+
+    let mut rows: Vec<Vec<Value>> = Vec::new();
+    if !query
+        .find
+        .iter()
+        .any(|elem| matches!(elem, FindElem::Aggregate(..)))
+    {
+        let width = query.find.len();
+        let mut seen = BTreeSet::new();
+        for tuple in tuples {
+            let row: Vec<Value> = tuple[..width].iter().map(|v| (*v).clone()).collect();
+            let key = serde_json::to_string(&row)
+                .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+            if seen.insert(key) {
+                rows.push(row);
+            }
+        }
+    } else {
+        // group by the values of the non-aggregated find elements
+        let group_cols: Vec<usize> = (0..query.find.len())
+            .filter(|&i| matches!(query.find[i], FindElem::Var(_)))
+            .collect();
+        let mut groups: Vec<(Vec<&Value>, Vec<&Vec<&Value>>)> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for tuple in &tuples {
+            let group: Vec<&Value> = group_cols.iter().map(|&i| tuple[i]).collect();
+            let key = serde_json::to_string(&group)
+                .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+            let slot = *index.entry(key).or_insert_with(|| {
+                groups.push((group, Vec::new()));
+                groups.len() - 1
+            });
+            groups[slot].1.push(tuple);
+        }
+        // aggregating nothing still counts 0 when nothing is grouped on
+        if groups.is_empty() && group_cols.is_empty() {
+            groups.push((Vec::new(), Vec::new()));
+        }
+        for (_, members) in groups {
+            let row = query
+                .find
+                .iter()
+                .enumerate()
+                .map(|(i, elem)| match elem {
+                    FindElem::Var(_) => members[0][i].clone(),
+                    FindElem::Aggregate(fun, _) => {
+                        let values: Vec<&Value> = members.iter().map(|t| t[i]).collect();
+                        aggregate(*fun, &values)
+                    }
+                })
+                .collect();
+            rows.push(row);
+        }
+    }
+    rows.sort_by(|a, b| compare_rows(a, b));
+    Ok(rows)
+}
+
+pub(crate) async fn query_on<S: Storage>(
+    storage: &mut S,
+    query: &Query,
+) -> Result<Vec<Vec<Value>>, sqlx::Error> {
+    let derived = derive_rules(storage, &query.rules).await?;
+    let bindings = eval_clauses(storage, &derived, &query.clauses, vec![Binding::new()]).await?;
+    find_rows(query, &bindings)
+}
+
+/// query() runs a Datalog query against the current state of the vault.
+/// Returns the rows of the result, one value per `:find` element, sorted.
+/// # Errors
+/// Returns an error if the SQL query fails.
+pub async fn query(db: &Db, query: &Query) -> Result<Vec<Vec<Value>>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    query_on(&mut *conn, query).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datom::{Datom, DatomOp, apply_datoms_on};
+    use crate::db::{TxMeta, test_conn};
+    use serde_json::json;
+    use sqlx::SqliteConnection;
+
+    fn add(e: &str, a: &str, v: Value) -> Datom {
+        Datom {
+            e: e.to_string(),
+            a: a.to_string(),
+            v,
+            op: DatomOp::Add,
+        }
+    }
+
+    async fn vault() -> SqliteConnection {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                add("page:1", "page/name", json!("2026-01-10")),
+                add("page:2", "page/name", json!("2026-01-11")),
+                add("block:1", "block/content", json!("TODO: write")),
+                add("block:1", "block/page", json!("page:1")),
+                add("block:1", "block/tags", json!(["work", "idea"])),
+                add("block:2", "block/content", json!("DONE: read")),
+                add("block:2", "block/page", json!("page:1")),
+                add("block:2", "block/parent", json!("block:1")),
+                add("block:3", "block/content", json!("TODO: sleep")),
+                add("block:3", "block/page", json!("page:2")),
+                add("block:3", "block/parent", json!("block:2")),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        conn
+    }
+
+    async fn run(conn: &mut SqliteConnection, query: &str) -> Vec<Vec<Value>> {
+        let query = Query::parse(query).unwrap();
+        query_on(conn, &query).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn query_joins_and_filters() {
+        let mut conn = vault().await;
+        let rows = run(
+            &mut conn,
+            r#"{:find [?c] :where [[?b "block/page" ?p] [?p "page/name" "2026-01-10"]
+                                  [?b "block/content" ?c]]}"#,
+        )
+        .await;
+        assert_eq!(
+            rows,
+            vec![vec![json!("DONE: read")], vec![json!("TODO: write")]]
+        );
+
+        let rows = run(
+            &mut conn,
+            r#"[:find ?b ?name
+                :where [?b :block/content ?c] [(clojure.string/starts-with? ?c "TODO")]
+                       [?b :block/page ?p] [?p :page/name ?name] [(> ?name "2026-01-10")]]"#,
+        )
+        .await;
+        assert_eq!(rows, vec![vec![json!("block:3"), json!("2026-01-11")]]);
+
+        // each element of a many attribute is a datom of its own
+        let rows = run(&mut conn, r#"[:find ?b :where [?b :block/tags "idea"]]"#).await;
+        assert_eq!(rows, vec![vec![json!("block:1")]]);
+
+        let rows = run(
+            &mut conn,
+            r#"{"find": ["?c"], "where": [["?b", "block/tags", "work"], ["?b", "block/content", "?c"]]}"#,
+        )
+        .await;
+        assert_eq!(rows, vec![vec![json!("TODO: write")]]);
+    }
+
+    #[tokio::test]
+    async fn query_derives_recursive_rules() {
+        let mut conn = vault().await;
+        let rows = run(
+            &mut conn,
+            r#"{:find [?d]
+                :in [$ %]
+                :where [[?a :block/content "TODO: write"] (descendant ?a ?d)]
+                :rules [[(descendant ?a ?d) [?d :block/parent ?a]]
+                        [(descendant ?a ?d) [?c :block/parent ?a] (descendant ?c ?d)]]}"#,
+        )
+        .await;
+        assert_eq!(rows, vec![vec![json!("block:2")], vec![json!("block:3")]]);
+    }
+
+    #[tokio::test]
+    async fn query_aggregates() {
+        let mut conn = vault().await;
+        let rows = run(
+            &mut conn,
+            "[:find ?name (count ?b) :where [?b :block/page ?p] [?p :page/name ?name]]",
+        )
+        .await;
+        assert_eq!(
+            rows,
+            vec![
+                vec![json!("2026-01-10"), json!(2)],
+                vec![json!("2026-01-11"), json!(1)]
+            ]
+        );
+
+        let rows = run(
+            &mut conn,
+            "[:find (max ?name) (count-distinct ?p) :where [?p :page/name ?name]]",
+        )
+        .await;
+        assert_eq!(rows, vec![vec![json!("2026-01-11"), json!(2)]]);
+
+        // :with keeps blocks with the same page apart before counting
+        let rows = run(
+            &mut conn,
+            "[:find (count ?p) :with ?b :where [?b :block/page ?p]]",
+        )
+        .await;
+        assert_eq!(rows, vec![vec![json!(3)]]);
+        let rows = run(&mut conn, "[:find (count ?p) :where [?b :block/page ?p]]").await;
+        assert_eq!(rows, vec![vec![json!(2)]]);
+
+        let rows = run(
+            &mut conn,
+            "[:find (count ?b) :where [?b :block/tags \"none\"]]",
+        )
+        .await;
+        assert_eq!(rows, vec![vec![json!(0)]]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_queries() {
+        for bad in [
+            "[:where [?b :block/content ?c]]",
+            "[:find ?x :where [?b :block/content ?c]]",
+            "[:find ?c :where [(> ?n 1)] [?b :block/content ?c]]",
+            "[:find ?c :where [?b :block/content ?c] [(matches? ?c \"a\")]]",
+            "[:find (median ?c) :where [?b :block/content ?c]]",
+            "[:find ?c :where [?b :block/content ?c] (ancestor ?b ?c)]",
+            "[:find ?c :in $ ?x :where [?b :block/content ?c]]",
+            "{:find [?c] :where [[?b :block/content ?c]]",
+        ] {
+            assert!(Query::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
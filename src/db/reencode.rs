@@ -0,0 +1,158 @@
+use sqlx::{Connection, Row, SqliteConnection};
+
+use crate::db::schema;
+use crate::db::transit::{self, Encoding};
+use crate::db::{Db, is_busy, retry_busy};
+
+/// reencode_on() rewrites the content of every vault row that is not already
+/// in `encoding`, in a single transaction. Instant attributes are told apart
+/// with the current schema.
+///
+/// Returns the number of rows rewritten.
+/// # Errors
+/// Returns an error if the SQL query fails or if a row cannot be decoded, in
+/// which case nothing is rewritten.
+async fn reencode_on(conn: &mut SqliteConnection, encoding: Encoding) -> Result<u64, sqlx::Error> {
+    let mut tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    let schema = schema::load_schema(&mut tx).await?;
+    let rows = sqlx::query("SELECT addr, content FROM vaults;")
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut rewritten = 0;
+    for row in rows {
+        let addr: i64 = row.get("addr");
+        let content: &[u8] = row.get("content");
+        if Encoding::of(content) == encoding {
+            continue;
+        }
+        let value = transit::decode_value(content).map_err(|err| {
+            sqlx::Error::Protocol(format!("Failed to decode vault row {}: {}", addr, err))
+        })?;
+        let content = transit::encode_value(&value, encoding, &schema)
+            .map_err(|err| sqlx::Error::Protocol(err.to_string()))?;
+        sqlx::query("UPDATE vaults SET content = ? WHERE addr = ?;")
+            .bind(content)
+            .bind(addr)
+            .execute(&mut *tx)
+            .await?;
+        rewritten += 1;
+    }
+
+    tx.commit().await?;
+    Ok(rewritten)
+}
+
+/// reencode() converts the vault in place to `encoding`, see reencode_on().
+pub async fn reencode(db: &Db, encoding: Encoding) -> Result<u64, sqlx::Error> {
+    retry_busy(is_busy, || async {
+        let mut conn = db.conn().await?;
+        reencode_on(&mut conn, encoding).await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_conn;
+
+    #[tokio::test]
+    async fn reencode_converts_legacy_rows_both_ways() {
+        let mut conn = test_conn().await;
+        sqlx::query("INSERT INTO vaults (addr, entity_id, content, addresses) VALUES (1, 'block:1', ?, '[]');")
+            .bind(r#"{"id":"block:1","attrs":{"block/content":"old"}}"#)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            reencode_on(&mut conn, Encoding::MessagePack).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            reencode_on(&mut conn, Encoding::MessagePack).await.unwrap(),
+            0
+        );
+        let row = sqlx::query("SELECT content FROM vaults WHERE addr = 1;")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        let content: &[u8] = row.get("content");
+        assert_eq!(Encoding::of(content), Encoding::MessagePack);
+        assert_eq!(
+            transit::decode_value(content).unwrap()["attrs"]["block/content"],
+            "old"
+        );
+
+        assert_eq!(reencode_on(&mut conn, Encoding::Json).await.unwrap(), 1);
+        let content: String = sqlx::query("SELECT CAST(content AS TEXT) AS content FROM vaults;")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("content");
+        assert_eq!(
+            content,
+            r#"{"attrs":{"block/content":"old"},"id":"block:1"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn transit_rows_tag_instants() {
+        let mut conn = test_conn().await;
+        let due = schema::Attribute {
+            ident: "todo/due".to_string(),
+            value_type: schema::ValueType::Instant,
+            cardinality: schema::Cardinality::One,
+            unique: None,
+        };
+        schema::declare_attribute_on(&mut conn, &due).await.unwrap();
+        sqlx::query("INSERT INTO vaults (addr, entity_id, content, addresses) VALUES (1, 'block:1', ?, '[]');")
+            .bind(r#"{"id":"block:1","attrs":{"todo/due":1700000000000,"block/order":3}}"#)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(reencode_on(&mut conn, Encoding::Transit).await.unwrap(), 1);
+        let content: String = sqlx::query("SELECT CAST(content AS TEXT) AS content FROM vaults;")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("content");
+        let written: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(
+            transit::read_transit(&written).unwrap(),
+            transit::Transit::Map(vec![
+                (
+                    transit::Transit::Keyword("attrs".to_string()),
+                    transit::Transit::Map(vec![
+                        (
+                            transit::Transit::Keyword("block/order".to_string()),
+                            transit::Transit::Int(3)
+                        ),
+                        (
+                            transit::Transit::Keyword("todo/due".to_string()),
+                            transit::Transit::Instant(1_700_000_000_000)
+                        ),
+                    ])
+                ),
+                (
+                    transit::Transit::Keyword("id".to_string()),
+                    transit::Transit::String("block:1".to_string())
+                ),
+            ])
+        );
+        assert!(content.contains(r#""~m1700000000000""#), "{}", content);
+
+        assert_eq!(reencode_on(&mut conn, Encoding::Json).await.unwrap(), 1);
+        let content: String = sqlx::query("SELECT CAST(content AS TEXT) AS content FROM vaults;")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get("content");
+        assert_eq!(
+            content,
+            r#"{"attrs":{"block/order":3,"todo/due":1700000000000},"id":"block:1"}"#
+        );
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Value;
+use sqlx::{Connection, Row, SqliteConnection};
+
+use crate::date::parse_instant;
+use crate::db::Db;
+use crate::db::datom::{Datom, DatomOp};
+use crate::db::error::TxError;
+use crate::db::index;
+use crate::db::storage::Storage;
+use crate::db::tempid::is_tempid;
+
+/// The type of the values an attribute holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    /// The id of another entity.
+    Ref,
+    /// Milliseconds since the UNIX epoch.
+    Instant,
+    Long,
+    Boolean,
+}
+
+/// Whether an entity holds one value of an attribute or a set of values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cardinality {
+    #[default]
+    One,
+    Many,
+}
+
+/// How unique the values of an attribute are across entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unique {
+    /// A value identifies its entity.
+    Identity,
+    /// A value may only be held by one entity.
+    Value,
+}
+
+impl ValueType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::Ref => "ref",
+            ValueType::Instant => "instant",
+            ValueType::Long => "long",
+            ValueType::Boolean => "boolean",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ValueType> {
+        match name {
+            "string" => Some(ValueType::String),
+            "ref" => Some(ValueType::Ref),
+            "instant" => Some(ValueType::Instant),
+            "long" => Some(ValueType::Long),
+            "boolean" => Some(ValueType::Boolean),
+            _ => None,
+        }
+    }
+}
+
+impl Cardinality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cardinality::One => "one",
+            Cardinality::Many => "many",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Cardinality> {
+        match name {
+            "one" => Some(Cardinality::One),
+            "many" => Some(Cardinality::Many),
+            _ => None,
+        }
+    }
+}
+
+impl Unique {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unique::Identity => "identity",
+            Unique::Value => "value",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Unique> {
+        match name {
+            "identity" => Some(Unique::Identity),
+            "value" => Some(Unique::Value),
+            _ => None,
+        }
+    }
+}
+
+/// A declared attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub ident: String,
+    pub value_type: ValueType,
+    pub cardinality: Cardinality,
+    pub unique: Option<Unique>,
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<24} {:<8} {}",
+            self.ident,
+            self.value_type.as_str(),
+            self.cardinality.as_str()
+        )?;
+        if let Some(unique) = self.unique {
+            write!(f, " unique/{}", unique.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// The attributes every vault knows about.
+const BUILTIN_ATTRIBUTES: &[(&str, ValueType, Cardinality, Option<Unique>)] = &[
+    ("block/content", ValueType::String, Cardinality::One, None),
+    ("block/title", ValueType::String, Cardinality::One, None),
+    ("block/page", ValueType::Ref, Cardinality::One, None),
+    ("block/tags", ValueType::String, Cardinality::Many, None),
+    (
+        "page/name",
+        ValueType::String,
+        Cardinality::One,
+        Some(Unique::Identity),
+    ),
+];
+
+/// The attributes of a vault: the built-in ones plus the ones declared in
+/// the `attributes` table. Attributes that are not declared are untyped,
+/// cardinality one and not unique.
+#[derive(Debug, Clone)]
+pub struct Schema {
+    attrs: HashMap<String, Attribute>,
+}
+
+impl Schema {
+    pub(crate) fn builtin() -> Schema {
+        let attrs = BUILTIN_ATTRIBUTES
+            .iter()
+            .map(|(ident, value_type, cardinality, unique)| {
+                let attr = Attribute {
+                    ident: ident.to_string(),
+                    value_type: *value_type,
+                    cardinality: *cardinality,
+                    unique: *unique,
+                };
+                (attr.ident.clone(), attr)
+            })
+            .collect();
+        Schema { attrs }
+    }
+
+    pub fn get(&self, ident: &str) -> Option<&Attribute> {
+        self.attrs.get(ident)
+    }
+
+    fn is_builtin(ident: &str) -> bool {
+        BUILTIN_ATTRIBUTES
+            .iter()
+            .any(|(builtin, ..)| *builtin == ident)
+    }
+
+    /// is_many() tells whether an attribute holds a set of values.
+    pub(crate) fn is_many(&self, ident: &str) -> bool {
+        self.get(ident)
+            .is_some_and(|attr| attr.cardinality == Cardinality::Many)
+    }
+
+    /// ref_attrs() returns the attributes whose values are entity ids.
+    pub(crate) fn ref_attrs(&self) -> impl Iterator<Item = &str> {
+        self.attrs
+            .values()
+            .filter(|attr| attr.value_type == ValueType::Ref)
+            .map(|attr| attr.ident.as_str())
+    }
+
+    /// unique_attrs() returns the attributes declared unique.
+    fn unique_attrs(&self) -> impl Iterator<Item = &str> {
+        self.attrs
+            .values()
+            .filter(|attr| attr.unique.is_some())
+            .map(|attr| attr.ident.as_str())
+    }
+
+    /// conform() checks a datom against the schema and returns it with its
+    /// value in the canonical form of the attribute's type: instants given as
+    /// dates or timestamps become milliseconds. A datom on a cardinality-many
+    /// attribute whose value is an array stands for one datom per element.
+    /// Retracts are not type checked, as they only remove values that were
+    /// already accepted.
+    /// # Errors
+    /// Returns a message naming the attribute when the value has the wrong type.
+    pub(crate) fn conform(&self, datom: &Datom) -> Result<Vec<Datom>, String> {
+        let Some(attr) = self.get(&datom.a) else {
+            return Ok(vec![datom.clone()]);
+        };
+        let values = match (&datom.v, attr.cardinality) {
+            (Value::Array(values), Cardinality::Many) => values.clone(),
+            (v, _) => vec![v.clone()],
+        };
+        values
+            .into_iter()
+            .map(|v| {
+                let v = if datom.op == DatomOp::Retract {
+                    v
+                } else {
+                    conform_value(attr.value_type, &v).ok_or_else(|| {
+                        format!(
+                            "Schema violation: {} expects a {} value, got {}",
+                            attr.ident,
+                            attr.value_type.as_str(),
+                            v
+                        )
+                    })?
+                };
+                Ok(Datom { v, ..datom.clone() })
+            })
+            .collect()
+    }
+}
+
+/// conform_value() returns `v` in the canonical form of `value_type`, or None
+/// if it is not a value of that type. A ref is an entity id: a string that is
+/// neither empty nor a tempid, as tempids are resolved before.
+fn conform_value(value_type: ValueType, v: &Value) -> Option<Value> {
+    match (value_type, v) {
+        (ValueType::String, Value::String(_)) => Some(v.clone()),
+        (ValueType::Ref, Value::String(id)) if !id.is_empty() && !is_tempid(id) => Some(v.clone()),
+        (ValueType::Long | ValueType::Instant, Value::Number(n)) if n.is_i64() => Some(v.clone()),
+        (ValueType::Instant, Value::String(s)) => parse_instant(s).map(Value::from),
+        (ValueType::Boolean, Value::Bool(_)) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+fn attribute_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Attribute, sqlx::Error> {
+    let ident: String = row.get("ident");
+    let invalid = |what: &str| sqlx::Error::Protocol(format!("Invalid {} for {}", what, ident));
+    let value_type =
+        ValueType::parse(row.get("value_type")).ok_or_else(|| invalid("value type"))?;
+    let cardinality =
+        Cardinality::parse(row.get("cardinality")).ok_or_else(|| invalid("cardinality"))?;
+    let unique = match row.get::<Option<&str>, _>("uniq") {
+        Some(name) => Some(Unique::parse(name).ok_or_else(|| invalid("uniqueness"))?),
+        None => None,
+    };
+    Ok(Attribute {
+        ident,
+        value_type,
+        cardinality,
+        unique,
+    })
+}
+
+/// load_schema() returns the built-in attributes plus the declared ones.
+pub(crate) async fn load_schema(conn: &mut SqliteConnection) -> Result<Schema, sqlx::Error> {
+    let mut schema = Schema::builtin();
+    let rows = sqlx::query("SELECT ident, value_type, cardinality, uniq FROM attributes;")
+        .fetch_all(conn)
+        .await?;
+    for row in rows {
+        let attr = attribute_from_row(&row)?;
+        schema.attrs.insert(attr.ident.clone(), attr);
+    }
+    Ok(schema)
+}
+
+/// The declarations of the declared attributes over time, oldest first, each
+/// with the last transaction before it.
+pub(crate) struct SchemaHistory {
+    declarations: Vec<(i64, Attribute)>,
+}
+
+impl SchemaHistory {
+    /// at() returns the schema transaction `tx` was applied with: the
+    /// built-in attributes plus the declarations made before it.
+    pub(crate) fn at(&self, tx: i64) -> Schema {
+        let mut schema = Schema::builtin();
+        for (after_tx, attr) in &self.declarations {
+            if *after_tx < tx {
+                schema.attrs.insert(attr.ident.clone(), attr.clone());
+            }
+        }
+        schema
+    }
+}
+
+/// load_schema_history() returns every recorded attribute declaration.
+pub(crate) async fn load_schema_history(
+    conn: &mut SqliteConnection,
+) -> Result<SchemaHistory, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT after_tx, ident, value_type, cardinality, uniq FROM attribute_history
+        ORDER BY rowid;",
+    )
+    .fetch_all(conn)
+    .await?;
+    let mut declarations = Vec::with_capacity(rows.len());
+    for row in rows {
+        declarations.push((row.get("after_tx"), attribute_from_row(&row)?));
+    }
+    Ok(SchemaHistory { declarations })
+}
+
+/// check_unique() fails if a value of a unique attribute touched by the
+/// current transaction is held by more than one entity. It runs on the
+/// datoms index, after the transaction's entities were reindexed.
+/// The index of the error is the position of the offending datom in `datoms`.
+pub(crate) async fn check_unique<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    datoms: &[Datom],
+) -> Result<(), TxError> {
+    for (index, datom) in datoms.iter().enumerate() {
+        if datom.op != DatomOp::Add || !schema.unique_attrs().any(|a| a == datom.a) {
+            continue;
+        }
+        let holders = storage.entities_with(&datom.a, &datom.v).await?;
+        if holders.len() > 1 {
+            return Err(TxError::Constraint {
+                index,
+                message: format!(
+                    "Unique constraint violation: {} {} is already held by {}",
+                    datom.a,
+                    datom.v,
+                    holders
+                        .iter()
+                        .find(|e| **e != datom.e)
+                        .unwrap_or(&holders[0])
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// declare_attribute_on() adds or replaces a declared attribute, after
+/// checking that the values already in the vault fit the declaration.
+pub(crate) async fn declare_attribute_on(
+    conn: &mut SqliteConnection,
+    attr: &Attribute,
+) -> Result<(), sqlx::Error> {
+    if Schema::is_builtin(&attr.ident) {
+        return Err(sqlx::Error::Protocol(format!(
+            "{} is a built-in attribute",
+            attr.ident
+        )));
+    }
+
+    for v in index::attribute_values(conn, &attr.ident).await? {
+        if conform_value(attr.value_type, &v).as_ref() != Some(&v) {
+            return Err(sqlx::Error::Protocol(format!(
+                "Existing value {} of {} is not a {}",
+                v,
+                attr.ident,
+                attr.value_type.as_str()
+            )));
+        }
+    }
+    if attr.unique.is_some() {
+        let duplicate =
+            sqlx::query("SELECT v FROM datoms WHERE a = ? GROUP BY v HAVING COUNT(*) > 1 LIMIT 1;")
+                .bind(&attr.ident)
+                .fetch_optional(&mut *conn)
+                .await?;
+        if let Some(row) = duplicate {
+            return Err(sqlx::Error::Protocol(format!(
+                "Existing value {} of {} is held by several entities",
+                row.get::<String, _>("v"),
+                attr.ident
+            )));
+        }
+    }
+
+    let mut sql_tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    sqlx::query(
+        "INSERT INTO attributes (ident, value_type, cardinality, uniq) VALUES (?, ?, ?, ?)
+        ON CONFLICT(ident) DO UPDATE SET value_type = excluded.value_type,
+            cardinality = excluded.cardinality, uniq = excluded.uniq;",
+    )
+    .bind(&attr.ident)
+    .bind(attr.value_type.as_str())
+    .bind(attr.cardinality.as_str())
+    .bind(attr.unique.map(|unique| unique.as_str()))
+    .execute(&mut *sql_tx)
+    .await?;
+    // recorded for reads as of a past transaction, see SchemaHistory
+    sqlx::query(
+        "INSERT INTO attribute_history (after_tx, ident, value_type, cardinality, uniq)
+        SELECT COALESCE(MAX(tx), 0), ?, ?, ?, ? FROM transactions;",
+    )
+    .bind(&attr.ident)
+    .bind(attr.value_type.as_str())
+    .bind(attr.cardinality.as_str())
+    .bind(attr.unique.map(|unique| unique.as_str()))
+    .execute(&mut *sql_tx)
+    .await?;
+    sql_tx.commit().await?;
+    Ok(())
+}
+
+/// declare_attribute() declares an attribute, see declare_attribute_on().
+pub async fn declare_attribute(db: &Db, attr: Attribute) -> Result<(), sqlx::Error> {
+    let mut conn = db.conn().await?;
+    declare_attribute_on(&mut conn, &attr).await
+}
+
+/// attributes() returns every known attribute, sorted by ident.
+pub async fn attributes(db: &Db) -> Result<Vec<Attribute>, sqlx::Error> {
+    let mut conn = db.conn().await?;
+    let schema = load_schema(&mut conn).await;
+    let mut attrs: Vec<Attribute> = schema?.attrs.into_values().collect();
+    attrs.sort_by(|a, b| a.ident.cmp(&b.ident));
+    Ok(attrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datom::apply_datoms_on;
+    use crate::db::{TxMeta, test_conn};
+
+    fn add(e: &str, a: &str, v: Value) -> Datom {
+        Datom {
+            op: DatomOp::Add,
+            e: e.to_string(),
+            a: a.to_string(),
+            v,
+        }
+    }
+
+    fn declared(ident: &str, value_type: ValueType, unique: Option<Unique>) -> Attribute {
+        Attribute {
+            ident: ident.to_string(),
+            value_type,
+            cardinality: Cardinality::One,
+            unique,
+        }
+    }
+
+    #[tokio::test]
+    async fn datoms_are_checked_against_value_types() {
+        let mut conn = test_conn().await;
+        declare_attribute_on(&mut conn, &declared("todo/due", ValueType::Instant, None))
+            .await
+            .unwrap();
+        declare_attribute_on(&mut conn, &declared("todo/done", ValueType::Boolean, None))
+            .await
+            .unwrap();
+
+        let report = apply_datoms_on(
+            &mut conn,
+            &[
+                add("block:1", "todo/due", Value::from("1970-01-01T00:00:01Z")),
+                add("block:1", "todo/done", Value::from(false)),
+                add("block:1", "free/form", Value::from(42)),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.entities[0].attrs["todo/due"], 1000);
+
+        for bad in [
+            add("block:1", "todo/done", Value::from("yes")),
+            add("block:1", "block/page", Value::from(7)),
+            add("block:1", "block/page", Value::from("")),
+            add("block:1", "block/content", Value::Null),
+        ] {
+            let err = apply_datoms_on(&mut conn, &[bad], &TxMeta::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, TxError::Schema { index: 0, .. }), "{}", err);
+            assert!(err.to_string().contains("Schema violation"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn unique_values_are_held_by_one_entity() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[add("page:1", "page/name", Value::from("2026-01-10"))],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let err = apply_datoms_on(
+            &mut conn,
+            &[
+                add("block:1", "block/content", Value::from("rolled back")),
+                add("page:2", "page/name", Value::from("2026-01-10")),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, TxError::Constraint { index: 1, .. }),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("page:1"), "{}", err);
+        assert!(
+            index::entities_with(&mut conn, "block/content", &Value::from("rolled back"))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // re-adding the value to its own entity is fine
+        apply_datoms_on(
+            &mut conn,
+            &[add("page:1", "page/name", Value::from("2026-01-10"))],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn declarations_must_fit_existing_values() {
+        let mut conn = test_conn().await;
+        apply_datoms_on(
+            &mut conn,
+            &[
+                add("block:1", "block/color", Value::from("blue")),
+                add("block:2", "block/color", Value::from("blue")),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        for attr in [
+            declared("block/color", ValueType::Long, None),
+            declared("block/color", ValueType::String, Some(Unique::Value)),
+            declared("page/name", ValueType::String, None),
+        ] {
+            assert!(declare_attribute_on(&mut conn, &attr).await.is_err());
+        }
+        declare_attribute_on(&mut conn, &declared("block/color", ValueType::String, None))
+            .await
+            .unwrap();
+        let schema = load_schema(&mut conn).await.unwrap();
+        assert_eq!(
+            schema.get("block/color").map(|attr| attr.value_type),
+            Some(ValueType::String)
+        );
+    }
+}
@@ -0,0 +1,534 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+
+use serde_json::{Map, Value};
+use sqlx::SqliteConnection;
+
+use crate::db::datom::{self, Datom, Entity};
+#[cfg(test)]
+use crate::db::datom::{TxOp, TxReport};
+#[cfg(test)]
+use crate::db::error::TxError;
+use crate::db::index::{self, HIGHLIGHT_END, HIGHLIGHT_START, SearchHit};
+use crate::db::log::{self, TxMeta};
+use crate::db::pull::{self, Pattern};
+use crate::db::query::{self, Query};
+use crate::db::schema::{self, Schema};
+
+/// Where a vault keeps its entities and their indexes.
+///
+/// Transactions, pulls, queries and reads of the current state run against
+/// any Storage. The SQLite one (a SqliteConnection) is the vault on disk and
+/// also keeps the transaction log that history, undo and as-of reads need;
+/// MemoryStorage keeps everything in memory and has no log.
+pub trait Storage: Send {
+    /// load_entities() loads entities by id. Entities that are not found are
+    /// returned empty.
+    fn load_entities(
+        &mut self,
+        entity_ids: &[&str],
+    ) -> impl Future<Output = Result<HashMap<String, Entity>, sqlx::Error>> + Send;
+
+    /// load_entity() loads one entity, empty if it is not found.
+    fn load_entity(
+        &mut self,
+        entity_id: &str,
+    ) -> impl Future<Output = Result<Entity, sqlx::Error>> + Send {
+        async move {
+            let mut loaded = self.load_entities(&[entity_id]).await?;
+            Ok(loaded.remove(entity_id).unwrap_or_else(|| Entity {
+                id: entity_id.to_string(),
+                attrs: Map::new(),
+            }))
+        }
+    }
+
+    /// write_entities() stores entities as they are now and updates the
+    /// indexes. `schema` tells which attributes are refs.
+    fn write_entities(
+        &mut self,
+        entities: &[Entity],
+        schema: &Schema,
+    ) -> impl Future<Output = Result<(), sqlx::Error>> + Send;
+
+    /// scan_entities() returns every entity.
+    fn scan_entities(&mut self) -> impl Future<Output = Result<Vec<Entity>, sqlx::Error>> + Send;
+
+    /// entities_with() returns the ids of the entities whose attribute `a`
+    /// has the value `v` (or holds it, for a set of values), sorted.
+    fn entities_with(
+        &mut self,
+        a: &str,
+        v: &Value,
+    ) -> impl Future<Output = Result<Vec<String>, sqlx::Error>> + Send;
+
+    /// match_datoms() returns the (entity, attribute, value) datoms with the
+    /// given entity, attribute and value; None matches any. Each element of
+    /// a set of values is a datom of its own.
+    fn match_datoms(
+        &mut self,
+        e: Option<&str>,
+        a: Option<&str>,
+        v: Option<&Value>,
+    ) -> impl Future<Output = Result<Vec<(String, String, Value)>, sqlx::Error>> + Send;
+
+    /// search_fulltext() returns the ids of the blocks whose "block/content"
+    /// matches `term`, best match first, with a highlighted snippet.
+    fn search_fulltext(
+        &mut self,
+        term: &str,
+    ) -> impl Future<Output = Result<Vec<(String, String)>, sqlx::Error>> + Send;
+
+    /// load_schema() returns the built-in attributes plus the declared ones.
+    fn load_schema(&mut self) -> impl Future<Output = Result<Schema, sqlx::Error>> + Send;
+
+    /// append_tx() records the datoms changed by a transaction and returns
+    /// its tx id.
+    fn append_tx(
+        &mut self,
+        datoms: &[Datom],
+        meta: &TxMeta,
+    ) -> impl Future<Output = Result<i64, sqlx::Error>> + Send;
+}
+
+impl Storage for SqliteConnection {
+    async fn load_entities(
+        &mut self,
+        entity_ids: &[&str],
+    ) -> Result<HashMap<String, Entity>, sqlx::Error> {
+        datom::load_entities(self, entity_ids).await
+    }
+
+    async fn write_entities(
+        &mut self,
+        entities: &[Entity],
+        schema: &Schema,
+    ) -> Result<(), sqlx::Error> {
+        datom::write_entities(self, entities, schema).await?;
+        index::reindex_entities(self, entities).await
+    }
+
+    async fn scan_entities(&mut self) -> Result<Vec<Entity>, sqlx::Error> {
+        datom::scan_entities(self).await
+    }
+
+    async fn entities_with(&mut self, a: &str, v: &Value) -> Result<Vec<String>, sqlx::Error> {
+        index::entities_with(self, a, v).await
+    }
+
+    async fn match_datoms(
+        &mut self,
+        e: Option<&str>,
+        a: Option<&str>,
+        v: Option<&Value>,
+    ) -> Result<Vec<(String, String, Value)>, sqlx::Error> {
+        index::match_datoms(self, e, a, v).await
+    }
+
+    async fn search_fulltext(&mut self, term: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+        index::search_fulltext(self, term).await
+    }
+
+    async fn load_schema(&mut self) -> Result<Schema, sqlx::Error> {
+        schema::load_schema(self).await
+    }
+
+    async fn append_tx(&mut self, datoms: &[Datom], meta: &TxMeta) -> Result<i64, sqlx::Error> {
+        log::append_tx(self, datoms, meta).await
+    }
+}
+
+/// A vault kept in memory, for `jottty serve --memory` and for tests that
+/// should not touch the disk. It starts empty, with the built-in attributes
+/// only, or as a copy of another vault (see copy_of()), and keeps no
+/// transaction log: transactions get a tx id, but their datoms are not
+/// recorded.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    entities: BTreeMap<String, Entity>,
+    /// The schema of the vault it was copied from; the built-in attributes
+    /// if None.
+    schema: Option<Schema>,
+    last_tx: i64,
+}
+
+/// datoms_of() returns the (attribute, value) datoms of an entity, one per
+/// element of a set of values, as the `datoms` index has them.
+fn datoms_of(entity: &Entity) -> impl Iterator<Item = (&String, &Value)> {
+    entity.attrs.iter().flat_map(|(a, v)| match v {
+        Value::Array(values) => values.iter().map(|v| (a, v)).collect::<Vec<_>>(),
+        v => vec![(a, v)],
+    })
+}
+
+/// search_words() returns the words of a search term, lowercased, dropping
+/// the FTS5 operators and syntax the in-memory search does not support.
+fn search_words(term: &str) -> Vec<String> {
+    term.split_whitespace()
+        .filter(|word| !matches!(*word, "AND" | "OR" | "NOT"))
+        .map(|word| {
+            word.trim_matches(|c| c == '"' || c == '*')
+                .to_ascii_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// highlight() wraps every occurrence of `words` in `content` in
+/// HIGHLIGHT_START and HIGHLIGHT_END, ignoring ASCII case.
+fn highlight(content: &str, words: &[String]) -> String {
+    let lower = content.to_ascii_lowercase();
+    let mut marks = vec![false; content.len()];
+    for word in words {
+        for (start, _) in lower.match_indices(word.as_str()) {
+            marks[start..start + word.len()].fill(true);
+        }
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut marked = false;
+    for (i, c) in content.char_indices() {
+        if marks[i] != marked {
+            out.push_str(if marks[i] {
+                HIGHLIGHT_START
+            } else {
+                HIGHLIGHT_END
+            });
+            marked = marks[i];
+        }
+        out.push(c);
+    }
+    if marked {
+        out.push_str(HIGHLIGHT_END);
+    }
+    out
+}
+
+impl Storage for MemoryStorage {
+    async fn load_entities(
+        &mut self,
+        entity_ids: &[&str],
+    ) -> Result<HashMap<String, Entity>, sqlx::Error> {
+        Ok(entity_ids
+            .iter()
+            .map(|id| {
+                let entity = self.entities.get(*id).cloned().unwrap_or_else(|| Entity {
+                    id: id.to_string(),
+                    attrs: Map::new(),
+                });
+                (id.to_string(), entity)
+            })
+            .collect())
+    }
+
+    async fn write_entities(
+        &mut self,
+        entities: &[Entity],
+        _schema: &Schema,
+    ) -> Result<(), sqlx::Error> {
+        for entity in entities {
+            if entity.attrs.is_empty() {
+                self.entities.remove(&entity.id);
+            } else {
+                self.entities.insert(entity.id.clone(), entity.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn scan_entities(&mut self) -> Result<Vec<Entity>, sqlx::Error> {
+        Ok(self.entities.values().cloned().collect())
+    }
+
+    async fn entities_with(&mut self, a: &str, v: &Value) -> Result<Vec<String>, sqlx::Error> {
+        let mut ids: Vec<String> = self
+            .match_datoms(None, Some(a), Some(v))
+            .await?
+            .into_iter()
+            .map(|(e, _, _)| e)
+            .collect();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    async fn match_datoms(
+        &mut self,
+        e: Option<&str>,
+        a: Option<&str>,
+        v: Option<&Value>,
+    ) -> Result<Vec<(String, String, Value)>, sqlx::Error> {
+        let entities: Vec<&Entity> = match e {
+            Some(e) => self.entities.get(e).into_iter().collect(),
+            None => self.entities.values().collect(),
+        };
+        Ok(entities
+            .into_iter()
+            .flat_map(|entity| {
+                datoms_of(entity)
+                    .filter(|(da, dv)| a.is_none_or(|a| a == *da) && v.is_none_or(|v| v == *dv))
+                    .map(|(da, dv)| (entity.id.clone(), da.clone(), dv.clone()))
+            })
+            .collect())
+    }
+
+    /// The in-memory search is a plain word search: a block matches if its
+    /// content has every word of the term, ignoring ASCII case. Blocks come
+    /// in id order.
+    async fn search_fulltext(&mut self, term: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+        let words = search_words(term);
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .entities
+            .values()
+            .filter_map(|entity| match entity.attrs.get("block/content") {
+                Some(Value::String(content)) => {
+                    let lower = content.to_ascii_lowercase();
+                    words
+                        .iter()
+                        .all(|word| lower.contains(word.as_str()))
+                        .then(|| (entity.id.clone(), highlight(content, &words)))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn load_schema(&mut self) -> Result<Schema, sqlx::Error> {
+        Ok(self.schema.clone().unwrap_or_else(Schema::builtin))
+    }
+
+    async fn append_tx(&mut self, _datoms: &[Datom], _meta: &TxMeta) -> Result<i64, sqlx::Error> {
+        self.last_tx += 1;
+        Ok(self.last_tx)
+    }
+}
+
+impl MemoryStorage {
+    /// copy_of() reads the current state of `storage` into memory: its
+    /// entities and its schema, without the transaction log.
+    /// # Errors
+    /// Returns an error if `storage` cannot be read.
+    pub async fn copy_of<S: Storage>(storage: &mut S) -> Result<MemoryStorage, sqlx::Error> {
+        let entities = storage.scan_entities().await?;
+        Ok(MemoryStorage {
+            entities: entities
+                .into_iter()
+                .map(|entity| (entity.id.clone(), entity))
+                .collect(),
+            schema: Some(storage.load_schema().await?),
+            last_tx: 0,
+        })
+    }
+
+    /// apply_ops() applies the ops of a transaction, like db::apply_ops().
+    /// They run against a copy of the vault that replaces it only once every
+    /// op was applied, so a failed transaction changes nothing.
+    /// # Errors
+    /// Returns a TxError like db::apply_ops().
+    #[cfg(test)]
+    pub async fn apply_ops(&mut self, ops: &[TxOp], meta: &TxMeta) -> Result<TxReport, TxError> {
+        let mut staged = self.clone();
+        let report = datom::transact_on(&mut staged, ops, meta).await?;
+        *self = staged;
+        Ok(report)
+    }
+
+    /// pull() reads an entity, like db::pull().
+    pub async fn pull(
+        &mut self,
+        pattern: &Pattern,
+        eid: &Value,
+    ) -> Result<Option<Value>, sqlx::Error> {
+        pull::pull_on(self, pattern, eid).await
+    }
+
+    /// query() runs a Datalog query, like db::query().
+    pub async fn query(&mut self, query: &Query) -> Result<Vec<Vec<Value>>, sqlx::Error> {
+        query::query_on(self, query).await
+    }
+
+    /// list_pages() lists the page names, like db::list_pages().
+    pub async fn list_pages(&mut self) -> Result<Vec<String>, sqlx::Error> {
+        datom::current_pages(self).await
+    }
+
+    /// list_page_blocks() lists the blocks of a page, like db::list_page_blocks().
+    pub async fn list_page_blocks(&mut self, page_id: &str) -> Result<Vec<Entity>, sqlx::Error> {
+        datom::current_page_blocks(self, page_id).await
+    }
+
+    /// search_blocks() searches "block/content", see search_fulltext().
+    pub async fn search_blocks(&mut self, term: &str) -> Result<Vec<SearchHit>, sqlx::Error> {
+        datom::current_search_hits(self, term).await
+    }
+
+    /// tagged_blocks() lists the blocks tagged with `tag`, like db::tagged_blocks().
+    pub async fn tagged_blocks(&mut self, tag: &str) -> Result<Vec<Entity>, sqlx::Error> {
+        datom::current_tagged_blocks(self, tag).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_conn;
+    use serde_json::json;
+
+    fn add(e: &str, a: &str, v: Value) -> TxOp {
+        TxOp::Datom(Datom {
+            op: datom::DatomOp::Add,
+            e: e.to_string(),
+            a: a.to_string(),
+            v,
+        })
+    }
+
+    /// reads() writes a small vault to `storage` and returns what the reads
+    /// make of it, so both backends can be compared.
+    async fn reads<S: Storage>(storage: &mut S) -> Vec<Value> {
+        let ops = [
+            add("tmp/page", "page/name", json!("2026-01-10")),
+            add("block:1", "block/content", json!("TODO: Buy milk")),
+            add("block:1", "block/page", json!("tmp/page")),
+            add("block:1", "block/tags", json!(["TODO", "home"])),
+            add("block:2", "block/content", json!("Call the plumber")),
+            add("block:2", "block/page", json!(["page/name", "2026-01-10"])),
+        ];
+        let report = datom::transact_on(storage, &ops[..4], &TxMeta::default())
+            .await
+            .unwrap();
+        let page = &report.tempids["tmp/page"];
+        datom::transact_on(storage, &ops[4..], &TxMeta::default())
+            .await
+            .unwrap();
+
+        let pattern = Pattern::parse("[page/name {block/_page [block/content]}]").unwrap();
+        let query =
+            Query::parse("[:find ?c (count ?t) :where [?b :block/tags ?t] [?b :block/content ?c]]")
+                .unwrap();
+        let ids =
+            |entities: Vec<Entity>| json!(entities.into_iter().map(|e| e.id).collect::<Vec<_>>());
+        vec![
+            pull::pull_on(storage, &pattern, &json!(["page/name", "2026-01-10"]))
+                .await
+                .unwrap()
+                .unwrap(),
+            json!(query::query_on(storage, &query).await.unwrap()),
+            json!(datom::current_pages(storage).await.unwrap()),
+            ids(datom::current_page_blocks(storage, page).await.unwrap()),
+            ids(datom::current_tagged_blocks(storage, "home").await.unwrap()),
+            json!(
+                datom::current_search_hits(storage, "plumber")
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|hit| hit.snippet)
+                    .collect::<Vec<_>>()
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn memory_storage_reads_like_sqlite() {
+        let from_sqlite = reads(&mut test_conn().await).await;
+        let from_memory = reads(&mut MemoryStorage::default()).await;
+
+        let mut page = from_memory[0].clone();
+        page.as_object_mut().unwrap().remove("db/id");
+        assert_eq!(
+            page,
+            json!({
+                "page/name": "2026-01-10",
+                "block/_page": [
+                    {"db/id": "block:1", "block/content": "TODO: Buy milk"},
+                    {"db/id": "block:2", "block/content": "Call the plumber"},
+                ],
+            })
+        );
+        assert_eq!(from_memory[1], json!([["TODO: Buy milk", 2]]));
+        assert_eq!(
+            from_memory[5],
+            json!([format!(
+                "Call the {}plumber{}",
+                HIGHLIGHT_START, HIGHLIGHT_END
+            )])
+        );
+        // tempids get fresh ids, so the pulled page ids differ
+        assert_eq!(from_sqlite[1..], from_memory[1..]);
+        assert_eq!(from_sqlite[0]["block/_page"], from_memory[0]["block/_page"]);
+    }
+
+    #[tokio::test]
+    async fn memory_copies_read_like_their_vault() {
+        let mut conn = test_conn().await;
+        let due = schema::Attribute {
+            ident: "todo/due".to_string(),
+            value_type: schema::ValueType::Instant,
+            cardinality: schema::Cardinality::One,
+            unique: None,
+        };
+        schema::declare_attribute_on(&mut conn, &due).await.unwrap();
+        let from_sqlite = reads(&mut conn).await;
+
+        let mut copy = MemoryStorage::copy_of(&mut conn).await.unwrap();
+        assert!(copy.load_schema().await.unwrap().get("todo/due").is_some());
+        assert_eq!(
+            copy.scan_entities().await.unwrap().len(),
+            conn.scan_entities().await.unwrap().len()
+        );
+        let pattern = Pattern::parse("[page/name {block/_page [block/content]}]").unwrap();
+        let page = json!(["page/name", "2026-01-10"]);
+        assert_eq!(
+            copy.pull(&pattern, &page).await.unwrap().unwrap(),
+            from_sqlite[0]
+        );
+        assert_eq!(json!(copy.list_pages().await.unwrap()), from_sqlite[2]);
+    }
+
+    #[tokio::test]
+    async fn failed_transactions_leave_memory_storage_unchanged() {
+        let mut storage = MemoryStorage::default();
+        storage
+            .apply_ops(
+                &[add("page:1", "page/name", json!("2026-01-10"))],
+                &TxMeta::default(),
+            )
+            .await
+            .unwrap();
+
+        let err = storage
+            .apply_ops(
+                &[
+                    add("block:1", "block/content", json!("rolled back")),
+                    add("page:2", "page/name", json!("2026-01-10")),
+                ],
+                &TxMeta::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, TxError::Constraint { index: 1, .. }),
+            "{}",
+            err
+        );
+        assert!(
+            storage
+                .load_entity("block:1")
+                .await
+                .unwrap()
+                .attrs
+                .is_empty()
+        );
+
+        let report = storage
+            .apply_ops(
+                &[add("block:1", "block/content", json!("kept"))],
+                &TxMeta::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.tx, 2);
+        assert_eq!(storage.list_pages().await.unwrap(), vec!["2026-01-10"]);
+    }
+}
@@ -0,0 +1,94 @@
+use serde_json::Value;
+use sqlx::{Connection, SqliteConnection};
+
+use crate::db::migrate::{backfilled, record_backfill};
+use crate::db::schema::load_schema;
+use crate::db::storage::Storage;
+
+const TAGS_BACKFILL: &str = "block/tags";
+
+/// Extracts the tags of a note: every `#tag` word, and a leading all-caps
+/// marker such as `TODO:`. Tags are returned without `#` or `:`, once each,
+/// in the order they appear.
+pub fn extract_tags(note: &str) -> Vec<String> {
+    let is_tag_char = |c: char| c.is_alphanumeric() || matches!(c, '-' | '_' | '/');
+    let mut tags: Vec<String> = Vec::new();
+    let mut push = |tag: &str| {
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    };
+
+    if let Some(marker) = note
+        .split_whitespace()
+        .next()
+        .and_then(|w| w.strip_suffix(':'))
+        && marker.len() > 1
+        && marker.chars().all(|c| c.is_ascii_uppercase())
+    {
+        push(marker);
+    }
+    for word in note.split_whitespace() {
+        if let Some(tag) = word.strip_prefix('#') {
+            let end = tag.find(|c: char| !is_tag_char(c)).unwrap_or(tag.len());
+            push(&tag[..end]);
+        }
+    }
+    tags
+}
+
+/// backfill_block_tags() sets `block/tags` on the blocks written before tags
+/// were extracted on write, from their content, so `jottty tag --filter`
+/// finds them. It runs once per vault, as recorded in `backfills`, and is
+/// not logged: like the indexes, the tags are derived from the content.
+pub(crate) async fn backfill_block_tags(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    if backfilled(conn, TAGS_BACKFILL).await? {
+        return Ok(());
+    }
+    let mut sql_tx = conn.begin_with("BEGIN IMMEDIATE").await?;
+    // another process may have run it while this one waited for the lock
+    if backfilled(&mut sql_tx, TAGS_BACKFILL).await? {
+        return Ok(());
+    }
+
+    let mut tagged = Vec::new();
+    for mut entity in sql_tx.scan_entities().await? {
+        if entity.attrs.contains_key("block/tags") {
+            continue;
+        }
+        let Some(Value::String(content)) = entity.attrs.get("block/content") else {
+            continue;
+        };
+        let tags = extract_tags(content);
+        if tags.is_empty() {
+            continue;
+        }
+        entity
+            .attrs
+            .insert("block/tags".to_string(), Value::from(tags));
+        tagged.push(entity);
+    }
+    let schema = load_schema(&mut sql_tx).await?;
+    sql_tx.write_entities(&tagged, &schema).await?;
+
+    record_backfill(&mut sql_tx, TAGS_BACKFILL).await?;
+    sql_tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_tags_finds_hashtags_and_markers() {
+        assert_eq!(
+            extract_tags("TODO: review #rust-lang, then #idea and #rust-lang again"),
+            vec!["TODO", "rust-lang", "idea"]
+        );
+        assert_eq!(
+            extract_tags("Note: not a marker # nor a tag"),
+            Vec::<String>::new()
+        );
+        assert_eq!(extract_tags("A: single letter"), Vec::<String>::new());
+    }
+}
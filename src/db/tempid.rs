@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::Value;
+
+use crate::date::{now_nanos, today_date};
+use crate::db::datom::{Datom, DatomOp, TxOp};
+use crate::db::error::TxError;
+use crate::db::schema::{Schema, Unique, ValueType};
+use crate::db::storage::Storage;
+
+/// The prefix of named tempids, like "tmp/new-block".
+pub(crate) const TEMPID_PREFIX: &str = "tmp/";
+
+/// is_tempid() tells whether the entity slot of an op is a tempid rather than
+/// an entity id. Only the explicit forms are tempids: a negative number like
+/// "-1" or a name under TEMPID_PREFIX. Any other string is an entity id, so
+/// writes to ids without a kind prefix (like "journal") update that entity.
+pub(crate) fn is_tempid(id: &str) -> bool {
+    id.starts_with(TEMPID_PREFIX) || id.parse::<i64>().is_ok_and(|n| n < 0)
+}
+
+/// op_ids() returns the ids an op refers to: its entity slot and, for a ref
+/// attribute, its value.
+fn op_ids<'a>(op: &'a TxOp, schema: &Schema) -> impl Iterator<Item = &'a str> {
+    let is_ref = |a: &str| {
+        schema
+            .get(a)
+            .is_some_and(|attr| attr.value_type == ValueType::Ref)
+    };
+    let v = match op {
+        TxOp::Datom(datom) if is_ref(&datom.a) => datom.v.as_str(),
+        TxOp::Cas { a, new, .. } if is_ref(a) => new.as_str(),
+        _ => None,
+    };
+    [Some(op.entity_id()), v].into_iter().flatten()
+}
+
+/// check_unprefixed_ids() rejects ids without a `kind:` prefix that name no
+/// entity, like "new-block". Such ids were tempids before tempids needed
+/// TEMPID_PREFIX, so a transaction still using one would otherwise create an
+/// entity with that literal id. Entities that already have such an id (like
+/// "journal") can still be written and referred to.
+/// # Errors
+/// Returns a TxError::Parse at the op with such an id, or a TxError::Storage
+/// if the SQL query fails.
+pub(crate) async fn check_unprefixed_ids<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    ops: &[TxOp],
+) -> Result<(), TxError> {
+    for (index, op) in ops.iter().enumerate() {
+        for id in op_ids(op, schema) {
+            if id.is_empty() || id.contains(':') || is_tempid(id) {
+                continue;
+            }
+            if storage.load_entity(id).await?.attrs.is_empty() {
+                return Err(TxError::Parse {
+                    index,
+                    message: format!(
+                        "No entity {:?}; a tempid must start with {:?}, like {:?}",
+                        id,
+                        TEMPID_PREFIX,
+                        format!("{}{}", TEMPID_PREFIX, id)
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// id_kind() picks the kind of a new entity from the namespace of the first
+/// attribute it is given, e.g. "block" for "block/content".
+fn id_kind(ops: &[TxOp], tempid: &str) -> String {
+    ops.iter()
+        .find_map(|op| match op {
+            TxOp::Datom(datom) if datom.e == tempid => Some(datom.a.as_str()),
+            TxOp::Cas { e, a, .. } if e == tempid => Some(a.as_str()),
+            _ => None,
+        })
+        .and_then(|a| a.split_once('/'))
+        .map_or("entity", |(ns, _)| ns)
+        .to_string()
+}
+
+/// Generates entity ids that are not in use, in the `kind:date-nanos` form
+/// the CLI has always used. Ids generated together are strictly increasing.
+struct IdGenerator {
+    date: String,
+    next: u128,
+}
+
+impl IdGenerator {
+    fn new() -> IdGenerator {
+        IdGenerator {
+            date: today_date(),
+            next: now_nanos(),
+        }
+    }
+
+    async fn fresh<S: Storage>(
+        &mut self,
+        storage: &mut S,
+        kind: &str,
+    ) -> Result<String, sqlx::Error> {
+        loop {
+            let id = format!("{}:{}-{}", kind, self.date, self.next);
+            self.next += 1;
+            if storage.load_entity(&id).await?.attrs.is_empty() {
+                return Ok(id);
+            }
+        }
+    }
+}
+
+fn resolve(id: &str, tempids: &BTreeMap<String, String>) -> String {
+    tempids.get(id).cloned().unwrap_or_else(|| id.to_string())
+}
+
+/// resolve_tempids() replaces the tempids of a transaction by entity ids.
+/// Tempids are found in entity slots and in the values of ref attributes.
+/// A tempid given a value of a unique identity attribute resolves to the
+/// entity that already holds that value (an upsert); every other tempid gets
+/// a fresh id. The same tempid means the same entity throughout the
+/// transaction.
+///
+/// Returns the ops with ids in place and the tempid→id map.
+/// # Errors
+/// Returns an error if the SQL query fails.
+pub(crate) async fn resolve_tempids<S: Storage>(
+    storage: &mut S,
+    schema: &Schema,
+    ops: &[TxOp],
+) -> Result<(Vec<TxOp>, BTreeMap<String, String>), sqlx::Error> {
+    let is_ref = |a: &str| {
+        schema
+            .get(a)
+            .is_some_and(|attr| attr.value_type == ValueType::Ref)
+    };
+
+    // tempids in order of appearance
+    let mut found: Vec<&str> = Vec::new();
+    for op in ops {
+        for id in op_ids(op, schema) {
+            if is_tempid(id) && !found.contains(&id) {
+                found.push(id);
+            }
+        }
+    }
+
+    let mut tempids = BTreeMap::new();
+    if found.is_empty() {
+        return Ok((ops.to_vec(), tempids));
+    }
+
+    // upserts: identity values already held by an entity, or by a tempid seen
+    // before; `same` maps a tempid to the earlier tempid it is the same entity as
+    let mut identities: HashMap<(&str, &Value), &str> = HashMap::new();
+    let mut same: Vec<(&str, &str)> = Vec::new();
+    for op in ops {
+        let TxOp::Datom(datom) = op else {
+            continue;
+        };
+        let identity = schema
+            .get(&datom.a)
+            .is_some_and(|attr| attr.unique == Some(Unique::Identity));
+        if datom.op != DatomOp::Add || !identity || !is_tempid(&datom.e) {
+            continue;
+        }
+        if tempids.contains_key(&datom.e) || same.iter().any(|(t, _)| *t == datom.e) {
+            continue;
+        }
+        let key = (datom.a.as_str(), &datom.v);
+        if let Some(earlier) = identities.get(&key) {
+            if *earlier != datom.e {
+                same.push((datom.e.as_str(), earlier));
+            }
+        } else {
+            if let Some(id) = storage
+                .entities_with(&datom.a, &datom.v)
+                .await?
+                .into_iter()
+                .next()
+            {
+                tempids.insert(datom.e.clone(), id);
+            }
+            identities.insert(key, datom.e.as_str());
+        }
+    }
+
+    let mut ids = IdGenerator::new();
+    for tempid in &found {
+        if !tempids.contains_key(*tempid) && !same.iter().any(|(t, _)| t == tempid) {
+            let id = ids.fresh(storage, &id_kind(ops, tempid)).await?;
+            tempids.insert(tempid.to_string(), id);
+        }
+    }
+    for (tempid, mut earlier) in same.clone() {
+        while let Some((_, e)) = same.iter().find(|(t, _)| *t == earlier) {
+            earlier = e;
+        }
+        let id = tempids[earlier].clone();
+        tempids.insert(tempid.to_string(), id);
+    }
+
+    let resolve_value = |a: &str, v: &Value| match v {
+        Value::String(id) if is_ref(a) => Value::from(resolve(id, &tempids)),
+        v => v.clone(),
+    };
+    let resolved = ops
+        .iter()
+        .map(|op| match op {
+            TxOp::Datom(datom) => TxOp::Datom(Datom {
+                e: resolve(&datom.e, &tempids),
+                v: resolve_value(&datom.a, &datom.v),
+                ..datom.clone()
+            }),
+            TxOp::RetractEntity(e) => TxOp::RetractEntity(resolve(e, &tempids)),
+            TxOp::Cas { e, a, old, new } => TxOp::Cas {
+                e: resolve(e, &tempids),
+                a: a.clone(),
+                old: old.clone(),
+                new: resolve_value(a, new),
+            },
+            TxOp::Lookup { .. } => op.clone(),
+        })
+        .collect();
+    Ok((resolved, tempids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::datom::{Entity, apply_ops_on};
+    use crate::db::{TxMeta, test_conn};
+
+    fn add(e: &str, a: &str, v: &str) -> TxOp {
+        TxOp::Datom(Datom {
+            op: DatomOp::Add,
+            e: e.to_string(),
+            a: a.to_string(),
+            v: Value::from(v),
+        })
+    }
+
+    #[tokio::test]
+    async fn tempids_get_fresh_ids_of_their_kind() {
+        let mut conn = test_conn().await;
+        let report = apply_ops_on(
+            &mut conn,
+            &[
+                add("-1", "block/content", "first"),
+                add("-1", "block/page", "tmp/new-page"),
+                add("-2", "block/content", "second"),
+                add("tmp/new-page", "page/name", "2026-01-10"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let first = &report.tempids["-1"];
+        let second = &report.tempids["-2"];
+        let page = &report.tempids["tmp/new-page"];
+        assert!(first.starts_with("block:") && second.starts_with("block:"));
+        assert!(first < second);
+        assert!(page.starts_with("page:"));
+        assert_eq!(report.entities[0].id, *first);
+        assert_eq!(
+            report.entities[0].attrs["block/page"],
+            Value::from(page.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn tempids_with_an_identity_upsert() {
+        let mut conn = test_conn().await;
+        apply_ops_on(
+            &mut conn,
+            &[add("page:2026-01-10", "page/name", "2026-01-10")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+
+        let report = apply_ops_on(
+            &mut conn,
+            &[
+                add("tmp/day", "page/name", "2026-01-10"),
+                add("tmp/day", "page/color", "blue"),
+                add("tmp/other", "page/name", "2026-01-11"),
+                add("tmp/same", "page/name", "2026-01-11"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.tempids["tmp/day"], "page:2026-01-10");
+        assert_eq!(report.tempids["tmp/other"], report.tempids["tmp/same"]);
+        assert_eq!(report.entities.len(), 2);
+        assert_eq!(report.entities[0].attrs["page/color"], "blue");
+    }
+
+    #[tokio::test]
+    async fn ids_without_a_kind_are_not_tempids() {
+        let mut conn = test_conn().await;
+        let schema = conn.load_schema().await.unwrap();
+        // an entity an older vault gave an id without a kind
+        let mut attrs = serde_json::Map::new();
+        attrs.insert("page/title".to_string(), Value::from("Journal"));
+        conn.write_entities(
+            &[Entity {
+                id: "journal".to_string(),
+                attrs,
+            }],
+            &schema,
+        )
+        .await
+        .unwrap();
+
+        let report = apply_ops_on(
+            &mut conn,
+            &[add("journal", "page/title", "Daily journal")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert!(report.tempids.is_empty());
+        let journal = conn.load_entity("journal").await.unwrap();
+        assert_eq!(journal.attrs["page/title"], "Daily journal");
+        assert_eq!(conn.scan_entities().await.unwrap().len(), 1);
+
+        let report = apply_ops_on(
+            &mut conn,
+            &[add("-1", "block/page", "journal")],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.entities[0].attrs["block/page"], "journal");
+    }
+
+    #[tokio::test]
+    async fn unknown_ids_without_a_kind_are_rejected() {
+        let mut conn = test_conn().await;
+        let err = apply_ops_on(
+            &mut conn,
+            &[
+                add("tmp/page", "page/name", "2026-01-10"),
+                add("new-block", "block/content", "first"),
+            ],
+            &TxMeta::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(&err, TxError::Parse { index: 1, .. }), "{}", err);
+        assert!(err.to_string().contains("\"tmp/new-block\""), "{}", err);
+        assert!(conn.scan_entities().await.unwrap().is_empty());
+    }
+}
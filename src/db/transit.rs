@@ -1,15 +1,621 @@
-use serde_json::Value;
-// TODO@chico: switch to a more efficient format like
-// Binary JSON (BSON) or MessagePack or CBOR.
+use std::collections::HashMap;
+use std::fmt;
 
-/// Encode a serde_json::Value into a JSON string.
-/// Returns a Result containing the JSON string or an error.
-pub fn encode_value(value: &Value) -> Result<String, serde_json::Error> {
-    serde_json::to_string(value)
+use chrono::DateTime;
+use serde_json::{Map, Number, Value};
+
+use crate::db::schema::{Schema, ValueType};
+
+/// The first byte of a MessagePack encoded value. JSON values are stored
+/// without a tag, so rows written before encodings existed (which always
+/// start with '{') still decode. Transit JSON values always start with '['.
+const MSGPACK_TAG: u8 = 0x01;
+
+/// How values are encoded in the content column of the vaults table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    #[default]
+    MessagePack,
+    /// Transit JSON, readable by transit-js/transit-clj and DataScript tooling.
+    Transit,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Transit => "transit",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" | "messagepack" => Some(Encoding::MessagePack),
+            "transit" => Some(Encoding::Transit),
+            _ => None,
+        }
+    }
+
+    /// of() detects the encoding of an encoded value from its tag byte.
+    pub fn of(input: &[u8]) -> Encoding {
+        match input.first() {
+            Some(&MSGPACK_TAG) => Encoding::MessagePack,
+            Some(b'[') => Encoding::Transit,
+            _ => Encoding::Json,
+        }
+    }
+}
+
+/// An error encoding or decoding a value.
+#[derive(Debug)]
+pub enum TransitError {
+    Json(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+    Transit(String),
+}
+
+impl fmt::Display for TransitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitError::Json(err) => write!(f, "invalid JSON value: {}", err),
+            TransitError::Encode(err) => write!(f, "failed to encode MessagePack: {}", err),
+            TransitError::Decode(err) => write!(f, "invalid MessagePack value: {}", err),
+            TransitError::Transit(msg) => write!(f, "invalid Transit value: {}", msg),
+        }
+    }
+}
+
+/// A value in the Transit data model.
+/// Only the types jottty stores are kept apart; symbols, big decimals and
+/// unknown tags are read as their string form, lists and sets as arrays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transit {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Keyword(String),
+    /// Milliseconds since the UNIX epoch, written as `~m`.
+    Instant(i64),
+    /// Written as `~u`.
+    Uuid(String),
+    Array(Vec<Transit>),
+    Map(Vec<(Transit, Transit)>),
+}
+
+/// Integers outside of this range don't survive a JavaScript reader as JSON
+/// numbers, so they are written as `~i` strings.
+const MAX_JSON_INT: i64 = (1 << 53) - 1;
+/// Strings shorter than this are never cached.
+const MIN_SIZE_CACHEABLE: usize = 4;
+const CACHE_CODE_DIGITS: usize = 44;
+const MAX_CACHE_ENTRIES: usize = CACHE_CODE_DIGITS * CACHE_CODE_DIGITS;
+const BASE_CHAR: u8 = b'0';
+const MAP_AS_ARRAY: &str = "^ ";
+
+/// is_cacheable() tells whether an emitted string goes through the cache:
+/// map keys, keywords, symbols and tags of 4 characters or more.
+fn is_cacheable(s: &str, as_map_key: bool) -> bool {
+    s.len() >= MIN_SIZE_CACHEABLE
+        && (as_map_key || s.starts_with("~#") || s.starts_with("~:") || s.starts_with("~$"))
+}
+
+fn cache_code(index: usize) -> String {
+    let mut code = String::from("^");
+    if index >= CACHE_CODE_DIGITS {
+        code.push(char::from(BASE_CHAR + (index / CACHE_CODE_DIGITS) as u8));
+    }
+    code.push(char::from(BASE_CHAR + (index % CACHE_CODE_DIGITS) as u8));
+    code
+}
+
+fn cache_index(code: &str) -> Option<usize> {
+    let digits: Vec<usize> = code
+        .bytes()
+        .map(|b| b.checked_sub(BASE_CHAR).map(usize::from))
+        .collect::<Option<_>>()?;
+    match digits.as_slice() {
+        [d] => Some(*d),
+        [hi, lo] => Some(hi * CACHE_CODE_DIGITS + lo),
+        _ => None,
+    }
+}
+
+/// Writes Transit values as Transit JSON, caching repeated keys and keywords
+/// the way transit-js does.
+#[derive(Default)]
+struct TransitWriter {
+    cache: HashMap<String, usize>,
+}
+
+impl TransitWriter {
+    fn emit_str(&mut self, s: String, as_map_key: bool) -> Value {
+        if is_cacheable(&s, as_map_key) {
+            if let Some(index) = self.cache.get(&s) {
+                return Value::String(cache_code(*index));
+            }
+            if self.cache.len() == MAX_CACHE_ENTRIES {
+                self.cache.clear();
+            }
+            self.cache.insert(s.clone(), self.cache.len());
+        }
+        Value::String(s)
+    }
+
+    fn emit(&mut self, value: &Transit, as_map_key: bool) -> Value {
+        match value {
+            Transit::Null if as_map_key => self.emit_str("~_".to_string(), true),
+            Transit::Null => Value::Null,
+            Transit::Bool(b) if as_map_key => {
+                self.emit_str(format!("~?{}", if *b { 't' } else { 'f' }), true)
+            }
+            Transit::Bool(b) => Value::Bool(*b),
+            Transit::Int(n) if as_map_key || !(-MAX_JSON_INT..=MAX_JSON_INT).contains(n) => {
+                self.emit_str(format!("~i{}", n), as_map_key)
+            }
+            Transit::Int(n) => Value::from(*n),
+            Transit::Float(f) if f.is_nan() => self.emit_str("~zNaN".to_string(), as_map_key),
+            Transit::Float(f) if f.is_infinite() => {
+                let inf = if *f > 0.0 { "~zINF" } else { "~z-INF" };
+                self.emit_str(inf.to_string(), as_map_key)
+            }
+            Transit::Float(f) if as_map_key => self.emit_str(format!("~d{}", f), true),
+            Transit::Float(f) => Number::from_f64(*f).map_or(Value::Null, Value::Number),
+            Transit::String(s) => {
+                let escaped = if s.starts_with(['~', '^', '`']) {
+                    format!("~{}", s)
+                } else {
+                    s.clone()
+                };
+                self.emit_str(escaped, as_map_key)
+            }
+            Transit::Keyword(k) => self.emit_str(format!("~:{}", k), as_map_key),
+            Transit::Instant(ms) => self.emit_str(format!("~m{}", ms), as_map_key),
+            Transit::Uuid(uuid) => self.emit_str(format!("~u{}", uuid), as_map_key),
+            Transit::Array(items) => {
+                Value::Array(items.iter().map(|item| self.emit(item, false)).collect())
+            }
+            Transit::Map(pairs) => {
+                let composite_keys = pairs
+                    .iter()
+                    .any(|(k, _)| matches!(k, Transit::Array(_) | Transit::Map(_)));
+                if composite_keys {
+                    let tag = self.emit_str("~#cmap".to_string(), false);
+                    let mut flat = Vec::with_capacity(pairs.len() * 2);
+                    for (k, v) in pairs {
+                        flat.push(self.emit(k, false));
+                        flat.push(self.emit(v, false));
+                    }
+                    return Value::Array(vec![tag, Value::Array(flat)]);
+                }
+                let mut out = Vec::with_capacity(pairs.len() * 2 + 1);
+                out.push(Value::String(MAP_AS_ARRAY.to_string()));
+                for (k, v) in pairs {
+                    out.push(self.emit(k, true));
+                    out.push(self.emit(v, false));
+                }
+                Value::Array(out)
+            }
+        }
+    }
+}
+
+/// write_transit() writes a value as Transit JSON. Scalars at the top level
+/// are wrapped in a `~#'` quote, as the spec requires.
+pub fn write_transit(value: &Transit) -> Value {
+    let mut writer = TransitWriter::default();
+    match value {
+        Transit::Array(_) | Transit::Map(_) => writer.emit(value, false),
+        _ => Value::Array(vec![Value::from("~#'"), writer.emit(value, false)]),
+    }
+}
+
+/// A decoded Transit string: a value, or the tag of a tagged value.
+enum ReadStr {
+    Value(Transit),
+    Tag(String),
 }
 
-/// Decode a JSON string into a serde_json::Value.
+/// Reads Transit JSON, in both the compact and the verbose form.
+#[derive(Default)]
+struct TransitReader {
+    cache: Vec<String>,
+}
+
+impl TransitReader {
+    fn read_str(&mut self, s: &str, as_map_key: bool) -> Result<ReadStr, TransitError> {
+        let raw = if s.starts_with('^') && s != MAP_AS_ARRAY {
+            cache_index(&s[1..])
+                .and_then(|index| self.cache.get(index))
+                .cloned()
+                .ok_or_else(|| TransitError::Transit(format!("unknown cache code {}", s)))?
+        } else {
+            if is_cacheable(s, as_map_key) {
+                if self.cache.len() == MAX_CACHE_ENTRIES {
+                    self.cache.clear();
+                }
+                self.cache.push(s.to_string());
+            }
+            s.to_string()
+        };
+        Ok(parse_raw(raw))
+    }
+
+    fn read(&mut self, value: &Value, as_map_key: bool) -> Result<Transit, TransitError> {
+        match value {
+            Value::Null => Ok(Transit::Null),
+            Value::Bool(b) => Ok(Transit::Bool(*b)),
+            Value::Number(n) => Ok(n
+                .as_i64()
+                .map(Transit::Int)
+                .unwrap_or_else(|| Transit::Float(n.as_f64().unwrap_or(f64::NAN)))),
+            Value::String(s) => match self.read_str(s, as_map_key)? {
+                ReadStr::Value(value) => Ok(value),
+                ReadStr::Tag(tag) => Ok(Transit::String(format!("~#{}", tag))),
+            },
+            Value::Array(items) => self.read_array(items),
+            Value::Object(map) => {
+                if map.len() == 1 {
+                    let (k, v) = map.iter().next().expect("map has one entry");
+                    if let ReadStr::Tag(tag) = self.read_str(k, true)? {
+                        return self.read_tagged(&tag, v);
+                    }
+                    let key = self.read_key_again(k)?;
+                    return Ok(Transit::Map(vec![(key, self.read(v, false)?)]));
+                }
+                let mut pairs = Vec::with_capacity(map.len());
+                for (k, v) in map {
+                    pairs.push((
+                        self.read(&Value::String(k.clone()), true)?,
+                        self.read(v, false)?,
+                    ));
+                }
+                Ok(Transit::Map(pairs))
+            }
+        }
+    }
+
+    /// read_key_again() decodes a verbose map key that was already pushed to
+    /// the cache by read_str(), without caching it twice.
+    fn read_key_again(&mut self, k: &str) -> Result<Transit, TransitError> {
+        match parse_raw(k.to_string()) {
+            ReadStr::Value(value) => Ok(value),
+            ReadStr::Tag(tag) => Ok(Transit::String(format!("~#{}", tag))),
+        }
+    }
+
+    fn read_array(&mut self, items: &[Value]) -> Result<Transit, TransitError> {
+        let Some((first, rest)) = items.split_first() else {
+            return Ok(Transit::Array(Vec::new()));
+        };
+        if first.as_str() == Some(MAP_AS_ARRAY) {
+            if rest.len() % 2 != 0 {
+                return Err(TransitError::Transit(
+                    "map with an odd number of items".to_string(),
+                ));
+            }
+            let mut pairs = Vec::with_capacity(rest.len() / 2);
+            for pair in rest.chunks(2) {
+                pairs.push((self.read(&pair[0], true)?, self.read(&pair[1], false)?));
+            }
+            return Ok(Transit::Map(pairs));
+        }
+
+        let first = match first {
+            Value::String(s) => match self.read_str(s, false)? {
+                ReadStr::Tag(tag) if rest.len() == 1 => return self.read_tagged(&tag, &rest[0]),
+                ReadStr::Tag(tag) => Transit::String(format!("~#{}", tag)),
+                ReadStr::Value(value) => value,
+            },
+            other => self.read(other, false)?,
+        };
+        let mut out = Vec::with_capacity(items.len());
+        out.push(first);
+        for item in rest {
+            out.push(self.read(item, false)?);
+        }
+        Ok(Transit::Array(out))
+    }
+
+    fn read_tagged(&mut self, tag: &str, rep: &Value) -> Result<Transit, TransitError> {
+        let value = self.read(rep, false)?;
+        match (tag, value) {
+            ("cmap", Transit::Array(items)) => {
+                if items.len() % 2 != 0 {
+                    return Err(TransitError::Transit(
+                        "cmap with an odd number of items".to_string(),
+                    ));
+                }
+                let mut items = items.into_iter();
+                let mut pairs = Vec::new();
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    pairs.push((k, v));
+                }
+                Ok(Transit::Map(pairs))
+            }
+            // quotes, lists, sets and unknown tags keep their representation
+            (_, value) => Ok(value),
+        }
+    }
+}
+
+/// parse_raw() decodes a Transit string that is not a cache code.
+fn parse_raw(raw: String) -> ReadStr {
+    let Some(rest) = raw.strip_prefix('~') else {
+        return ReadStr::Value(Transit::String(raw));
+    };
+    let Some(tag) = rest.chars().next() else {
+        return ReadStr::Value(Transit::String(raw));
+    };
+    let body = &rest[tag.len_utf8()..];
+    let value = match tag {
+        '~' | '^' | '`' => Transit::String(rest.to_string()),
+        '#' => return ReadStr::Tag(body.to_string()),
+        ':' => Transit::Keyword(body.to_string()),
+        '$' => Transit::String(body.to_string()),
+        'm' => body
+            .parse()
+            .map_or(Transit::String(raw.clone()), Transit::Instant),
+        't' => DateTime::parse_from_rfc3339(body).map_or(Transit::String(raw.clone()), |t| {
+            Transit::Instant(t.timestamp_millis())
+        }),
+        'u' => Transit::Uuid(body.to_string()),
+        'i' | 'n' => body
+            .parse()
+            .map_or_else(|_| Transit::String(body.to_string()), Transit::Int),
+        'd' | 'f' => body
+            .parse()
+            .map_or(Transit::String(raw.clone()), Transit::Float),
+        'z' => match body {
+            "NaN" => Transit::Float(f64::NAN),
+            "INF" => Transit::Float(f64::INFINITY),
+            "-INF" => Transit::Float(f64::NEG_INFINITY),
+            _ => Transit::String(raw.clone()),
+        },
+        '_' => Transit::Null,
+        '?' => Transit::Bool(body == "t"),
+        // unknown scalar tags are kept as they are
+        _ => Transit::String(raw.clone()),
+    };
+    ReadStr::Value(value)
+}
+
+/// read_transit() reads a Transit JSON value.
+/// # Errors
+/// Returns an error on cache codes that were never defined or malformed maps.
+pub fn read_transit(value: &Value) -> Result<Transit, TransitError> {
+    TransitReader::default().read(value, false)
+}
+
+/// json_to_transit() maps a serde_json::Value to Transit. Object keys, which
+/// are attribute names in the entity maps, become keywords, and the values of
+/// the instant attributes of `schema` become instants, so DataScript reads
+/// them back as dates.
+fn json_to_transit(value: &Value, schema: &Schema) -> Transit {
+    match value {
+        Value::Null => Transit::Null,
+        Value::Bool(b) => Transit::Bool(*b),
+        Value::Number(n) => n
+            .as_i64()
+            .map(Transit::Int)
+            .unwrap_or_else(|| Transit::Float(n.as_f64().unwrap_or(f64::NAN))),
+        Value::String(s) => Transit::String(s.clone()),
+        Value::Array(items) => Transit::Array(
+            items
+                .iter()
+                .map(|item| json_to_transit(item, schema))
+                .collect(),
+        ),
+        Value::Object(map) => Transit::Map(
+            map.iter()
+                .map(|(k, v)| {
+                    let v = match schema.get(k) {
+                        Some(attr) if attr.value_type == ValueType::Instant => {
+                            instant_to_transit(v, schema)
+                        }
+                        _ => json_to_transit(v, schema),
+                    };
+                    (Transit::Keyword(k.clone()), v)
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// instant_to_transit() maps the value of an instant attribute, milliseconds
+/// or a set of them, to Transit instants.
+fn instant_to_transit(value: &Value, schema: &Schema) -> Transit {
+    match value {
+        Value::Number(n) if n.is_i64() => Transit::Instant(n.as_i64().unwrap_or_default()),
+        Value::Array(items) => Transit::Array(
+            items
+                .iter()
+                .map(|item| instant_to_transit(item, schema))
+                .collect(),
+        ),
+        value => json_to_transit(value, schema),
+    }
+}
+
+/// transit_to_json() maps a Transit value back to a serde_json::Value.
+/// Keywords and UUIDs become strings and instants milliseconds.
+fn transit_to_json(value: Transit) -> Value {
+    match value {
+        Transit::Null => Value::Null,
+        Transit::Bool(b) => Value::Bool(b),
+        Transit::Int(n) => Value::from(n),
+        Transit::Float(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+        Transit::String(s) | Transit::Keyword(s) | Transit::Uuid(s) => Value::String(s),
+        Transit::Instant(ms) => Value::from(ms),
+        Transit::Array(items) => Value::Array(items.into_iter().map(transit_to_json).collect()),
+        Transit::Map(pairs) => {
+            let mut map = Map::new();
+            for (k, v) in pairs {
+                let key = match transit_to_json(k) {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                map.insert(key, transit_to_json(v));
+            }
+            Value::Object(map)
+        }
+    }
+}
+
+/// Encode a serde_json::Value with the given encoding. `schema` tells which
+/// attributes hold instants, which Transit tags as such.
+/// Returns a Result containing the encoded bytes or an error.
+pub fn encode_value(
+    value: &Value,
+    encoding: Encoding,
+    schema: &Schema,
+) -> Result<Vec<u8>, TransitError> {
+    match encoding {
+        Encoding::Json => serde_json::to_vec(value).map_err(TransitError::Json),
+        Encoding::MessagePack => {
+            let mut out = vec![MSGPACK_TAG];
+            rmp_serde::encode::write_named(&mut out, value).map_err(TransitError::Encode)?;
+            Ok(out)
+        }
+        Encoding::Transit => serde_json::to_vec(&write_transit(&json_to_transit(value, schema)))
+            .map_err(TransitError::Json),
+    }
+}
+
+/// Decode bytes written by encode_value() into a serde_json::Value, whatever
+/// encoding they were written with.
 /// Returns a Result containing the Value or an error.
-pub fn decode_value(input: &str) -> Result<Value, serde_json::Error> {
-    serde_json::from_str(input)
+pub fn decode_value(input: &[u8]) -> Result<Value, TransitError> {
+    match Encoding::of(input) {
+        Encoding::Json => serde_json::from_slice(input).map_err(TransitError::Json),
+        Encoding::MessagePack => rmp_serde::from_slice(&input[1..]).map_err(TransitError::Decode),
+        Encoding::Transit => {
+            let json: Value = serde_json::from_slice(input).map_err(TransitError::Json)?;
+            read_transit(&json).map(transit_to_json)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn values_round_trip_in_every_encoding() {
+        let value = json!({
+            "id": "block:1",
+            "attrs": {"block/content": "héllo", "block/order": -3, "block/done": true, "block/score": 1.5}
+        });
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Transit] {
+            let bytes = encode_value(&value, encoding, &Schema::builtin()).unwrap();
+            assert_eq!(Encoding::of(&bytes), encoding);
+            assert_eq!(decode_value(&bytes).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn untagged_json_rows_still_decode() {
+        let legacy = br#"{"id":"block:1","attrs":{}}"#;
+        assert_eq!(
+            decode_value(legacy).unwrap(),
+            json!({"id": "block:1", "attrs": {}})
+        );
+        assert!(
+            encode_value(
+                &json!({"id": "block:1", "attrs": {}}),
+                Encoding::MessagePack,
+                &Schema::builtin()
+            )
+            .unwrap()
+            .len()
+                < legacy.len()
+        );
+    }
+
+    #[test]
+    fn transit_writer_caches_keys_and_keywords() {
+        let maps = Transit::Array(vec![
+            Transit::Map(vec![(
+                Transit::Keyword("block/content".into()),
+                Transit::Int(1),
+            )]),
+            Transit::Map(vec![(
+                Transit::Keyword("block/content".into()),
+                Transit::Int(2),
+            )]),
+            Transit::Keyword("block/content".into()),
+            Transit::String("~tilde".into()),
+        ]);
+        let written = write_transit(&maps);
+        assert_eq!(
+            written,
+            json!([
+                ["^ ", "~:block/content", 1],
+                ["^ ", "^0", 2],
+                "^0",
+                "~~tilde"
+            ])
+        );
+        assert_eq!(read_transit(&written).unwrap(), maps);
+    }
+
+    #[test]
+    fn transit_reads_tagged_values() {
+        let input = json!([
+            "~m1700000000000",
+            "~t2023-11-14T22:13:20.000Z",
+            "~u531a379e-31bb-4ce1-8690-158dceb64be6",
+            "~i9007199254740993",
+            ["~#set", [1, 2]],
+            ["~#cmap", [["^ ", "~:a", 1], "x"]],
+            {"~:verbose": true}
+        ]);
+        assert_eq!(
+            read_transit(&input).unwrap(),
+            Transit::Array(vec![
+                Transit::Instant(1_700_000_000_000),
+                Transit::Instant(1_700_000_000_000),
+                Transit::Uuid("531a379e-31bb-4ce1-8690-158dceb64be6".into()),
+                Transit::Int(9_007_199_254_740_993),
+                Transit::Array(vec![Transit::Int(1), Transit::Int(2)]),
+                Transit::Map(vec![(
+                    Transit::Map(vec![(Transit::Keyword("a".into()), Transit::Int(1))]),
+                    Transit::String("x".into())
+                )]),
+                Transit::Map(vec![(
+                    Transit::Keyword("verbose".into()),
+                    Transit::Bool(true)
+                )]),
+            ])
+        );
+        assert_eq!(
+            write_transit(&Transit::Instant(1_700_000_000_000)),
+            json!(["~#'", "~m1700000000000"])
+        );
+    }
+
+    #[test]
+    fn entities_are_written_as_transit_maps_with_keyword_keys() {
+        let entity =
+            json!({"id": "block:1", "attrs": {"block/content": "~home", "block/page": "page:1"}});
+        let bytes = encode_value(&entity, Encoding::Transit, &Schema::builtin()).unwrap();
+        let written: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            written,
+            json!([
+                "^ ",
+                "~:attrs",
+                ["^ ", "~:block/content", "~~home", "~:block/page", "page:1"],
+                "~:id",
+                "block:1"
+            ])
+        );
+        assert_eq!(decode_value(&bytes).unwrap(), entity);
+    }
 }
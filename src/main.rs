@@ -2,11 +2,24 @@ mod cli;
 mod config;
 mod date;
 mod db;
+mod diff;
+mod server;
 mod transact;
-use db::init_db;
+use db::Db;
 
 #[tokio::main]
 async fn main() {
-    init_db().await;
-    cli::run().await;
+    let db = match Db::from_config().await {
+        Ok(db) => db,
+        Err(err) if db::is_busy(&err) => {
+            eprintln!("The vault is busy: another process is writing to it, try again.");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Failed to open the vault: {}", err);
+            std::process::exit(1);
+        }
+    };
+    cli::run(&db).await;
+    db.close().await;
 }
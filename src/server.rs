@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::Mutex;
+
+use crate::db::{self, Db, MemoryStorage, Pattern, Query};
+
+/// The vault the API serves: the configured one, or a copy of it kept in
+/// memory for as long as the server runs.
+#[derive(Clone)]
+enum Vault {
+    File(Db),
+    Memory(Arc<Mutex<MemoryStorage>>),
+}
+
+/// The body of `POST /api/pull`.
+#[derive(Debug, Deserialize)]
+struct PullRequest {
+    /// A pull pattern like `[* {block/page [page/name]}]`.
+    pattern: String,
+    /// An entity id or a lookup ref `[a, v]`.
+    eid: Value,
+}
+
+/// The body of `POST /query`, told apart by its "kind".
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum QueryRequest {
+    /// The names of all pages.
+    Pages,
+    /// The blocks of a page.
+    PageBlocks { page: String },
+    /// A full-text search, or a tag filter for a term like "tag:TODO".
+    Search { term: String },
+    /// A Datalog query, as EDN text or as JSON.
+    Datalog { query: Value },
+}
+
+fn error_response(status: StatusCode, body: Value) -> Response {
+    (status, Json(body)).into_response()
+}
+
+async fn pull_handler(State(vault): State<Vault>, Json(request): Json<PullRequest>) -> Response {
+    let pattern = match Pattern::parse(&request.pattern) {
+        Ok(pattern) => pattern,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, json!({ "error": err })),
+    };
+    let pulled = match vault {
+        Vault::File(db) => db::pull(&db, &pattern, &request.eid).await,
+        Vault::Memory(storage) => storage.lock().await.pull(&pattern, &request.eid).await,
+    };
+    match pulled {
+        Ok(Some(entity)) => Json(entity).into_response(),
+        Ok(None) => error_response(
+            StatusCode::NOT_FOUND,
+            json!({ "error": format!("No entity {}", request.eid) }),
+        ),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({ "error": err.to_string() }),
+        ),
+    }
+}
+
+/// parse_query() reads the query of a "datalog" request: EDN text, or the
+/// query itself as JSON.
+fn parse_query(query: &Value) -> Result<Query, String> {
+    match query {
+        Value::String(text) => Query::parse(text),
+        json => Query::from_json(json),
+    }
+}
+
+async fn query_handler(State(vault): State<Vault>, Json(request): Json<QueryRequest>) -> Response {
+    let result = match request {
+        QueryRequest::Pages => match vault {
+            Vault::File(db) => db::list_pages(&db, None).await,
+            Vault::Memory(storage) => storage.lock().await.list_pages().await,
+        }
+        .map(|pages| json!(pages)),
+        QueryRequest::PageBlocks { page } => match vault {
+            Vault::File(db) => db::list_page_blocks(&db, &page, None).await,
+            Vault::Memory(storage) => storage.lock().await.list_page_blocks(&page).await,
+        }
+        .map(|blocks| json!(blocks)),
+        QueryRequest::Search { term } => match (term.strip_prefix("tag:"), vault) {
+            (Some(tag), Vault::File(db)) => db::tagged_blocks(&db, tag, None).await,
+            (Some(tag), Vault::Memory(storage)) => storage.lock().await.tagged_blocks(tag).await,
+            (None, Vault::File(db)) => db::search_blocks(&db, &term, None)
+                .await
+                .map(|hits| hits.into_iter().map(|hit| hit.entity).collect()),
+            (None, Vault::Memory(storage)) => storage
+                .lock()
+                .await
+                .search_blocks(&term)
+                .await
+                .map(|hits| hits.into_iter().map(|hit| hit.entity).collect()),
+        }
+        .map(|blocks| json!(blocks)),
+        QueryRequest::Datalog { query } => {
+            let query = match parse_query(&query) {
+                Ok(query) => query,
+                Err(err) => {
+                    return error_response(StatusCode::BAD_REQUEST, json!({ "error": err }));
+                }
+            };
+            match vault {
+                Vault::File(db) => db::query(&db, &query).await,
+                Vault::Memory(storage) => storage.lock().await.query(&query).await,
+            }
+            .map(|rows| json!(rows))
+        }
+    };
+    match result {
+        Ok(result) => Json(json!({ "result": result })).into_response(),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({ "error": err.to_string() }),
+        ),
+    }
+}
+
+/// serve() runs the HTTP API on `addr` until the process is stopped.
+/// With `memory`, it serves a copy of the configured vault kept in memory,
+/// taken when the server starts, instead of the vault itself.
+/// See notes/api.md for the endpoints.
+/// # Errors
+/// Returns an error if `addr` cannot be bound or the vault to copy cannot be
+/// read.
+pub async fn serve(db: &Db, addr: &str, memory: bool) -> std::io::Result<()> {
+    let vault = if memory {
+        let mut conn = db.conn().await.map_err(std::io::Error::other)?;
+        let storage = MemoryStorage::copy_of(&mut *conn)
+            .await
+            .map_err(std::io::Error::other)?;
+        Vault::Memory(Arc::new(Mutex::new(storage)))
+    } else {
+        Vault::File(db.clone())
+    };
+    let app = Router::new()
+        .route("/api/pull", post(pull_handler))
+        .route("/query", post(query_handler))
+        .with_state(vault);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await
+}
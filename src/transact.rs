@@ -1,44 +1,372 @@
-use crate::db::{self, Datom, DatomOp, Entity};
-use serde_json::Value;
+use crate::db::{self, Datom, DatomOp, Db, TxError, TxKind, TxMeta, TxOp, TxReport};
+use serde_json::{Map, Value};
 
-fn normalize_op(op: &str) -> Option<DatomOp> {
-    let op = op.trim_start_matches(':');
-    match op {
-        "db/add" => Some(DatomOp::Add),
-        "db/retract" => Some(DatomOp::Retract),
-        _ => None,
+/// The prefix of the tempids given to entity maps without `db/id`. Tempids
+/// under it are reserved: a transaction that writes one is rejected, so a
+/// tempid of the user's never merges with an anonymous entity map.
+const RESERVED_TEMPID_PREFIX: &str = "tmp/db/";
+
+/// check_reserved() fails if `value` is, or holds, a reserved tempid.
+fn check_reserved(value: &Value) -> Result<(), String> {
+    match value {
+        Value::String(s) if s.starts_with(RESERVED_TEMPID_PREFIX) => Err(format!(
+            "Tempids starting with {} are reserved: {}",
+            RESERVED_TEMPID_PREFIX, s
+        )),
+        Value::Array(items) => items.iter().try_for_each(check_reserved),
+        _ => Ok(()),
+    }
+}
+
+fn string_at(list: &[Value], idx: usize, name: &str) -> Result<String, String> {
+    list[idx]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Datom {} must be a string", name))
+}
+
+/// The entity of an op: an entity id (or tempid), or a lookup ref `[a, v]`.
+enum EntitySlot {
+    Id(String),
+    Lookup(String, Value),
+}
+
+impl EntitySlot {
+    fn from_value(value: &Value) -> Result<EntitySlot, String> {
+        match value {
+            Value::String(id) => Ok(EntitySlot::Id(id.clone())),
+            Value::Array(lookup) => match lookup.as_slice() {
+                [Value::String(a), v] => Ok(EntitySlot::Lookup(a.clone(), v.clone())),
+                _ => Err("Lookup ref must be [attribute, value]".to_string()),
+            },
+            _ => Err("Datom e must be a string or a lookup ref".to_string()),
+        }
+    }
+
+    /// op() puts the entity in the entity slot of `op`.
+    fn op(&self, op: TxOp) -> TxOp {
+        match self {
+            EntitySlot::Id(id) => op.with_entity(id.clone()),
+            EntitySlot::Lookup(a, v) => TxOp::Lookup {
+                a: a.clone(),
+                v: v.clone(),
+                op: Box::new(op),
+            },
+        }
+    }
+
+    /// to_value() is the value of a ref attribute pointing at the entity.
+    fn to_value(&self) -> Value {
+        match self {
+            EntitySlot::Id(id) => Value::from(id.as_str()),
+            EntitySlot::Lookup(a, v) => Value::Array(vec![Value::from(a.as_str()), v.clone()]),
+        }
     }
 }
 
-fn datom_from_value(value: Value) -> Result<Datom, String> {
+/// op_from_value() parses one element of a transaction in list form:
+/// `[op, e, a, v]` for `db/add` and `db/retract`, `[op, e]` for
+/// `db/retractEntity` and `[op, e, a, old, new]` for `db/cas`.
+/// Ops may be written as keywords (`:db/add`). The entity `e` is an entity
+/// id, a tempid or a lookup ref `[a, v]`.
+/// `index` is the position of the element in the transaction, for errors.
+fn op_from_value(index: usize, value: Value) -> Result<TxOp, TxError> {
+    let parse = |message: String| TxError::Parse { index, message };
     let list = value
         .as_array()
-        .ok_or_else(|| "Datom must be an array".to_string())?;
-    if list.len() != 4 {
-        return Err("Datom list must have 4 items".to_string());
+        .ok_or_else(|| parse("Datom must be an array or an entity map".to_string()))?;
+    let op = list
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| parse("Datom op must be a string".to_string()))?
+        .trim_start_matches(':');
+    let arity = match op {
+        "db/add" | "db/retract" => 4,
+        "db/retractEntity" => 2,
+        "db/cas" => 5,
+        _ => {
+            return Err(TxError::UnknownOp {
+                index,
+                op: op.to_string(),
+            });
+        }
+    };
+    if list.len() != arity {
+        return Err(parse(format!(
+            "Datom list for {} must have {} items",
+            op, arity
+        )));
     }
-    let op = list[0]
-        .as_str()
-        .ok_or_else(|| "Datom op must be a string".to_string())
-        .and_then(|op| normalize_op(op).ok_or_else(|| format!("Unsupported op: {}", op)))?;
-    let e = list[1]
-        .as_str()
-        .ok_or_else(|| "Datom e must be a string".to_string())?
-        .to_string();
-    let a = list[2]
-        .as_str()
-        .ok_or_else(|| "Datom a must be a string".to_string())?
-        .to_string();
-    let v = list[3].clone();
-    Ok(Datom { op, e, a, v })
+    list[1..]
+        .iter()
+        .try_for_each(check_reserved)
+        .map_err(parse)?;
+
+    let slot = EntitySlot::from_value(&list[1]).map_err(parse)?;
+    let e = String::new();
+    let op = match op {
+        "db/retractEntity" => TxOp::RetractEntity(e),
+        "db/cas" => TxOp::Cas {
+            e,
+            a: string_at(list, 2, "a").map_err(parse)?,
+            old: Some(list[3].clone()).filter(|old| !old.is_null()),
+            new: list[4].clone(),
+        },
+        _ => TxOp::Datom(Datom {
+            op: if op == "db/add" {
+                DatomOp::Add
+            } else {
+                DatomOp::Retract
+            },
+            e,
+            a: string_at(list, 2, "a").map_err(parse)?,
+            v: list[3].clone(),
+        }),
+    };
+    Ok(slot.op(op))
+}
+
+/// ops_from_map() expands an entity map into one `db/add` per attribute:
+/// `{"db/id": e, a: v, ...}` is `["db/add", e, a, v]` for each `a`, and a
+/// map without `db/id` is a new entity with a tempid of its own.
+/// A map in the value (or in a set of values) is a child entity: it is
+/// expanded in turn and the value refers to it.
+/// Appends the ops to `ops` and returns the entity of the map.
+fn ops_from_map(
+    map: &Map<String, Value>,
+    ops: &mut Vec<TxOp>,
+    tempids: &mut usize,
+) -> Result<EntitySlot, String> {
+    let slot = match map.get("db/id").or_else(|| map.get(":db/id")) {
+        Some(id) => {
+            check_reserved(id)?;
+            EntitySlot::from_value(id)?
+        }
+        None => {
+            *tempids += 1;
+            EntitySlot::Id(format!("{}map-{}", RESERVED_TEMPID_PREFIX, tempids))
+        }
+    };
+
+    for (a, v) in map {
+        let a = a.trim_start_matches(':');
+        if a == "db/id" {
+            continue;
+        }
+        let v = match v {
+            Value::Object(child) => ops_from_map(child, ops, tempids)?.to_value(),
+            Value::Array(items) if items.iter().any(Value::is_object) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(match item {
+                        Value::Object(child) => ops_from_map(child, ops, tempids)?.to_value(),
+                        item => {
+                            check_reserved(item)?;
+                            item.clone()
+                        }
+                    });
+                }
+                Value::Array(values)
+            }
+            v => {
+                check_reserved(v)?;
+                v.clone()
+            }
+        };
+        ops.push(slot.op(TxOp::Datom(Datom {
+            op: DatomOp::Add,
+            e: String::new(),
+            a: a.to_string(),
+            v,
+        })));
+    }
+    Ok(slot)
+}
+
+/// ops_from_values() parses a transaction: each element is an op in list
+/// form, see op_from_value(), or an entity map, see ops_from_map().
+/// Returns the ops and, for each op, the position of the element it comes
+/// from: an entity map expands into several ops.
+fn ops_from_values(values: Vec<Value>) -> Result<(Vec<TxOp>, Vec<usize>), TxError> {
+    let mut ops = Vec::with_capacity(values.len());
+    let mut positions = Vec::with_capacity(values.len());
+    let mut tempids = 0;
+    for (index, value) in values.into_iter().enumerate() {
+        match value {
+            Value::Object(map) => {
+                ops_from_map(&map, &mut ops, &mut tempids)
+                    .map_err(|message| TxError::Parse { index, message })?;
+            }
+            value => ops.push(op_from_value(index, value)?),
+        }
+        positions.resize(ops.len(), index);
+    }
+    Ok((ops, positions))
 }
 
-pub async fn transact_with_fallback(datoms: Vec<Value>) -> Result<Vec<Entity>, String> {
-    let mut parsed = Vec::with_capacity(datoms.len());
-    for value in datoms {
-        parsed.push(datom_from_value(value)?);
+/// at_position() turns the op index of an error into the position of the
+/// element the op was parsed from.
+fn at_position(err: TxError, positions: &[usize]) -> TxError {
+    match err.index() {
+        Some(op) => err.at(positions[op]),
+        None => err,
     }
-    db::apply_datoms(&parsed)
+}
+
+/// transact_with_fallback() parses and applies a transaction.
+/// # Errors
+/// Returns a TxError whose index is the position in `datoms` of the element
+/// that failed, see TxError.
+pub async fn transact_with_fallback(
+    db: &Db,
+    datoms: Vec<Value>,
+    meta: TxMeta,
+) -> Result<TxReport, TxError> {
+    let (parsed, positions) = ops_from_values(datoms)?;
+    db::apply_ops(db, &parsed, &meta)
         .await
-        .map_err(|err| err.to_string())
+        .map_err(|err| at_position(err, &positions))
+}
+
+/// undo() reverts up to `n` of the most recent transactions, newest first.
+/// Each revert is its own transaction so it can be redone.
+/// Returns the pairs of (reverted tx, undo tx).
+pub async fn undo(db: &Db, n: usize, meta: &TxMeta) -> Result<Vec<(i64, i64)>, TxError> {
+    revert_n(db, n, TxKind::Undo, meta).await
+}
+
+/// redo() re-applies up to `n` of the most recently undone transactions by
+/// reverting the undo transactions.
+/// Returns the pairs of (reverted undo tx, redo tx).
+pub async fn redo(db: &Db, n: usize, meta: &TxMeta) -> Result<Vec<(i64, i64)>, TxError> {
+    revert_n(db, n, TxKind::Redo, meta).await
+}
+
+/// revert_n() runs up to `n` reverts of `kind`, see db::revert_latest().
+async fn revert_n(
+    db: &Db,
+    n: usize,
+    kind: TxKind,
+    meta: &TxMeta,
+) -> Result<Vec<(i64, i64)>, TxError> {
+    let mut done = Vec::new();
+    for _ in 0..n {
+        let Some((tx, report)) = db::revert_latest(db, kind, meta).await? else {
+            break;
+        };
+        done.push((tx, report.tx));
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn datom(op: &TxOp) -> &Datom {
+        match op {
+            TxOp::Datom(datom) => datom,
+            TxOp::Lookup { op, .. } => datom(op),
+            op => panic!("not a datom: {:?}", op),
+        }
+    }
+
+    #[test]
+    fn entity_maps_expand_into_add_datoms() {
+        let (ops, _) = ops_from_values(vec![json!({
+            "db/id": "tmp/new",
+            "block/content": "TODO: Finish the project",
+            "block/page": {"db/id": ["page/name", "2026-01-10"], "page/color": "blue"},
+            "block/tags": ["TODO", "work"],
+        })])
+        .unwrap();
+
+        let datoms: Vec<(&str, &str, &Value)> = ops
+            .iter()
+            .map(datom)
+            .map(|d| (d.e.as_str(), d.a.as_str(), &d.v))
+            .collect();
+        assert_eq!(
+            datoms,
+            vec![
+                (
+                    "tmp/new",
+                    "block/content",
+                    &json!("TODO: Finish the project")
+                ),
+                ("", "page/color", &json!("blue")),
+                ("tmp/new", "block/page", &json!(["page/name", "2026-01-10"])),
+                ("tmp/new", "block/tags", &json!(["TODO", "work"])),
+            ]
+        );
+        assert!(matches!(&ops[1], TxOp::Lookup { a, .. } if a == "page/name"));
+    }
+
+    #[tokio::test]
+    async fn entity_maps_with_the_same_tempid_make_new_entities() {
+        let mut storage = db::MemoryStorage::default();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let (ops, _) = ops_from_values(vec![json!({
+                "db/id": "tmp/new",
+                "block/content": "TODO: Finish the project",
+            })])
+            .unwrap();
+            let report = storage.apply_ops(&ops, &TxMeta::default()).await.unwrap();
+            ids.push(report.tempids["tmp/new"].clone());
+        }
+        assert_ne!(ids[0], ids[1]);
+        assert!(ids.iter().all(|id| id.starts_with("block:")));
+    }
+
+    #[test]
+    fn entity_maps_without_id_get_tempids() {
+        let (ops, positions) = ops_from_values(vec![
+            json!({"block/content": "a", "block/page": {"page/name": "2026-01-10"}}),
+            json!(["db/add", "block:1", "block/content", "b"]),
+        ])
+        .unwrap();
+
+        // map keys are sorted, the child entity comes before the ref to it
+        assert_eq!(datom(&ops[0]).a, "block/content");
+        let page = datom(&ops[1]);
+        assert_eq!(
+            (page.e.as_str(), page.a.as_str()),
+            ("tmp/db/map-2", "page/name")
+        );
+        let block = datom(&ops[2]);
+        assert_eq!(block.e, "tmp/db/map-1");
+        assert_eq!(block.v, json!("tmp/db/map-2"));
+        assert_eq!(datom(&ops[3]).e, "block:1");
+        assert_eq!(positions, vec![0, 0, 0, 1]);
+
+        // a user's tempid can't name the tempid of an entity map
+        for value in [
+            json!(["db/add", "tmp/db/map-1", "block/content", "b"]),
+            json!({"db/id": "tmp/db/map-1", "block/content": "b"}),
+            json!({"block/page": "tmp/db/map-1"}),
+        ] {
+            let err = ops_from_values(vec![json!({"block/content": "a"}), value]).unwrap_err();
+            assert!(matches!(err, TxError::Parse { index: 1, .. }), "{}", err);
+        }
+    }
+
+    #[test]
+    fn parse_errors_carry_the_datom_index() {
+        let err = ops_from_values(vec![
+            json!(["db/add", "block:1", "block/content", "a"]),
+            json!(["db/frob", "block:1"]),
+        ])
+        .unwrap_err();
+        assert!(matches!(err, TxError::UnknownOp { index: 1, ref op } if op == "db/frob"));
+        assert_eq!(err.exit_code(), 2);
+
+        let err = ops_from_values(vec![json!({"db/id": 42}), json!("x")]).unwrap_err();
+        assert!(matches!(err, TxError::Parse { index: 0, .. }));
+        assert_eq!(
+            err.to_string(),
+            "datom 0: Datom e must be a string or a lookup ref"
+        );
+    }
 }